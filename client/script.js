@@ -489,12 +489,20 @@ const SERVER_TO_CLIENT_PACKET_YOUR_PLAYER_INFO = 3;
 const SERVER_TO_CLIENT_PACKET_BOARD_LOSS = 4;
 const SERVER_TO_CLIENT_PACKET_BOARD_WIN = 5;
 const SERVER_TO_CLIENT_PACKET_NEXT_BOARD_CONFIG = 6;
+const SERVER_TO_CLIENT_PACKET_WELCOME = 7;
+const SERVER_TO_CLIENT_PACKET_ERROR = 8;
 
 const CLIENT_TO_SERVER_PACKET_REVEAL_SQUARE = 0;
 const CLIENT_TO_SERVER_PACKET_CHORD_SQUARE = 1;
 const CLIENT_TO_SERVER_PACKET_TOGGLE_FLAG = 2;
 const CLIENT_TO_SERVER_PACKET_NEW_BOARD = 3;
 const CLIENT_TO_SERVER_PACKET_NEXT_BOARD_CONFIG = 4;
+const CLIENT_TO_SERVER_PACKET_HELLO = 5;
+
+// Protocol version this client speaks. Its packets have the same layouts as before the handshake.
+const PROTOCOL_VERSION = 1;
+// Optional capabilities this client understands.
+const CAPABILITIES = 0;
 
 class ServerToClientPacketFullBoard {
     // board and player_ids are 2d arrays indexed by row, col order.
@@ -573,6 +581,22 @@ class ServerToClientPacketNextBoardConfig {
     }
 }
 
+class ServerToClientPacketWelcome {
+    constructor(version, capabilities) {
+        this.type = SERVER_TO_CLIENT_PACKET_WELCOME;
+        this.version = version;
+        this.capabilities = capabilities;
+    }
+}
+
+class ServerToClientPacketError {
+    constructor(code, message) {
+        this.type = SERVER_TO_CLIENT_PACKET_ERROR;
+        this.code = code;
+        this.message = message;
+    }
+}
+
 class ClientToServerPacketRevealSquare {
     constructor(i, j) {
         this.type = CLIENT_TO_SERVER_PACKET_REVEAL_SQUARE;
@@ -612,6 +636,14 @@ class ClientToServerPacketNextBoardConfig {
     }
 }
 
+class ClientToServerPacketHello {
+    constructor(version, capabilities) {
+        this.type = CLIENT_TO_SERVER_PACKET_HELLO;
+        this.version = version;
+        this.capabilities = capabilities;
+    }
+}
+
 // Decode bytes from server into a packet.
 function decode_bytes(bytes) {
     const bytes_view = new DataView(bytes);
@@ -743,6 +775,19 @@ function decode_bytes(bytes) {
             packet = new ServerToClientPacketNextBoardConfig(width, height, num_mines);
             break;
         }
+        case SERVER_TO_CLIENT_PACKET_WELCOME: {
+            const version = bytes_view.getUint16(1, true);
+            const capabilities = bytes_view.getUint32(3, true);
+            packet = new ServerToClientPacketWelcome(version, capabilities);
+            break;
+        }
+        case SERVER_TO_CLIENT_PACKET_ERROR: {
+            const code = bytes_view.getUint8(1);
+            let utf8decoder = new TextDecoder();
+            const message = utf8decoder.decode(new Uint8Array(bytes.slice(2)));
+            packet = new ServerToClientPacketError(code, message);
+            break;
+        }
         default:
             console.log("Received packet with unknown type ", packet_type);
     }
@@ -792,6 +837,14 @@ function encode_packet(packet) {
             view.setUint32(5, packet.num_mines, true);
             break;
         }
+        case CLIENT_TO_SERVER_PACKET_HELLO: {
+            bytes = new ArrayBuffer(7);
+            let view = new DataView(bytes);
+            view.setUint8(0, CLIENT_TO_SERVER_PACKET_HELLO);
+            view.setUint16(1, packet.version, true);
+            view.setUint32(3, packet.capabilities, true);
+            break;
+        }
         default:
             console.log("Trying to send packet with unknown type ", packet.type);
     }
@@ -997,6 +1050,14 @@ function handle_packet(packet, state) {
             draw_next_board_config(packet.width, packet.height, packet.num_mines);
             break;
         }
+        case SERVER_TO_CLIENT_PACKET_WELCOME: {
+            console.log("Server speaks protocol version", packet.version, "with capabilities", packet.capabilities);
+            break;
+        }
+        case SERVER_TO_CLIENT_PACKET_ERROR: {
+            console.log("Server reported error", packet.code, ":", packet.message);
+            break;
+        }
         default:
             console.log("Received packet with unknown type ", packet.type);
     }
@@ -1055,6 +1116,7 @@ function setup_socket() {
     socket.addEventListener("open", (event) => {
         timeout = SOCKET_TIMEOUT_INIT;
         console.log("socket connected");
+        socket.send(encode_packet(new ClientToServerPacketHello(PROTOCOL_VERSION, CAPABILITIES)));
     });
     socket.addEventListener("close", (event) => {
         console.log("socket closed");
@@ -1071,7 +1133,9 @@ function setup_socket() {
     socket.addEventListener("message", (event) => {
         if (event.data instanceof ArrayBuffer) {
             packet = decode_bytes(event.data);
-            handle_packet(packet, state);
+            if (packet !== undefined) {
+                handle_packet(packet, state);
+            }
         } else {
             console.log("Message from server ", event.data);
         }
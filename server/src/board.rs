@@ -1,36 +1,120 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use itertools::Itertools;
 use rand::distributions::Distribution;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Entry {
-    Unknown { flagged: bool },
+    Unknown { mark: Option<Mark> },
     Mine,
     Revealed { num_mines: u8 },
 }
 
 impl Entry {
     pub fn is_unknown(&self) -> bool {
-        match self {
-            &Entry::Unknown { .. } => true,
-            _ => false,
-        }
+        matches!(self, Entry::Unknown { .. })
     }
 
     pub fn is_flagged(&self) -> bool {
-        match self {
-            &Entry::Unknown { flagged: true } => true,
-            _ => false,
-        }
+        matches!(self, Entry::Unknown { mark: Some(Mark::Flag) })
     }
 
     pub fn is_mine(&self) -> bool {
-        match self {
-            &Entry::Mine => true,
-            _ => false,
+        matches!(self, Entry::Mine)
+    }
+
+    pub fn is_wrong_flag(&self) -> bool {
+        matches!(self, Entry::Unknown { mark: Some(Mark::WrongFlag) })
+    }
+}
+
+// What a player has put on an unrevealed square, or what became of their flag. Only flags count
+// when chording.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mark {
+    Flag,
+    Question,
+    Safe, // The player thinks the square is not a mine.
+    WrongFlag, // A flag shown to be on a square which is not a mine. It stays until the square is revealed.
+}
+
+// Every mark, in the order cycling through them puts them on a square.
+pub const MARK_CYCLE: [Mark; 3] = [Mark::Flag, Mark::Question, Mark::Safe];
+
+impl Mark {
+    // The mark after the given one in the cycle, then none. Squares with no mark, or one which is
+    // not in the cycle, get its first mark.
+    pub fn next(mark: Option<Mark>, cycle: &[Mark]) -> Option<Mark> {
+        match mark.and_then(|mark| cycle.iter().position(|&other| other == mark)) {
+            Some(index) => cycle.get(index + 1).copied(),
+            None => cycle.first().copied(),
         }
     }
 }
 
+// Why an action on the board did nothing.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Rejection {
+    OutOfBounds,
+    GameOver,
+    NotStarted, // Chording before the first reveal.
+    AlreadyRevealed,
+    NotRevealed, // Chording on a square that isn't a revealed number.
+    FlagCountMismatch { num_mines: u8, num_flags: u8 },
+    NothingToReveal, // Chording on a square whose neighbours are all revealed or flagged.
+    Eliminated, // The player hit a mine in elimination mode and may only watch.
+    Frozen,     // The player hit a mine in freeze mode and may not act again yet.
+    NotYourTurn,
+    NotFlagOwner,  // Taking off someone else's flag where only they may.
+    FlagDisproved, // Marking a square whose flag has been shown to be wrong.
+}
+
+// A square of the revealed board as it is after an action.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SquareChange {
+    pub coords: (u16, u16),
+    pub entry: Entry,
+    pub player_id: u8, // Player the square is now attributed to.
+    pub cascade_owner: u8, // Player whose reveal or chord opened the square, or 0 if it is not revealed.
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Outcome {
+    Ongoing,
+    Won,
+    Lost { hit_mines: Vec<(u16, u16)>, wrong_flags: Vec<(u16, u16)> },
+}
+
+// What an accepted reveal or chord did.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ActionResult {
+    pub changes: Vec<SquareChange>,
+    pub outcome: Outcome, // Won or Lost if this action finished the game.
+    pub started: bool,    // Whether this action was the first reveal, which starts the game clock.
+    pub exploded: Vec<(u16, u16)>, // Mines hit without ending the game, on boards which survive mines.
+}
+
+// Clicks a player has made on a board. Clicks once the game is over are not counted.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct ClickCounts {
+    pub effective: u32, // Reveals and chords which revealed something.
+    pub wasted: u32,    // Reveals, chords and flags which were rejected.
+    pub flags: u32,     // Flags placed or removed.
+}
+
+// How efficiently a board has been played.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BoardStats {
+    pub three_bv: u32,        // Fewest clicks which clear the board. See count_three_bv.
+    pub solved_three_bv: u32, // Part of the 3BV which has been revealed.
+    pub elapsed_ms: u64,
+    pub three_bv_per_second: f32, // Solved 3BV per second on the game clock.
+    pub efficiency: f32,          // Solved 3BV per click by all players, as a percentage.
+    pub clicks: Vec<(u8, ClickCounts)>, // By player ID, in increasing order.
+}
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum BoardState {
     Uninitialized,
@@ -43,31 +127,97 @@ pub struct MultiplayerBoard {
     width: u16,
     height: u16,
     num_mines: u32,       // Total mines on board.
+    seed: u64,            // Together with the first click, determines where the mines are.
     entries_cleared: u32, // Total non-mine entries revealed so far.
     board_state: BoardState,
     // None if board hasn't been constructed yet. Indexed by (i, j) = (row, col).
     true_board: Option<Vec<Vec<Entry>>>,
+    three_bv: Option<u32>, // None until the mines have been placed.
     revealed_board: Vec<Vec<Entry>>, // Board revealed to player.
     player_ids: Vec<Vec<u8>>,        // Attributes entries to players.
+    // Attributes revealed squares to the players whose reveal or chord opened them, including by a
+    // cascade or the board break, which player_ids attribute to no one.
+    cascade_owners: Vec<Vec<u8>>,
+    first_click_ms: Option<u64>,     // When the first square was revealed.
+    finish_ms: Option<u64>,          // When the board was lost or won.
+    clicks: BTreeMap<u8, ClickCounts>,
+    // Whether hitting a mine leaves it revealed and play going, rather than losing the board.
+    survive_mines: bool,
+    show_wrong_flags: bool, // Whether flags are shown as wrong once the revealed squares prove there is no mine under them.
 }
 
 impl MultiplayerBoard {
-    pub fn new(width: u16, height: u16, num_mines: u32) -> MultiplayerBoard {
+    pub fn new(width: u16, height: u16, num_mines: u32, seed: u64) -> MultiplayerBoard {
         MultiplayerBoard {
             width,
             height,
             num_mines,
+            seed,
             entries_cleared: 0,
             board_state: BoardState::Uninitialized,
             true_board: None,
+            three_bv: None,
             revealed_board: vec![
-                vec![Entry::Unknown { flagged: false }; width as usize];
+                vec![Entry::Unknown { mark: None }; width as usize];
                 height as usize
             ],
             player_ids: vec![vec![0; width as usize]; height as usize],
+            cascade_owners: vec![vec![0; width as usize]; height as usize],
+            first_click_ms: None,
+            finish_ms: None,
+            clicks: BTreeMap::new(),
+            survive_mines: false,
+            show_wrong_flags: false,
         }
     }
 
+    // A board whose mines have already been placed, e.g. to replay a recorded game.
+    pub fn with_mines(width: u16, height: u16, seed: u64, mines: &[(u16, u16)]) -> MultiplayerBoard {
+        let mut board = MultiplayerBoard::new(width, height, mines.len() as u32, seed);
+        board.set_mines(mines);
+        board
+    }
+
+    // A board whose mines are placed as if the given square were revealed first, so that everyone
+    // who plays it gets the same layout.
+    pub fn with_safe_square(width: u16, height: u16, num_mines: u32, seed: u64, safe_square: (u16, u16)) -> MultiplayerBoard {
+        let mines = Self::place_mines(width, height, safe_square, num_mines, seed);
+        Self::with_mines(width, height, seed, &mines)
+    }
+
+    // Revealed mines count as flags when chording, so the numbers next to them still add up.
+    pub fn set_survive_mines(&mut self, survive_mines: bool) {
+        self.survive_mines = survive_mines;
+    }
+
+    pub fn survive_mines(&self) -> bool {
+        self.survive_mines
+    }
+
+    pub fn set_show_wrong_flags(&mut self, show_wrong_flags: bool) {
+        self.show_wrong_flags = show_wrong_flags;
+    }
+
+    pub fn show_wrong_flags(&self) -> bool {
+        self.show_wrong_flags
+    }
+
+    // Ends an ongoing board in a loss without a mine being hit, e.g. once no one is left who may
+    // play it. Every mine which has been hit counts as a hit mine.
+    pub fn lose(&mut self, now_ms: u64) -> Outcome {
+        self.board_state = BoardState::Lost;
+        self.finish_ms = Some(now_ms);
+        let mut hit_mines = vec![];
+        for i in 0..self.height {
+            for j in 0..self.width {
+                if self.revealed_entry_at((i, j)).is_mine() {
+                    hit_mines.push((i, j));
+                }
+            }
+        }
+        Outcome::Lost { hit_mines, wrong_flags: self.wrong_flags() }
+    }
+
     pub fn width(&self) -> u16 {
         self.width
     }
@@ -80,6 +230,52 @@ impl MultiplayerBoard {
         self.num_mines
     }
 
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Non-mine squares revealed so far.
+    pub fn squares_cleared(&self) -> u32 {
+        self.entries_cleared
+    }
+
+    // Non-mine squares on the board, which all have to be revealed to win.
+    pub fn squares_to_clear(&self) -> u32 {
+        (self.width as u32) * (self.height as u32) - self.num_mines
+    }
+
+    pub fn first_click_ms(&self) -> Option<u64> {
+        self.first_click_ms
+    }
+
+    pub fn finish_ms(&self) -> Option<u64> {
+        self.finish_ms
+    }
+
+    // Time on the game clock, which runs from the first reveal until the board is lost or won.
+    pub fn elapsed_ms(&self, now_ms: u64) -> u64 {
+        match self.first_click_ms {
+            Some(first_click_ms) => self.finish_ms.unwrap_or(now_ms).saturating_sub(first_click_ms),
+            None => 0,
+        }
+    }
+
+    // None until the board has been started.
+    pub fn stats(&self, now_ms: u64) -> Option<BoardStats> {
+        let three_bv = self.three_bv?;
+        let (_, solved_three_bv) = self.count_three_bv();
+        let elapsed_ms = self.elapsed_ms(now_ms);
+        let total_clicks: u32 = self.clicks.values().map(|clicks| clicks.effective + clicks.wasted + clicks.flags).sum();
+        Some(BoardStats {
+            three_bv,
+            solved_three_bv,
+            elapsed_ms,
+            three_bv_per_second: solved_three_bv as f32 * 1000.0 / elapsed_ms.max(1) as f32,
+            efficiency: if total_clicks == 0 { 0.0 } else { solved_three_bv as f32 * 100.0 / total_clicks as f32 },
+            clicks: self.clicks.iter().map(|(&player_id, &clicks)| (player_id, clicks)).collect(),
+        })
+    }
+
     pub fn board_state(&self) -> &BoardState {
         &self.board_state
     }
@@ -92,13 +288,13 @@ impl MultiplayerBoard {
         &self.player_ids
     }
 
+    pub fn cascade_owners(&self) -> &Vec<Vec<u8>> {
+        &self.cascade_owners
+    }
+
     pub fn true_entry_at(&self, coords: (u16, u16)) -> Option<Entry> {
         assert!(coords.0 < self.height && coords.1 < self.width);
-        if let Some(ref true_board) = self.true_board {
-            Some(true_board[coords.0 as usize][coords.1 as usize])
-        } else {
-            None
-        }
+        self.true_board.as_ref().map(|true_board| true_board[coords.0 as usize][coords.1 as usize])
     }
 
     pub fn revealed_entry_at(&self, coords: (u16, u16)) -> Entry {
@@ -111,142 +307,195 @@ impl MultiplayerBoard {
         self.player_ids[coords.0 as usize][coords.1 as usize]
     }
 
-    // Reveals the given square and returns a list of squares which have been changed in the
-    // revealed board.
-    pub fn reveal_square(&mut self, reveal_coords: (u16, u16), player_id: u8) -> Vec<(u16, u16)> {
-        assert!(reveal_coords.0 < self.height && reveal_coords.1 < self.width);
-        match self.board_state {
-            BoardState::Uninitialized => {
-                assert!(self.true_board.is_none());
-                let true_board = Self::construct_board(self.width, self.height, reveal_coords, self.num_mines);
-                self.true_board = Some(true_board);
-                self.board_state = BoardState::Ongoing;
-                self.reveal_square(reveal_coords, 0) // Attribute board break to no one.
+    // Attributes the squares and cascades of a player who is gone to no one, and drops their clicks.
+    pub fn forget_player(&mut self, player_id: u8) {
+        for id in self.player_ids.iter_mut().flatten() {
+            if *id == player_id {
+                *id = 0;
             }
-            BoardState::Ongoing => {
-                assert!(self.true_board.is_some());
-                if !self.revealed_entry_at(reveal_coords).is_unknown() {
-                    return vec![];
-                }
+        }
+        for owner in self.cascade_owners.iter_mut().flatten() {
+            if *owner == player_id {
+                *owner = 0;
+            }
+        }
+        self.clicks.remove(&player_id);
+    }
 
-                let true_board: &[Vec<Entry>] = self.true_board.as_ref().unwrap();
+    pub fn cascade_owner_at(&self, coords: (u16, u16)) -> u8 {
+        assert!(coords.0 < self.height && coords.1 < self.width);
+        self.cascade_owners[coords.0 as usize][coords.1 as usize]
+    }
 
-                // Reveal square clicked on.
-                self.revealed_board[reveal_coords.0 as usize][reveal_coords.1 as usize] =
-                    true_board[reveal_coords.0 as usize][reveal_coords.1 as usize];
-                self.player_ids[reveal_coords.0 as usize][reveal_coords.1 as usize] = player_id;
+    pub fn in_bounds(&self, coords: (u16, u16)) -> bool {
+        coords.0 < self.height && coords.1 < self.width
+    }
 
-                if self.revealed_entry_at(reveal_coords).is_mine() {
-                    self.board_state = BoardState::Lost;
-                    return vec![reveal_coords]; // Short-circuit if lose game.
-                } else {
-                    self.entries_cleared += 1;
+    // Where the mines are. None until they have been placed, which normally happens on the first reveal.
+    pub fn mines(&self) -> Option<Vec<(u16, u16)>> {
+        let true_board = self.true_board.as_ref()?;
+        let mut mines = vec![];
+        for (i, row) in true_board.iter().enumerate() {
+            for (j, entry) in row.iter().enumerate() {
+                if entry.is_mine() {
+                    mines.push((i as u16, j as u16));
                 }
+            }
+        }
+        Some(mines)
+    }
 
-                // Reveal all squares adjacent to those which have 0 mines adjacent.
-                let mut changed_coords = vec![reveal_coords];
-                let mut unchecked_coords = vec![reveal_coords];
-                while let Some(coords) = unchecked_coords.pop() {
-                    if let Entry::Revealed { num_mines: 0 } = self.revealed_entry_at(coords) {
-                        // Reveal all unrevealed adjacent entries.
-                        for adjacent_coords in Self::adjacent_coords(coords, self.width, self.height) {
-                            if self.revealed_entry_at(adjacent_coords).is_unknown() {
-                                self.revealed_board[adjacent_coords.0 as usize]
-                                    [adjacent_coords.1 as usize] = true_board
-                                    [adjacent_coords.0 as usize]
-                                    [adjacent_coords.1 as usize];
-                                unchecked_coords.push(adjacent_coords);
-                                changed_coords.push(adjacent_coords);
-                                assert!(!self.revealed_entry_at(adjacent_coords).is_mine());
-                                self.entries_cleared += 1;
-                                // Attribute all extended reveals to no one.
-                                self.player_ids[adjacent_coords.0 as usize]
-                                    [adjacent_coords.1 as usize] = 0;
-                            }
-                        }
-                    }
+    // Squares which are flagged but are not mines, including flags already shown as wrong. Empty
+    // until the board has been constructed.
+    pub fn wrong_flags(&self) -> Vec<(u16, u16)> {
+        let mut wrong_flags = vec![];
+        for i in 0..self.height {
+            for j in 0..self.width {
+                let coords = (i, j);
+                let entry = self.revealed_entry_at(coords);
+                if (entry.is_flagged() || entry.is_wrong_flag())
+                    && self.true_entry_at(coords).is_some_and(|entry| !entry.is_mine())
+                {
+                    wrong_flags.push(coords);
                 }
+            }
+        }
+        wrong_flags
+    }
 
-                // Win condition.
-                if self.entries_cleared == (self.width as u32) * (self.height as u32) - (self.num_mines as u32) {
-                    self.board_state = BoardState::Won;
-                }
+    // Reveals the given square, cascading through squares with no adjacent mines. Actions are timed
+    // with now_ms, in milliseconds since the Unix epoch.
+    pub fn reveal_square(&mut self, reveal_coords: (u16, u16), player_id: u8, now_ms: u64) -> Result<ActionResult, Rejection> {
+        let result = self.reveal(reveal_coords, player_id, now_ms);
+        self.count_click(player_id, &result, false);
+        result
+    }
+
+    // If a revealed square has its own number of flags adjacent, reveal the remaining adjacent squares.
+    pub fn chord_square(&mut self, chord_coords: (u16, u16), player_id: u8, now_ms: u64) -> Result<ActionResult, Rejection> {
+        let result = self.chord(chord_coords, player_id, now_ms);
+        self.count_click(player_id, &result, false);
+        result
+    }
+
+    // Moves the square's mark on to the next in the cycle. Toggling a flag is cycling through just
+    // [Mark::Flag].
+    pub fn cycle_mark_square(&mut self, coords: (u16, u16), player_id: u8, cycle: &[Mark]) -> Result<SquareChange, Rejection> {
+        let result = self.cycle_mark(coords, player_id, cycle);
+        self.count_click(player_id, &result, true);
+        result
+    }
+
+    fn count_click<T>(&mut self, player_id: u8, result: &Result<T, Rejection>, is_flag: bool) {
+        let clicks = self.clicks.entry(player_id).or_default();
+        match result {
+            Ok(_) if is_flag => clicks.flags += 1,
+            Ok(_) => clicks.effective += 1,
+            Err(Rejection::GameOver) => {}
+            Err(_) => clicks.wasted += 1,
+        }
+    }
 
-                changed_coords
+    fn reveal(&mut self, reveal_coords: (u16, u16), player_id: u8, now_ms: u64) -> Result<ActionResult, Rejection> {
+        if !self.in_bounds(reveal_coords) {
+            return Err(Rejection::OutOfBounds);
+        }
+        match self.board_state {
+            BoardState::Uninitialized => {
+                if self.true_board.is_none() {
+                    let mines = Self::place_mines(self.width, self.height, reveal_coords, self.num_mines, self.seed);
+                    self.set_mines(&mines);
+                }
+                self.board_state = BoardState::Ongoing;
+                self.first_click_ms = Some(now_ms);
+                // Attribute board break to no one, though it is still the player's cascade.
+                let changed_coords = self.flood_reveal(reveal_coords, 0, player_id);
+                let mut result = self.action_result(changed_coords, now_ms);
+                result.started = true;
+                Ok(result)
             }
-            BoardState::Lost | BoardState::Won => {
-                vec![]
+            BoardState::Ongoing => {
+                if !self.revealed_entry_at(reveal_coords).is_unknown() {
+                    return Err(Rejection::AlreadyRevealed);
+                }
+                let changed_coords = self.flood_reveal(reveal_coords, player_id, player_id);
+                Ok(self.action_result(changed_coords, now_ms))
             }
+            BoardState::Lost | BoardState::Won => Err(Rejection::GameOver),
         }
     }
 
-    // If a revealed square has its own number of flags adjacent, reveal the remaining adjacent squares.
-    pub fn chord_square(&mut self, chord_coords: (u16, u16), player_id: u8) -> Vec<(u16, u16)> {
-        assert!(chord_coords.0 < self.height && chord_coords.1 < self.width);
+    fn chord(&mut self, chord_coords: (u16, u16), player_id: u8, now_ms: u64) -> Result<ActionResult, Rejection> {
+        if !self.in_bounds(chord_coords) {
+            return Err(Rejection::OutOfBounds);
+        }
         match self.board_state {
-            BoardState::Uninitialized | BoardState::Lost | BoardState::Won => {
-                vec![]
-            }
+            BoardState::Uninitialized => Err(Rejection::NotStarted),
+            BoardState::Lost | BoardState::Won => Err(Rejection::GameOver),
             BoardState::Ongoing => {
                 // Check that chorded square is revealed.
                 let Entry::Revealed { num_mines } = self.revealed_entry_at(chord_coords) else {
-                    return vec![];
+                    return Err(Rejection::NotRevealed);
                 };
 
                 // Check that the number of adjacent flags matches the revealed square.
                 let num_flags = Self::adjacent_coords(chord_coords, self.width, self.height)
                     .iter()
-                    .filter(|coords| self.revealed_entry_at(**coords).is_flagged())
+                    .filter(|coords| {
+                        let entry = self.revealed_entry_at(**coords);
+                        entry.is_flagged() || entry.is_mine()
+                    })
                     .count();
                 if num_mines as usize != num_flags {
-                    return vec![];
+                    return Err(Rejection::FlagCountMismatch { num_mines, num_flags: num_flags as u8 });
                 }
 
                 let mut changed_coords = vec![];
-                let mut mines: Vec<(u16, u16)> = vec![];
                 for coords in Self::adjacent_coords(chord_coords, self.width, self.height) {
+                    if self.board_state != BoardState::Ongoing {
+                        break; // Hit a mine.
+                    }
+                    // Earlier cascades may have revealed this square already.
                     let revealed_entry = self.revealed_entry_at(coords);
                     if revealed_entry.is_unknown() && !revealed_entry.is_flagged() {
-                        let new_changed_coords = self.reveal_square(coords, player_id);
-                        mines.extend(
-                            new_changed_coords
-                                .iter()
-                                .filter(|coords| self.revealed_entry_at(**coords).is_mine()),
-                        );
-                        changed_coords.extend_from_slice(&new_changed_coords);
+                        changed_coords.extend(self.flood_reveal(coords, player_id, player_id));
                     }
                 }
-                // changed_coords
-                if mines.len() == 0 {
-                    changed_coords
+                if changed_coords.is_empty() {
+                    Err(Rejection::NothingToReveal)
                 } else {
-                    mines
+                    Ok(self.action_result(changed_coords, now_ms))
                 }
             }
         }
     }
 
-    // Returns true if toggled flag, false if not (e.g. wasn't a flaggable square).
-    pub fn toggle_flag_square(&mut self, coords: (u16, u16), player_id: u8) -> bool {
-        assert!(coords.0 < self.height && coords.1 < self.width);
+    fn cycle_mark(&mut self, coords: (u16, u16), player_id: u8, cycle: &[Mark]) -> Result<SquareChange, Rejection> {
+        if !self.in_bounds(coords) {
+            return Err(Rejection::OutOfBounds);
+        }
         match self.board_state {
             BoardState::Uninitialized | BoardState::Ongoing => {
-                if let Entry::Unknown { ref mut flagged } =
+                if let Entry::Unknown { ref mut mark } =
                     self.revealed_board[coords.0 as usize][coords.1 as usize]
                 {
-                    if *flagged == false {
-                        *flagged = true;
-                        self.player_ids[coords.0 as usize][coords.1 as usize] = player_id;
-                    } else {
-                        *flagged = false;
-                        self.player_ids[coords.0 as usize][coords.1 as usize] = 0;
+                    if *mark == Some(Mark::WrongFlag) {
+                        return Err(Rejection::FlagDisproved);
                     }
-                    true
+                    *mark = Mark::next(*mark, cycle);
+                    // Marks are attributed to whoever put them there.
+                    self.player_ids[coords.0 as usize][coords.1 as usize] = if mark.is_some() { player_id } else { 0 };
+                    // A flag on a square already proven safe is wrong from the start.
+                    let flagged = self.revealed_entry_at(coords).is_flagged();
+                    if flagged && self.show_wrong_flags && self.board_state == BoardState::Ongoing && self.proven_safe(coords) {
+                        self.revealed_board[coords.0 as usize][coords.1 as usize] = Entry::Unknown { mark: Some(Mark::WrongFlag) };
+                    }
+                    Ok(self.square_change(coords))
                 } else {
-                    false
+                    Err(Rejection::AlreadyRevealed)
                 }
             }
-            BoardState::Lost | BoardState::Won => false,
+            BoardState::Lost | BoardState::Won => Err(Rejection::GameOver),
         }
     }
 
@@ -261,16 +510,15 @@ impl MultiplayerBoard {
         }
     }
 
-    fn construct_board(
+    fn place_mines(
         width: u16,
         height: u16,
         first_click: (u16, u16),
         num_mines: u32,
-    ) -> Vec<Vec<Entry>> {
+        seed: u64,
+    ) -> Vec<(u16, u16)> {
         assert!(first_click.0 < height && first_click.1 < width);
 
-        let mut true_board = vec![vec![Entry::Unknown { flagged: false }; width.into()]; height.into()];
-
         let mines: Vec<(u16, u16)> = if num_mines >= (width as u32) * (height as u32) {
             // Entire board is mines.
             let mut mines = vec![];
@@ -281,7 +529,7 @@ impl MultiplayerBoard {
             }
             mines
         } else {
-            let mut rng = rand::thread_rng();
+            let mut rng = StdRng::seed_from_u64(seed);
             let num_guaranteed_non_mine = Self::adjacent_coords(first_click, width, height).len() + 1;
             rand::distributions::Uniform::new(0, (width as u32) * (height as u32))
                 .sample_iter(&mut rng)
@@ -297,6 +545,11 @@ impl MultiplayerBoard {
         };
             
         assert!(mines.len() == num_mines as usize);
+        mines
+    }
+
+    fn board_with_mines(width: u16, height: u16, mines: &[(u16, u16)]) -> Vec<Vec<Entry>> {
+        let mut true_board = vec![vec![Entry::Unknown { mark: None }; width.into()]; height.into()];
 
         // Board with values in {0, 1}, padded by zeros.
         let mut mine_board: Vec<Vec<u8>> = vec![vec![0; width as usize + 2]; height as usize + 2];
@@ -307,16 +560,14 @@ impl MultiplayerBoard {
         }
 
         // Count mines on squares that are on the interior.
-        for i in 0..height as usize {
-            for j in 0..width as usize {
-                if true_board[i][j] != Entry::Mine {
-                    let mut adjacent_mines = 0;
-                    for k in i..(i + 3) {
-                        for l in j..(j + 3) {
-                            adjacent_mines += mine_board[k][l];
-                        }
-                    }
-                    true_board[i][j] = Entry::Revealed {
+        for (i, row) in true_board.iter_mut().enumerate() {
+            for (j, entry) in row.iter_mut().enumerate() {
+                if *entry != Entry::Mine {
+                    let adjacent_mines = mine_board[i..(i + 3)]
+                        .iter()
+                        .map(|mine_row| mine_row[j..(j + 3)].iter().sum::<u8>())
+                        .sum();
+                    *entry = Entry::Revealed {
                         num_mines: adjacent_mines,
                     };
                 }
@@ -325,11 +576,230 @@ impl MultiplayerBoard {
         true_board
     }
 
+    // Reveals an unknown square on an ongoing board and returns the squares which have been changed
+    // in the revealed board. Updates the board state if the game is lost or won. The square is
+    // attributed to player_id and everything it opens to cascade_owner.
+    fn flood_reveal(&mut self, reveal_coords: (u16, u16), player_id: u8, cascade_owner: u8) -> Vec<(u16, u16)> {
+        assert!(self.board_state == BoardState::Ongoing);
+        assert!(self.revealed_entry_at(reveal_coords).is_unknown());
+
+        let true_board: &[Vec<Entry>] = self.true_board.as_ref().unwrap();
+
+        // Reveal square clicked on.
+        self.revealed_board[reveal_coords.0 as usize][reveal_coords.1 as usize] =
+            true_board[reveal_coords.0 as usize][reveal_coords.1 as usize];
+        self.player_ids[reveal_coords.0 as usize][reveal_coords.1 as usize] = player_id;
+        self.cascade_owners[reveal_coords.0 as usize][reveal_coords.1 as usize] = cascade_owner;
+
+        if self.revealed_entry_at(reveal_coords).is_mine() {
+            if !self.survive_mines {
+                self.board_state = BoardState::Lost;
+            }
+            return vec![reveal_coords]; // Short-circuit if lose game.
+        } else {
+            self.entries_cleared += 1;
+        }
+
+        // Reveal all squares adjacent to those which have 0 mines adjacent.
+        let mut changed_coords = vec![reveal_coords];
+        let mut unchecked_coords = vec![reveal_coords];
+        while let Some(coords) = unchecked_coords.pop() {
+            if let Entry::Revealed { num_mines: 0 } = self.revealed_entry_at(coords) {
+                // Reveal all unrevealed adjacent entries.
+                for adjacent_coords in Self::adjacent_coords(coords, self.width, self.height) {
+                    if self.revealed_entry_at(adjacent_coords).is_unknown() {
+                        self.revealed_board[adjacent_coords.0 as usize]
+                            [adjacent_coords.1 as usize] = true_board
+                            [adjacent_coords.0 as usize]
+                            [adjacent_coords.1 as usize];
+                        unchecked_coords.push(adjacent_coords);
+                        changed_coords.push(adjacent_coords);
+                        assert!(!self.revealed_entry_at(adjacent_coords).is_mine());
+                        self.entries_cleared += 1;
+                        // Attribute all extended reveals to no one.
+                        self.player_ids[adjacent_coords.0 as usize]
+                            [adjacent_coords.1 as usize] = 0;
+                        self.cascade_owners[adjacent_coords.0 as usize][adjacent_coords.1 as usize] = cascade_owner;
+                    }
+                }
+            }
+        }
+
+        // Win condition.
+        if self.entries_cleared == self.squares_to_clear() {
+            self.board_state = BoardState::Won;
+        }
+
+        changed_coords
+    }
+
+    fn set_mines(&mut self, mines: &[(u16, u16)]) {
+        self.true_board = Some(Self::board_with_mines(self.width, self.height, mines));
+        self.three_bv = Some(self.count_three_bv().0);
+    }
+
+    // Counts the board's 3BV, the fewest clicks which clear it: one for each opening (a connected
+    // area of squares with no adjacent mines, together with the numbers bordering it) and one for
+    // each numbered square outside of every opening. Returns the 3BV and how much of it has been
+    // revealed.
+    fn count_three_bv(&self) -> (u32, u32) {
+        let true_board: &[Vec<Entry>] = self.true_board.as_ref().unwrap();
+        let is_zero = |coords: (u16, u16)| true_board[coords.0 as usize][coords.1 as usize] == Entry::Revealed { num_mines: 0 };
+        let mut counted = vec![vec![false; self.width as usize]; self.height as usize];
+        let mut three_bv = 0;
+        let mut solved = 0;
+
+        for i in 0..self.height {
+            for j in 0..self.width {
+                if counted[i as usize][j as usize] || !is_zero((i, j)) {
+                    continue;
+                }
+                three_bv += 1;
+                // Revealing any part of an opening cascades through all of it.
+                if !self.revealed_entry_at((i, j)).is_unknown() {
+                    solved += 1;
+                }
+                counted[i as usize][j as usize] = true;
+                let mut unchecked_coords = vec![(i, j)];
+                while let Some(coords) = unchecked_coords.pop() {
+                    for adjacent_coords in Self::adjacent_coords(coords, self.width, self.height) {
+                        if !counted[adjacent_coords.0 as usize][adjacent_coords.1 as usize] {
+                            counted[adjacent_coords.0 as usize][adjacent_coords.1 as usize] = true;
+                            if is_zero(adjacent_coords) {
+                                unchecked_coords.push(adjacent_coords);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for i in 0..self.height {
+            for j in 0..self.width {
+                if !counted[i as usize][j as usize] && !true_board[i as usize][j as usize].is_mine() {
+                    three_bv += 1;
+                    if !self.revealed_entry_at((i, j)).is_unknown() {
+                        solved += 1;
+                    }
+                }
+            }
+        }
+        (three_bv, solved)
+    }
+
+    pub fn square_change(&self, coords: (u16, u16)) -> SquareChange {
+        SquareChange {
+            coords,
+            entry: self.revealed_entry_at(coords),
+            player_id: self.player_at(coords),
+            cascade_owner: self.cascade_owner_at(coords),
+        }
+    }
+
+    // Describes an accepted action which changed the given squares. Actions are only accepted on
+    // unfinished boards, so a finished board means this action finished it and stops the clock.
+    fn action_result(&mut self, mut changed_coords: Vec<(u16, u16)>, now_ms: u64) -> ActionResult {
+        if self.show_wrong_flags && self.board_state == BoardState::Ongoing {
+            let disproved = self.disprove_flags(&changed_coords);
+            changed_coords.extend(disproved);
+        }
+        if matches!(self.board_state, BoardState::Lost | BoardState::Won) {
+            self.finish_ms = Some(now_ms);
+        }
+        let outcome = match self.board_state {
+            BoardState::Uninitialized | BoardState::Ongoing => Outcome::Ongoing,
+            BoardState::Won => Outcome::Won,
+            BoardState::Lost => Outcome::Lost {
+                hit_mines: changed_coords
+                    .iter()
+                    .copied()
+                    .filter(|coords| self.revealed_entry_at(*coords).is_mine())
+                    .collect(),
+                wrong_flags: self.wrong_flags(),
+            },
+        };
+        let exploded = match outcome {
+            Outcome::Lost { .. } => vec![],
+            _ => changed_coords.iter().copied().filter(|coords| self.revealed_entry_at(*coords).is_mine()).collect(),
+        };
+        ActionResult {
+            changes: changed_coords.into_iter().map(|coords| self.square_change(coords)).collect(),
+            outcome,
+            started: false,
+            exploded,
+        }
+    }
+
+    // Shows the flags near the given squares as wrong where the revealed squares prove there is no
+    // mine under them. Returns the flags shown as wrong.
+    fn disprove_flags(&mut self, changed_coords: &[(u16, u16)]) -> Vec<(u16, u16)> {
+        // A square can only prove something about flags within reach of the numbers next to it.
+        let flags: BTreeSet<(u16, u16)> = changed_coords.iter()
+            .flat_map(|&coords| self.nearby_coords(coords, 3))
+            .filter(|&coords| self.revealed_entry_at(coords).is_flagged())
+            .collect();
+        let disproved = flags.into_iter().filter(|&coords| self.proven_safe(coords)).collect_vec();
+        for &(i, j) in &disproved {
+            self.revealed_board[i as usize][j as usize] = Entry::Unknown { mark: Some(Mark::WrongFlag) };
+        }
+        disproved
+    }
+
+    // Whether what has been revealed proves an unrevealed square is not a mine: a number next to it
+    // already has all its mines revealed, or another number has the same count of mines left to
+    // find among unrevealed squares which are all next to the first but not this square. Flags
+    // prove nothing, as they may be wrong themselves.
+    fn proven_safe(&self, coords: (u16, u16)) -> bool {
+        Self::adjacent_coords(coords, self.width, self.height).into_iter().any(|number_coords| {
+            let Some(mines_left) = self.mines_left(number_coords) else {
+                return false;
+            };
+            if mines_left == 0 {
+                return true;
+            }
+            let unrevealed = self.unrevealed_adjacent(number_coords);
+            self.nearby_coords(number_coords, 2).into_iter().any(|other_coords| {
+                if other_coords == number_coords || self.mines_left(other_coords) != Some(mines_left) {
+                    return false;
+                }
+                let other_unrevealed = self.unrevealed_adjacent(other_coords);
+                !other_unrevealed.contains(&coords) && other_unrevealed.iter().all(|square| unrevealed.contains(square))
+            })
+        })
+    }
+
+    // For a revealed number, how many of its mines have not been revealed.
+    fn mines_left(&self, coords: (u16, u16)) -> Option<u8> {
+        let Entry::Revealed { num_mines } = self.revealed_entry_at(coords) else {
+            return None;
+        };
+        let revealed_mines = Self::adjacent_coords(coords, self.width, self.height)
+            .into_iter()
+            .filter(|&adjacent| self.revealed_entry_at(adjacent).is_mine())
+            .count();
+        Some(num_mines.saturating_sub(revealed_mines as u8))
+    }
+
+    fn unrevealed_adjacent(&self, coords: (u16, u16)) -> Vec<(u16, u16)> {
+        Self::adjacent_coords(coords, self.width, self.height)
+            .into_iter()
+            .filter(|&adjacent| self.revealed_entry_at(adjacent).is_unknown())
+            .collect()
+    }
+
+    // Returns the squares at most the given number of rows and columns away, including coords.
+    fn nearby_coords(&self, coords: (u16, u16), distance: u16) -> Vec<(u16, u16)> {
+        let (i, j) = coords;
+        let rows = i.saturating_sub(distance)..=i.saturating_add(distance).min(self.height - 1);
+        let columns = j.saturating_sub(distance)..=j.saturating_add(distance).min(self.width - 1);
+        rows.cartesian_product(columns).collect()
+    }
+
     fn adjacent(a: (u16, u16), b: (u16, u16)) -> bool {
         let (ai, aj) = a;
         let (bi, bj) = b;
-        let idist = if ai >= bi { ai - bi } else { bi - ai };
-        let jdist = if aj >= bj { aj - bj } else { bj - aj };
+        let idist = ai.abs_diff(bi);
+        let jdist = aj.abs_diff(bj);
         idist <= 1 && jdist <= 1
     }
 
@@ -390,3 +860,200 @@ impl MultiplayerBoard {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mines in the corners of a 4x4 board, so that every other square is a 1.
+    const CORNERS: [(u16, u16); 4] = [(0, 0), (0, 3), (3, 0), (3, 3)];
+
+    // A board with mines in the given places, as if its first reveal had already been made.
+    fn started_board(width: u16, height: u16, mines: &[(u16, u16)]) -> MultiplayerBoard {
+        let mut board = MultiplayerBoard::with_mines(width, height, 0, mines);
+        board.board_state = BoardState::Ongoing;
+        board
+    }
+
+    #[test]
+    fn rejects_reveals_and_flags_which_do_nothing() {
+        let mut board = started_board(4, 4, &CORNERS);
+        assert_eq!(board.reveal_square((4, 0), 1, 0), Err(Rejection::OutOfBounds));
+        assert_eq!(board.cycle_mark_square((0, 4), 1, &[Mark::Flag]), Err(Rejection::OutOfBounds));
+        board.reveal_square((1, 1), 1, 0).unwrap();
+        assert_eq!(board.reveal_square((1, 1), 2, 0), Err(Rejection::AlreadyRevealed));
+        assert_eq!(board.cycle_mark_square((1, 1), 2, &[Mark::Flag]), Err(Rejection::AlreadyRevealed));
+
+        board.reveal_square((0, 0), 1, 0).unwrap();
+        assert_eq!(*board.board_state(), BoardState::Lost);
+        assert_eq!(board.reveal_square((2, 2), 1, 0), Err(Rejection::GameOver));
+        assert_eq!(board.cycle_mark_square((2, 2), 1, &[Mark::Flag]), Err(Rejection::GameOver));
+    }
+
+    #[test]
+    fn rejects_chords_which_do_nothing() {
+        let mut board = MultiplayerBoard::new(4, 4, 4, 0);
+        assert_eq!(board.chord_square((1, 1), 1, 0), Err(Rejection::NotStarted));
+
+        let mut board = started_board(4, 4, &CORNERS);
+        assert_eq!(board.chord_square((1, 1), 1, 0), Err(Rejection::NotRevealed));
+        board.reveal_square((1, 1), 1, 0).unwrap();
+        assert_eq!(board.chord_square((1, 1), 1, 0), Err(Rejection::FlagCountMismatch { num_mines: 1, num_flags: 0 }));
+        board.cycle_mark_square((0, 0), 1, &[Mark::Flag]).unwrap();
+        assert_eq!(board.chord_square((1, 1), 1, 0).unwrap().changes.len(), 7);
+        assert_eq!(board.chord_square((1, 1), 1, 0), Err(Rejection::NothingToReveal));
+    }
+
+    #[test]
+    fn reports_what_each_action_changed() {
+        let mut board = started_board(4, 4, &CORNERS);
+        let result = board.reveal_square((1, 1), 2, 0).unwrap();
+        let revealed = SquareChange { coords: (1, 1), entry: Entry::Revealed { num_mines: 1 }, player_id: 2, cascade_owner: 2 };
+        assert_eq!(result, ActionResult { changes: vec![revealed], outcome: Outcome::Ongoing, started: false, exploded: vec![] });
+
+        let flagged = SquareChange { coords: (0, 1), entry: Entry::Unknown { mark: Some(Mark::Flag) }, player_id: 3, cascade_owner: 0 };
+        assert_eq!(board.cycle_mark_square((0, 1), 3, &[Mark::Flag]), Ok(flagged));
+    }
+
+    #[test]
+    fn reports_hit_mines_and_wrong_flags_when_a_chord_loses() {
+        let mut board = started_board(4, 4, &CORNERS);
+        board.reveal_square((1, 1), 1, 0).unwrap();
+        board.cycle_mark_square((0, 1), 1, &[Mark::Flag]).unwrap();
+        let result = board.chord_square((1, 1), 1, 0).unwrap();
+        assert_eq!(result.outcome, Outcome::Lost { hit_mines: vec![(0, 0)], wrong_flags: vec![(0, 1)] });
+        // Squares revealed before the mine was hit are still sent.
+        assert!(result.changes.iter().any(|change| change.entry == Entry::Revealed { num_mines: 1 }));
+    }
+
+    #[test]
+    fn reports_a_win_once_every_safe_square_is_revealed() {
+        let mut board = started_board(3, 1, &[(0, 0)]);
+        assert_eq!(board.reveal_square((0, 2), 1, 0).unwrap().outcome, Outcome::Won);
+    }
+
+    #[test]
+    fn places_the_same_mines_for_the_same_seed_and_first_click() {
+        let mut boards: Vec<MultiplayerBoard> = [7, 7, 8].into_iter().map(|seed| MultiplayerBoard::new(16, 16, 40, seed)).collect();
+        for board in boards.iter_mut() {
+            board.reveal_square((8, 8), 1, 0).unwrap();
+        }
+        let true_board = |board: &MultiplayerBoard| board.true_board.clone().unwrap();
+        assert_eq!(true_board(&boards[0]), true_board(&boards[1]));
+        assert_ne!(true_board(&boards[0]), true_board(&boards[2]));
+    }
+
+    #[test]
+    fn counts_one_3bv_per_opening_and_per_number_outside_them() {
+        assert_eq!(MultiplayerBoard::with_mines(4, 4, 0, &CORNERS).count_three_bv(), (12, 0));
+        // Two openings, each bordered by one of the numbers either side of the mine.
+        assert_eq!(MultiplayerBoard::with_mines(5, 1, 0, &[(0, 2)]).count_three_bv(), (2, 0));
+        assert_eq!(MultiplayerBoard::with_mines(5, 5, 0, &[(0, 0)]).count_three_bv(), (1, 0));
+    }
+
+    #[test]
+    fn tracks_each_players_clicks_until_the_game_is_over() {
+        let mut board = started_board(5, 1, &[(0, 2)]);
+        board.first_click_ms = Some(0);
+        board.reveal_square((0, 0), 1, 1_000).unwrap();
+        board.reveal_square((0, 1), 1, 1_000).unwrap_err();
+        board.cycle_mark_square((0, 2), 2, &[Mark::Flag]).unwrap();
+        board.reveal_square((0, 4), 2, 2_000).unwrap();
+        board.reveal_square((0, 4), 2, 3_000).unwrap_err();
+
+        let stats = board.stats(2_000).unwrap();
+        assert_eq!((stats.three_bv, stats.solved_three_bv, stats.elapsed_ms), (2, 2, 2_000));
+        assert_eq!(stats.three_bv_per_second, 1.0);
+        assert_eq!(stats.efficiency, 50.0);
+        assert_eq!(
+            stats.clicks,
+            vec![
+                (1, ClickCounts { effective: 1, wasted: 1, flags: 0 }),
+                (2, ClickCounts { effective: 1, wasted: 0, flags: 1 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn forgets_the_squares_and_clicks_of_a_player_who_is_gone() {
+        let mut board = started_board(5, 1, &[(0, 2)]);
+        board.reveal_square((0, 0), 1, 0).unwrap();
+        board.cycle_mark_square((0, 2), 2, &[Mark::Flag]).unwrap();
+        board.forget_player(1);
+        assert_eq!(board.player_ids()[0], vec![0, 0, 2, 0, 0]);
+        assert_eq!(board.stats(0).unwrap().clicks, vec![(2, ClickCounts { effective: 0, wasted: 0, flags: 1 })]);
+    }
+
+    #[test]
+    fn plays_on_past_hit_mines_when_surviving_them() {
+        let mut board = started_board(4, 4, &CORNERS);
+        board.set_survive_mines(true);
+        let result = board.reveal_square((0, 0), 1, 0).unwrap();
+        assert_eq!((result.outcome, result.exploded), (Outcome::Ongoing, vec![(0, 0)]));
+        assert_eq!(*board.board_state(), BoardState::Ongoing);
+
+        // The hit mine counts as a flag next to the 1.
+        board.reveal_square((1, 1), 2, 0).unwrap();
+        assert_eq!(board.chord_square((1, 1), 2, 0).unwrap().changes.len(), 7);
+        assert_eq!(board.lose(5), Outcome::Lost { hit_mines: vec![(0, 0)], wrong_flags: vec![] });
+        assert_eq!(board.finish_ms(), Some(5));
+    }
+
+    #[test]
+    fn records_whose_reveal_opened_each_square() {
+        let mut board = MultiplayerBoard::with_mines(4, 4, 0, &[(3, 3)]);
+        board.reveal_square((0, 0), 5, 0).unwrap();
+        assert_eq!((board.player_at((0, 0)), board.player_at((2, 2))), (0, 0));
+        assert_eq!((board.cascade_owner_at((0, 0)), board.cascade_owner_at((2, 2))), (5, 5));
+        assert_eq!(board.cascade_owner_at((3, 3)), 0);
+
+        board.forget_player(5);
+        assert_eq!(board.cascade_owner_at((2, 2)), 0);
+    }
+
+    #[test]
+    fn cycles_marks_but_counts_only_flags_when_chording() {
+        let mut board = started_board(4, 4, &CORNERS);
+        let marks: Vec<Entry> = (0..4).map(|_| board.cycle_mark_square((0, 0), 1, &MARK_CYCLE).unwrap().entry).collect();
+        assert_eq!(marks, vec![
+            Entry::Unknown { mark: Some(Mark::Flag) },
+            Entry::Unknown { mark: Some(Mark::Question) },
+            Entry::Unknown { mark: Some(Mark::Safe) },
+            Entry::Unknown { mark: None },
+        ]);
+
+        board.cycle_mark_square((0, 0), 1, &[Mark::Question]).unwrap();
+        board.reveal_square((1, 1), 1, 0).unwrap();
+        assert_eq!(board.chord_square((1, 1), 1, 0), Err(Rejection::FlagCountMismatch { num_mines: 1, num_flags: 0 }));
+        assert_eq!(Mark::next(Some(Mark::Question), &[Mark::Flag]), Some(Mark::Flag));
+    }
+
+    #[test]
+    fn shows_flags_as_wrong_only_once_the_revealed_squares_prove_it() {
+        let mut board = started_board(4, 4, &CORNERS);
+        board.set_survive_mines(true);
+        board.set_show_wrong_flags(true);
+        let flag = |board: &mut MultiplayerBoard, coords| board.cycle_mark_square(coords, 1, &[Mark::Flag]).unwrap().entry;
+        board.reveal_square((1, 1), 1, 0).unwrap();
+        // The 1 could still be the mine under this flag for all anyone has seen.
+        assert_eq!(flag(&mut board, (0, 1)), Entry::Unknown { mark: Some(Mark::Flag) });
+
+        board.reveal_square((0, 0), 1, 0).unwrap();
+        assert_eq!(board.revealed_entry_at((0, 1)), Entry::Unknown { mark: Some(Mark::WrongFlag) });
+        assert_eq!(flag(&mut board, (1, 0)), Entry::Unknown { mark: Some(Mark::WrongFlag) });
+        // Nothing revealed says anything about this one, though it isn't on a mine either.
+        assert_eq!(flag(&mut board, (2, 3)), Entry::Unknown { mark: Some(Mark::Flag) });
+
+        // The 1 in the corner has its mine among squares the 1 beside it shares, so the last
+        // square next to the second 1 is safe.
+        let mut board = started_board(4, 2, &[(0, 0), (0, 3)]);
+        board.set_show_wrong_flags(true);
+        flag(&mut board, (0, 0));
+        flag(&mut board, (0, 2));
+        board.reveal_square((1, 0), 1, 0).unwrap();
+        assert_eq!(board.revealed_entry_at((0, 2)), Entry::Unknown { mark: Some(Mark::Flag) });
+        board.reveal_square((1, 1), 1, 0).unwrap();
+        assert_eq!(board.revealed_entry_at((0, 2)), Entry::Unknown { mark: Some(Mark::WrongFlag) });
+        assert_eq!(board.revealed_entry_at((0, 0)), Entry::Unknown { mark: Some(Mark::Flag) });
+    }
+}
@@ -0,0 +1,302 @@
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::board;
+use crate::history::Reader;
+
+// Version of the record format described at the bottom of this file.
+pub const DAILY_VERSION: u16 = 1;
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+pub const SECRET_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    Beginner = 0,
+    Intermediate = 1,
+    Expert = 2,
+}
+
+impl Preset {
+    pub fn from_u8(preset: u8) -> Option<Preset> {
+        match preset {
+            0 => Some(Preset::Beginner),
+            1 => Some(Preset::Intermediate),
+            2 => Some(Preset::Expert),
+            _ => None,
+        }
+    }
+
+    // Width, height and number of mines.
+    pub fn config(self) -> (u16, u16, u32) {
+        match self {
+            Preset::Beginner => (9, 9, 10),
+            Preset::Intermediate => (16, 16, 40),
+            Preset::Expert => (30, 16, 99),
+        }
+    }
+}
+
+// Days since the Unix epoch (UTC).
+pub fn day_of(time_ms: u64) -> u32 {
+    (time_ms / MS_PER_DAY) as u32
+}
+
+// The server's secret which daily boards are made from, so that nobody can work out a board from
+// its date ahead of playing it.
+pub struct DailySeeds {
+    secret: [u8; SECRET_LEN],
+}
+
+impl DailySeeds {
+    pub fn new(secret: [u8; SECRET_LEN]) -> DailySeeds {
+        DailySeeds { secret }
+    }
+
+    // Reads the secret file at the given path, or writes a new random secret there if there is no
+    // file yet.
+    pub fn load_or_create(path: &std::path::Path, rng: &mut impl RngCore) -> std::io::Result<DailySeeds> {
+        match std::fs::read(path) {
+            Ok(bytes) => {
+                let secret = bytes.try_into().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is not a daily secret file", path.display()))
+                })?;
+                Ok(DailySeeds::new(secret))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let mut secret = [0; SECRET_LEN];
+                rng.fill_bytes(&mut secret);
+                std::fs::write(path, secret)?;
+                Ok(DailySeeds::new(secret))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // The board everyone plays for the given day and preset, and the square guaranteed not to be a
+    // mine.
+    pub fn board(&self, day: u32, preset: Preset) -> (board::MultiplayerBoard, (u16, u16)) {
+        let (width, height, num_mines) = preset.config();
+        let safe_square = (height / 2, width / 2);
+        (board::MultiplayerBoard::with_safe_square(width, height, num_mines, self.seed(day, preset), safe_square), safe_square)
+    }
+
+    // ChaCha20 keyed with the secret, on a stream of its own for each day and preset.
+    fn seed(&self, day: u32, preset: Preset) -> u64 {
+        let mut rng = ChaCha20Rng::from_seed(self.secret);
+        rng.set_stream((day as u64) << 8 | preset as u64);
+        rng.next_u64()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DailyOutcome {
+    Unfinished = 0, // Started but not yet lost or won. Still uses up the attempt.
+    Lost = 1,
+    Won = 2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DailyResult {
+    pub day: u32,
+    pub preset: Preset,
+    pub identity: String, // Stays the same across games and restarts, unlike player IDs.
+    pub name: String,
+    pub outcome: DailyOutcome,
+    pub duration_ms: u64,     // Time on the game clock.
+    pub solved_three_bv: u32, // Breaks ties between losses.
+}
+
+impl DailyResult {
+    // One record of the daily results file, including its length prefix.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 4]; // Length, filled in at the end.
+        bytes.extend_from_slice(&DAILY_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.day.to_le_bytes());
+        bytes.push(self.preset as u8);
+        for string in [&self.identity, &self.name] {
+            let string = &string.as_bytes()[..string.len().min(u8::MAX as usize)];
+            bytes.push(string.len() as u8);
+            bytes.extend_from_slice(string);
+        }
+        bytes.push(self.outcome as u8);
+        bytes.extend_from_slice(&self.duration_ms.to_le_bytes());
+        bytes.extend_from_slice(&self.solved_three_bv.to_le_bytes());
+
+        let length = (bytes.len() - 4) as u32;
+        bytes[..4].copy_from_slice(&length.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<DailyResult> {
+        let mut reader = Reader::new(bytes);
+        if reader.u16()? != DAILY_VERSION {
+            return None;
+        }
+        let day = reader.u32()?;
+        let preset = Preset::from_u8(reader.u8()?)?;
+        let identity_len = reader.u8()?;
+        let identity = String::from_utf8_lossy(reader.take(identity_len as usize)?).into_owned();
+        let name_len = reader.u8()?;
+        let name = String::from_utf8_lossy(reader.take(name_len as usize)?).into_owned();
+        let outcome = match reader.u8()? {
+            0 => DailyOutcome::Unfinished,
+            1 => DailyOutcome::Lost,
+            2 => DailyOutcome::Won,
+            _ => return None,
+        };
+        let duration_ms = reader.u64()?;
+        let solved_three_bv = reader.u32()?;
+        Some(DailyResult { day, preset, identity, name, outcome, duration_ms, solved_three_bv })
+    }
+}
+
+// Every attempt at a daily challenge, latest result only.
+pub struct DailyResults {
+    results: Vec<DailyResult>,
+}
+
+impl DailyResults {
+    pub fn new() -> DailyResults {
+        DailyResults { results: vec![] }
+    }
+
+    // Reads the daily results file at the given path, if there is one. Records are appended as
+    // attempts start and finish, so later records replace earlier ones for the same attempt.
+    pub fn load(path: &std::path::Path) -> std::io::Result<DailyResults> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(DailyResults::new()),
+            Err(err) => return Err(err),
+        };
+        let mut results = DailyResults::new();
+        let mut offset = 0;
+        while offset + 4 <= bytes.len() {
+            let length = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            let Some(record_bytes) = bytes.get(offset + 4..offset + 4 + length) else {
+                break;
+            };
+            match DailyResult::decode(record_bytes) {
+                Some(result) => results.record(result),
+                None => println!("Skipping unreadable daily result at byte {offset} of {}.", path.display()),
+            }
+            offset += 4 + length;
+        }
+        if offset < bytes.len() {
+            println!("Dropping incomplete daily result at byte {offset} of {}.", path.display());
+            std::fs::OpenOptions::new().write(true).open(path)?.set_len(offset as u64)?;
+        }
+        Ok(results)
+    }
+
+    pub fn get(&self, day: u32, preset: Preset, identity: &str) -> Option<&DailyResult> {
+        self.results.iter().find(|result| result.day == day && result.preset == preset && result.identity == identity)
+    }
+
+    pub fn record(&mut self, result: DailyResult) {
+        self.results.retain(|other| {
+            !(other.day == result.day && other.preset == result.preset && other.identity == result.identity)
+        });
+        self.results.push(result);
+    }
+
+    // Finished attempts at the given challenge, best first: wins by time, then losses by how much
+    // of the board was solved.
+    pub fn ranking(&self, day: u32, preset: Preset) -> Vec<&DailyResult> {
+        let mut ranking: Vec<&DailyResult> = self.results.iter()
+            .filter(|result| result.day == day && result.preset == preset && result.outcome != DailyOutcome::Unfinished)
+            .collect();
+        ranking.sort_by(|a, b| match (a.outcome, b.outcome) {
+            (DailyOutcome::Won, DailyOutcome::Won) => a.duration_ms.cmp(&b.duration_ms),
+            (DailyOutcome::Won, _) => std::cmp::Ordering::Less,
+            (_, DailyOutcome::Won) => std::cmp::Ordering::Greater,
+            _ => b.solved_three_bv.cmp(&a.solved_three_bv).then(a.duration_ms.cmp(&b.duration_ms)),
+        });
+        ranking
+    }
+}
+
+/*
+ * Daily Results File Format:
+ *   Appended to as daily challenge attempts start and finish. Little Endian.
+ *   The file is a series of records, each of which is:
+ *   u32: length of the rest of the record.
+ *   u16: record version, currently 1. Readers skip records of versions they don't know.
+ *   u32: day of the challenge, in days since the Unix epoch (UTC).
+ *   u8: preset.
+ *     0: beginner, 9x9 with 10 mines.
+ *     1: intermediate, 16x16 with 40 mines.
+ *     2: expert, 30x16 with 99 mines.
+ *   u8: length of identity.
+ *   (length of identity) bytes: identity in UTF-8. "token:" followed by the token the player
+ *     connected with, or their IP address if they connected without one.
+ *   u8: length of name.
+ *   (length of name) bytes: name the player had during the attempt, in UTF-8.
+ *   u8: outcome.
+ *     0: unfinished.
+ *     1: lost.
+ *     2: won.
+ *   u64: time on the game clock in milliseconds.
+ *   u32: part of the board's 3BV which was revealed.
+ *   A later record for the same day, preset and identity replaces an earlier one.
+ */
+
+/*
+ * Daily Secret File Format:
+ *   32 random bytes, written when the server first starts without one. The daily boards are made
+ *   from it, so they change if it does, and anyone who has it can work out every daily board.
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(identity: &str, outcome: DailyOutcome, duration_ms: u64, solved_three_bv: u32) -> DailyResult {
+        DailyResult {
+            day: 1,
+            preset: Preset::Beginner,
+            identity: identity.to_string(),
+            name: identity.to_uppercase(),
+            outcome,
+            duration_ms,
+            solved_three_bv,
+        }
+    }
+
+    #[test]
+    fn makes_the_same_board_only_from_the_same_secret_day_and_preset() {
+        let seeds = DailySeeds::new([1; SECRET_LEN]);
+        let mines = |seeds: &DailySeeds, day, preset| seeds.board(day, preset).0.mines();
+        assert_eq!(mines(&seeds, 1, Preset::Expert), mines(&DailySeeds::new([1; SECRET_LEN]), 1, Preset::Expert));
+        assert_ne!(mines(&seeds, 1, Preset::Expert), mines(&DailySeeds::new([2; SECRET_LEN]), 1, Preset::Expert));
+        assert_ne!(mines(&seeds, 1, Preset::Expert), mines(&seeds, 2, Preset::Expert));
+
+        let (board, safe_square) = seeds.board(1, Preset::Beginner);
+        assert_eq!((board.width(), board.height(), board.num_mines()), (9, 9, 10));
+        assert!(!board.mines().unwrap().contains(&safe_square));
+    }
+
+    #[test]
+    fn ranks_wins_by_time_ahead_of_losses_by_progress() {
+        let mut results = DailyResults::new();
+        results.record(result("slow", DailyOutcome::Won, 9_000, 30));
+        results.record(result("close", DailyOutcome::Lost, 1_000, 25));
+        results.record(result("fast", DailyOutcome::Unfinished, 0, 0));
+        results.record(result("early", DailyOutcome::Lost, 500, 2));
+        results.record(result("fast", DailyOutcome::Won, 4_000, 30));
+
+        let ranking: Vec<&str> = results.ranking(1, Preset::Beginner).iter().map(|result| result.identity.as_str()).collect();
+        assert_eq!(ranking, vec!["fast", "slow", "close", "early"]);
+        assert!(results.ranking(1, Preset::Expert).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_daily_secret_across_restarts() {
+        let path = std::env::temp_dir().join(format!("daily-secret-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut rng = rand::rngs::mock::StepRng::new(7, 1);
+        let created = DailySeeds::load_or_create(&path, &mut rng).unwrap();
+        let loaded = DailySeeds::load_or_create(&path, &mut rng).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(created.secret, loaded.secret);
+    }
+}
@@ -1,4 +1,76 @@
 use crate::board;
+use crate::daily::{self, DailyResult};
+use crate::game::PlayerStats;
+use crate::history::GameRecord;
+use crate::leaderboard::{self, LeaderboardRow};
+use crate::mode::{FlagRules, GameMode};
+
+// Most games listed in one Game History packet.
+pub const MAX_GAME_HISTORY_PAGE: usize = 50;
+
+// Version of the protocol described at the bottom of this file. Bump whenever a packet layout changes.
+pub const PROTOCOL_VERSION: u16 = 4;
+// Oldest version a client may ask for in its Hello packet.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+// Optional capabilities this server supports. A client advertises the ones it understands in its
+// Hello packet and the server only uses those in the intersection.
+pub const SERVER_CAPABILITIES: u32 = CAPABILITY_ACTION_REJECTED
+    | CAPABILITY_REPLAYS
+    | CAPABILITY_TRUE_BOARD
+    | CAPABILITY_GAME_CLOCK
+    | CAPABILITY_GAME_STATS
+    | CAPABILITY_GAME_SUMMARY
+    | CAPABILITY_GAME_MODES
+    | CAPABILITY_MARKS
+    | CAPABILITY_WRONG_FLAGS;
+
+// Client wants to be told when its reveals, chords and flags did nothing.
+pub const CAPABILITY_ACTION_REJECTED: u32 = 1 << 0;
+// Client wants to be told when a finished game can be replayed.
+pub const CAPABILITY_REPLAYS: u32 = 1 << 1;
+// Client wants to be sent where every mine was once a game is over.
+pub const CAPABILITY_TRUE_BOARD: u32 = 1 << 2;
+// Client wants to be kept in sync with the game clock.
+pub const CAPABILITY_GAME_CLOCK: u32 = 1 << 3;
+// Client wants 3BV and click statistics when a game ends.
+pub const CAPABILITY_GAME_STATS: u32 = 1 << 4;
+// Client wants to know what each player did when a game ends.
+pub const CAPABILITY_GAME_SUMMARY: u32 = 1 << 5;
+// Client understands game modes other than co-op and the packets they bring.
+pub const CAPABILITY_GAME_MODES: u32 = 1 << 6;
+// Client understands question and safe marks, and marks private to its player.
+pub const CAPABILITY_MARKS: u32 = 1 << 7;
+// Client can show flags which have been shown to be wrong.
+pub const CAPABILITY_WRONG_FLAGS: u32 = 1 << 8;
+
+// What has been agreed on with a single client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Protocol {
+    pub version: u16,
+    pub capabilities: u32,
+}
+
+impl Protocol {
+    // Spoken with clients that never send a Hello packet. Only the packets that existed before the
+    // handshake are sent to them.
+    pub const LEGACY: Protocol = Protocol { version: 0, capabilities: 0 };
+
+    // Returns None if the client's version is too old to be served.
+    pub fn negotiate(version: u16, capabilities: u32) -> Option<Protocol> {
+        if version < MIN_PROTOCOL_VERSION {
+            None
+        } else {
+            Some(Protocol {
+                version: version.min(PROTOCOL_VERSION),
+                capabilities: capabilities & SERVER_CAPABILITIES,
+            })
+        }
+    }
+
+    pub fn has_capability(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+}
 
 #[derive(Debug)]
 pub enum ClientToServerPacket {
@@ -7,65 +79,281 @@ pub enum ClientToServerPacket {
     ChordSquare((u16, u16)),
     NewBoard,
     NextBoardConfig{width: u16, height: u16, num_mines: u32},
+    Hello { version: u16, capabilities: u32 },
+    StartReplay { game_id: u64 },
+    ReplaySpeed { speed_percent: u16 },
+    StepReplay,
+    SeekReplay { num_actions: u32 },
+    StopReplay,
+    RequestGameHistory { before_game_id: u64, max_games: u8 },
+    RequestGame { game_id: u64 },
+    RequestLeaderboard { period: leaderboard::Period, metric: leaderboard::Metric, config: leaderboard::BoardConfig },
+    StartDaily { preset: daily::Preset },
+    LeaveDaily,
+    RequestDailyRanking { preset: daily::Preset },
+    NextGameMode(GameMode),
+    ChooseTeam { team: u8 },
+    CycleMark { coords: (u16, u16), private: bool },
+    NextFlagRules(FlagRules),
 }
 
 impl ClientToServerPacket {
-    pub fn decode(bytes: &[u8]) -> Result<ClientToServerPacket, ()> {
-        if bytes.len() < 1 {
-            return Err(());
+    pub fn decode(bytes: &[u8]) -> Result<ClientToServerPacket, PacketError> {
+        if bytes.is_empty() {
+            return Err(PacketError::Empty);
         }
         let packet_type = bytes[0];
         match packet_type {
             0 => {
-                if bytes.len() != 5 {
-                    Err(())
-                } else {
-                    let i = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
-                    let j = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
-                    Ok(ClientToServerPacket::RevealSquare((i, j)))
-                }
+                check_length(bytes, 5)?;
+                let i = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
+                let j = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
+                Ok(ClientToServerPacket::RevealSquare((i, j)))
             }
             1 => {
-                if bytes.len() != 5 {
-                    Err(())
-                } else {
-                    let i = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
-                    let j = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
-                    Ok(ClientToServerPacket::ChordSquare((i, j)))
-                }
+                check_length(bytes, 5)?;
+                let i = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
+                let j = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
+                Ok(ClientToServerPacket::ChordSquare((i, j)))
             },
             2 => {
-                if bytes.len() != 5 {
-                    Err(())
-                } else {
-                    let i = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
-                    let j = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
-                    Ok(ClientToServerPacket::ToggleFlagSquare((i, j)))
-                }
+                check_length(bytes, 5)?;
+                let i = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
+                let j = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
+                Ok(ClientToServerPacket::ToggleFlagSquare((i, j)))
             }
             3 => {
-                if bytes.len() != 1 {
-                    Err(())
-                } else {
-                    Ok(ClientToServerPacket::NewBoard)
-                }
+                check_length(bytes, 1)?;
+                Ok(ClientToServerPacket::NewBoard)
             }
             4 => {
-                if bytes.len() != 9 {
-                    Err(())
-                } else {
-                    let width = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
-                    let height = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
-                    let num_mines = u32::from_le_bytes(bytes[5..9].try_into().unwrap());
-                    Ok(ClientToServerPacket::NextBoardConfig { width, height, num_mines })
+                check_length(bytes, 9)?;
+                let width = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
+                let height = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
+                let num_mines = u32::from_le_bytes(bytes[5..9].try_into().unwrap());
+                if !board::MultiplayerBoard::possible_config(width, height, num_mines) {
+                    return Err(PacketError::InvalidConfig { width, height, num_mines });
                 }
+                Ok(ClientToServerPacket::NextBoardConfig { width, height, num_mines })
+            }
+            5 => {
+                check_length(bytes, 7)?;
+                let version = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
+                let capabilities = u32::from_le_bytes(bytes[3..7].try_into().unwrap());
+                Ok(ClientToServerPacket::Hello { version, capabilities })
+            }
+            6 => {
+                check_length(bytes, 9)?;
+                let game_id = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+                Ok(ClientToServerPacket::StartReplay { game_id })
+            }
+            7 => {
+                check_length(bytes, 3)?;
+                let speed_percent = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
+                Ok(ClientToServerPacket::ReplaySpeed { speed_percent })
+            }
+            8 => {
+                check_length(bytes, 1)?;
+                Ok(ClientToServerPacket::StepReplay)
+            }
+            9 => {
+                check_length(bytes, 5)?;
+                let num_actions = u32::from_le_bytes(bytes[1..5].try_into().unwrap());
+                Ok(ClientToServerPacket::SeekReplay { num_actions })
+            }
+            10 => {
+                check_length(bytes, 1)?;
+                Ok(ClientToServerPacket::StopReplay)
+            }
+            11 => {
+                check_length(bytes, 10)?;
+                let before_game_id = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+                let max_games = bytes[9];
+                Ok(ClientToServerPacket::RequestGameHistory { before_game_id, max_games })
+            }
+            12 => {
+                check_length(bytes, 9)?;
+                let game_id = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+                Ok(ClientToServerPacket::RequestGame { game_id })
+            }
+            13 => {
+                check_length(bytes, 11)?;
+                let period = match bytes[1] {
+                    0 => leaderboard::Period::Lifetime,
+                    1 => leaderboard::Period::Weekly,
+                    _ => return Err(PacketError::UnknownLeaderboard { period: bytes[1], metric: bytes[2] }),
+                };
+                let metric = match bytes[2] {
+                    0 => leaderboard::Metric::GamesPlayed,
+                    1 => leaderboard::Metric::Wins,
+                    2 => leaderboard::Metric::SquaresCleared,
+                    3 => leaderboard::Metric::FastestWin,
+                    _ => return Err(PacketError::UnknownLeaderboard { period: bytes[1], metric: bytes[2] }),
+                };
+                let width = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
+                let height = u16::from_le_bytes(bytes[5..7].try_into().unwrap());
+                let num_mines = u32::from_le_bytes(bytes[7..11].try_into().unwrap());
+                Ok(ClientToServerPacket::RequestLeaderboard { period, metric, config: (width, height, num_mines) })
+            }
+            14 => {
+                check_length(bytes, 2)?;
+                let preset = daily::Preset::from_u8(bytes[1]).ok_or(PacketError::UnknownPreset(bytes[1]))?;
+                Ok(ClientToServerPacket::StartDaily { preset })
+            }
+            15 => {
+                check_length(bytes, 1)?;
+                Ok(ClientToServerPacket::LeaveDaily)
+            }
+            16 => {
+                check_length(bytes, 2)?;
+                let preset = daily::Preset::from_u8(bytes[1]).ok_or(PacketError::UnknownPreset(bytes[1]))?;
+                Ok(ClientToServerPacket::RequestDailyRanking { preset })
+            }
+            17 => {
+                check_length(bytes, 6)?;
+                let param = u32::from_le_bytes(bytes[2..6].try_into().unwrap());
+                let mode = GameMode::decode(bytes[1], param).ok_or(PacketError::InvalidGameMode { mode: bytes[1], param })?;
+                Ok(ClientToServerPacket::NextGameMode(mode))
             }
-            _ => Err(()),
+            18 => {
+                check_length(bytes, 2)?;
+                Ok(ClientToServerPacket::ChooseTeam { team: bytes[1] })
+            }
+            19 => {
+                check_length(bytes, 6)?;
+                let i = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
+                let j = u16::from_le_bytes(bytes[3..5].try_into().unwrap());
+                Ok(ClientToServerPacket::CycleMark { coords: (i, j), private: bytes[5] != 0 })
+            }
+            20 => {
+                check_length(bytes, 3)?;
+                let rules = FlagRules::decode(bytes[1], bytes[2])
+                    .ok_or(PacketError::InvalidFlagRules { removal: bytes[1], options: bytes[2] })?;
+                Ok(ClientToServerPacket::NextFlagRules(rules))
+            }
+            _ => Err(PacketError::UnknownPacketType(packet_type)),
         }
     }
 
 }
 
+fn check_length(bytes: &[u8], expected: usize) -> Result<(), PacketError> {
+    if bytes.len() == expected {
+        Ok(())
+    } else {
+        Err(PacketError::WrongLength { packet_type: bytes[0], expected, actual: bytes.len() })
+    }
+}
+
+// Something a client sent that the server could not act on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketError {
+    UnsupportedVersion(u16),
+    UnexpectedHello,
+    Empty,
+    UnknownPacketType(u8),
+    WrongLength { packet_type: u8, expected: usize, actual: usize },
+    InvalidConfig { width: u16, height: u16, num_mines: u32 },
+    UnknownGame(u64),
+    NotReplaying,
+    UnknownGameRecord(u64),
+    UnknownLeaderboard { period: u8, metric: u8 },
+    UnknownPreset(u8),
+    DailyAlreadyAttempted,
+    InvalidGameMode { mode: u8, param: u32 },
+    InvalidTeam(u8),
+    InvalidFlagRules { removal: u8, options: u8 },
+}
+
+// How the server treats a client which caused an error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorResponse {
+    Ignore,     // Log it and carry on.
+    Warn,       // Send the client an Error packet and carry on.
+    Disconnect, // Send the client an Error packet and close the connection.
+}
+
+impl PacketError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            PacketError::UnsupportedVersion(..) => ErrorCode::UnsupportedVersion,
+            PacketError::UnexpectedHello => ErrorCode::UnexpectedHello,
+            PacketError::Empty | PacketError::WrongLength { .. } => ErrorCode::WrongLength,
+            PacketError::UnknownPacketType(..) => ErrorCode::UnknownPacketType,
+            PacketError::InvalidConfig { .. } => ErrorCode::InvalidConfig,
+            PacketError::UnknownGame(..) => ErrorCode::UnknownGame,
+            PacketError::NotReplaying => ErrorCode::NotReplaying,
+            PacketError::UnknownGameRecord(..) => ErrorCode::UnknownGameRecord,
+            PacketError::UnknownLeaderboard { .. } => ErrorCode::UnknownLeaderboard,
+            PacketError::UnknownPreset(..) => ErrorCode::UnknownPreset,
+            PacketError::DailyAlreadyAttempted => ErrorCode::DailyAlreadyAttempted,
+            PacketError::InvalidGameMode { .. } => ErrorCode::InvalidGameMode,
+            PacketError::InvalidTeam(..) => ErrorCode::InvalidTeam,
+            PacketError::InvalidFlagRules { .. } => ErrorCode::InvalidFlagRules,
+        }
+    }
+
+    pub fn response(&self) -> ErrorResponse {
+        match self {
+            // Nothing sensible can be sent to a client we don't share a protocol with.
+            PacketError::UnsupportedVersion(..) => ErrorResponse::Disconnect,
+            // A malformed packet of a known type means the client is out of sync with the protocol.
+            PacketError::Empty | PacketError::WrongLength { .. } => ErrorResponse::Disconnect,
+            // Possibly a newer client trying a packet this server doesn't know yet.
+            PacketError::UnknownPacketType(..) => ErrorResponse::Warn,
+            PacketError::UnexpectedHello | PacketError::InvalidConfig { .. } | PacketError::InvalidGameMode { .. } => ErrorResponse::Warn,
+            PacketError::InvalidFlagRules { .. } => ErrorResponse::Warn,
+            // The game may have been pushed out of the replay library since the client heard of it.
+            PacketError::UnknownGame(..) => ErrorResponse::Warn,
+            PacketError::UnknownGameRecord(..) => ErrorResponse::Warn,
+            PacketError::UnknownLeaderboard { .. } => ErrorResponse::Warn,
+            PacketError::UnknownPreset(..) | PacketError::DailyAlreadyAttempted => ErrorResponse::Warn,
+            // Possibly a team choice racing the start of the board.
+            PacketError::InvalidTeam(..) => ErrorResponse::Warn,
+            // Replay controls racing the end of a replay.
+            PacketError::NotReplaying => ErrorResponse::Ignore,
+        }
+    }
+
+    pub fn to_packet(&self) -> ServerToClientPacket {
+        ServerToClientPacket::Error { code: self.code(), message: self.to_string() }
+    }
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            PacketError::UnsupportedVersion(version) => write!(
+                f,
+                "Protocol version {version} is not supported. Supported versions are {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}."
+            ),
+            PacketError::UnexpectedHello => write!(f, "Hello was sent after the protocol was already settled."),
+            PacketError::Empty => write!(f, "Empty packet."),
+            PacketError::UnknownPacketType(packet_type) => write!(f, "Unknown packet type {packet_type}."),
+            PacketError::WrongLength { packet_type, expected, actual } => write!(
+                f,
+                "Packet of type {packet_type} should be {expected} bytes long but was {actual}."
+            ),
+            PacketError::InvalidConfig { width, height, num_mines } => write!(
+                f,
+                "Invalid board config: {width}x{height} with {num_mines} mines."
+            ),
+            PacketError::UnknownGame(game_id) => write!(f, "No replay of game {game_id} is available."),
+            PacketError::NotReplaying => write!(f, "Replay control sent while not watching a replay."),
+            PacketError::UnknownGameRecord(game_id) => write!(f, "No record of game {game_id} exists."),
+            PacketError::UnknownLeaderboard { period, metric } => write!(
+                f,
+                "There is no leaderboard for period {period} and metric {metric}."
+            ),
+            PacketError::UnknownPreset(preset) => write!(f, "Unknown difficulty preset {preset}."),
+            PacketError::DailyAlreadyAttempted => write!(f, "Today's challenge on this preset has already been attempted."),
+            PacketError::InvalidGameMode { mode, param } => write!(f, "Invalid game mode {mode} with setting {param}."),
+            PacketError::InvalidTeam(team) => write!(f, "Team {team} can't be joined now."),
+            PacketError::InvalidFlagRules { removal, options } => write!(f, "Invalid flag rules {removal} with options {options}."),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ServerToClientPacket {
     FullBoard {
@@ -75,9 +363,10 @@ pub enum ServerToClientPacket {
         board_state: board::BoardState,
         entries: Vec<Vec<board::Entry>>,
         player_ids: Vec<Vec<u8>>,
+        cascade_owners: Vec<Vec<u8>>,
     },
     SparseBoard {
-        updates: Vec<(u16, u16, u8, u8)>,
+        updates: Vec<(u16, u16, u8, u8, u8)>, // i, j, entry, player ID, cascade owner.
     },
     PlayerInfo {
         player_id: u8,
@@ -91,75 +380,182 @@ pub enum ServerToClientPacket {
     },
     BoardLoss {
         loser_id: u8,
+        duration_ms: u64,
         hit_mines: Vec<(u16, u16)>,
         wrong_flags: Vec<(u16, u16)>,
     },
-    BoardWin,
+    BoardWin { duration_ms: u64 },
     NextBoardConfig { width: u16, height: u16, num_mines: u32 },
+    Welcome { version: u16, capabilities: u32 },
+    Error { code: ErrorCode, message: String },
+    ActionRejected { action: Action, coords: (u16, u16), rejection: board::Rejection },
+    GameRecorded { game_id: u64 },
+    GameHistory { games: Vec<GameRecord> },
+    GameDetails { game: GameRecord },
+    TrueBoard { mines: Vec<(u16, u16)> },
+    GameClock { first_click_ms: u64, finish_ms: u64, elapsed_ms: u64 },
+    GameStats(board::BoardStats),
+    GameSummary { players: Vec<(u8, PlayerStats)> },
+    Leaderboard {
+        period: leaderboard::Period,
+        metric: leaderboard::Metric,
+        config: leaderboard::BoardConfig,
+        rows: Vec<LeaderboardRow>,
+    },
+    DailyChallenge { day: u32, preset: daily::Preset, safe_square: (u16, u16) },
+    DailyRanking { day: u32, preset: daily::Preset, results: Vec<DailyResult> },
+    GameMode(GameMode),
+    NextGameMode(GameMode),
+    SafeSquare { coords: (u16, u16) },
+    RaceProgress { player_id: u8, squares_cleared: u32, squares_to_clear: u32, board_state: board::BoardState },
+    RaceFinished { winner_id: u8, duration_ms: u64 },
+    PlayerTeam { player_id: u8, team: u8 },
+    TeamScores { winning_team: u8, scores: Vec<(u8, u32)> },
+    PlayerEliminated { player_id: u8 },
+    Standings { player_ids: Vec<u8> },
+    Lives { lives_left: u8, lives: u8 },
+    PlayerFrozen { player_id: u8, until_ms: u64, remaining_ms: u32, score: u32 },
+    TurnChanged { player_id: u8, until_ms: u64, remaining_ms: u32 },
+    Territory { winner_id: u8, areas: Vec<(u8, u32)> },
+    PrivateMark { coords: (u16, u16), mark: Option<board::Mark> },
+    FlagRules(FlagRules),
+    NextFlagRules(FlagRules),
+    FlagRemovalRequested { coords: (u16, u16), player_id: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    UnsupportedVersion = 0,
+    UnexpectedHello = 1,
+    UnknownPacketType = 2,
+    WrongLength = 3,
+    // 4 was coordinates off the board, which are now reported with an Action Rejected packet.
+    // Codes clients have seen are never reused, so new codes go at the end.
+    InvalidConfig = 5,
+    UnknownGame = 6,
+    NotReplaying = 7,
+    UnknownGameRecord = 8,
+    UnknownLeaderboard = 9,
+    UnknownPreset = 10,
+    DailyAlreadyAttempted = 11,
+    InvalidGameMode = 12,
+    InvalidTeam = 13,
+    InvalidFlagRules = 14,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Reveal = 0,
+    Chord = 1,
+    ToggleFlag = 2,
+    CycleMark = 3, // Marks shared with the room. Private marks are not actions on the board.
 }
 
 impl ServerToClientPacket {
-    pub fn encode(&self) -> Vec<u8> {
+    pub fn full_board(board: &board::MultiplayerBoard) -> ServerToClientPacket {
+        ServerToClientPacket::FullBoard {
+            width: board.width(),
+            height: board.height(),
+            num_mines: board.num_mines(),
+            board_state: *board.board_state(),
+            entries: board.revealed_board().clone(),
+            player_ids: board.player_ids().clone(),
+            cascade_owners: board.cascade_owners().clone(),
+        }
+    }
+
+    // Where every mine on a lost or won board is.
+    pub fn true_board(board: &board::MultiplayerBoard) -> ServerToClientPacket {
+        ServerToClientPacket::TrueBoard { mines: board.mines().expect("finished board has been constructed") }
+    }
+
+    pub fn game_clock(board: &board::MultiplayerBoard, now_ms: u64) -> ServerToClientPacket {
+        ServerToClientPacket::GameClock {
+            first_click_ms: board.first_click_ms().unwrap_or(0),
+            finish_ms: board.finish_ms().unwrap_or(0),
+            elapsed_ms: board.elapsed_ms(now_ms),
+        }
+    }
+
+    pub fn sparse_board(changes: &[board::SquareChange]) -> ServerToClientPacket {
+        let updates = changes.iter()
+            .map(|change| (change.coords.0, change.coords.1, encode_entry(&change.entry), change.player_id, change.cascade_owner))
+            .collect();
+        ServerToClientPacket::SparseBoard { updates }
+    }
+
+    // Returns None if the packet cannot be understood by a client speaking the given protocol.
+    pub fn encode(&self, protocol: Protocol) -> Option<Vec<u8>> {
+        if protocol.version < self.min_version() || !protocol.has_capability(self.required_capability()) {
+            return None;
+        }
         let mut bytes = vec![];
-        match self {
-            &Self::FullBoard { width, height, num_mines, board_state, ref entries, ref player_ids } => {
-                bytes.reserve_exact(1 + 2 + 2 + 4 + 1 +  2 * (width as usize) * (height as usize));
+        match *self {
+            Self::FullBoard { width, height, num_mines, board_state, ref entries, ref player_ids, ref cascade_owners } => {
+                bytes.reserve_exact(1 + 2 + 2 + 4 + 1 +  3 * (width as usize) * (height as usize));
                 bytes.push(0);
                 bytes.extend_from_slice(&width.to_le_bytes());
                 bytes.extend_from_slice(&height.to_le_bytes());
                 bytes.extend_from_slice(&num_mines.to_le_bytes());
-                match board_state {
-                    board::BoardState::Uninitialized | board::BoardState::Ongoing => {
-                        bytes.push(1);
-                    }
-                    board::BoardState::Lost => {
-                        bytes.push(2);
-                    }
-                    board::BoardState::Won => {
-                        bytes.push(3);
-                    }
+                bytes.push(encode_board_state(board_state));
+                for row in entries {
+                    bytes.extend(row.iter().map(|entry| entry_for(encode_entry(entry), protocol)));
                 }
-                for i in 0..height as usize {
-                    for j in 0..width as usize {
-                        bytes.push(encode_entry(&entries[i][j]));
-                    }
+                for row in player_ids {
+                    bytes.extend_from_slice(row);
                 }
-                for i in 0..height as usize {
-                    for j in 0..width as usize {
-                        bytes.push(player_ids[i][j]);
+                if protocol.version >= 4 {
+                    for row in cascade_owners {
+                        bytes.extend_from_slice(row);
                     }
                 }
             }
-            &Self::SparseBoard { ref updates } => {
-                bytes.reserve_exact(1 + 6 * updates.len());
+            Self::SparseBoard { ref updates } => {
+                bytes.reserve_exact(1 + 7 * updates.len());
                 bytes.push(1);
-                for &(i, j, entry, player_id) in updates {
+                for &(i, j, entry, player_id, cascade_owner) in updates {
                     bytes.extend_from_slice(&i.to_le_bytes());
                     bytes.extend_from_slice(&j.to_le_bytes());
-                    bytes.push(entry);
+                    bytes.push(entry_for(entry, protocol));
                     bytes.push(player_id);
+                    if protocol.version >= 4 {
+                        bytes.push(cascade_owner);
+                    }
                 }
             }
-            &Self::PlayerInfo { player_id, ref color, ref name } => {
+            Self::PlayerInfo { player_id, ref color, ref name } => {
                 bytes.reserve_exact(1 + 1 + 4);
                 bytes.push(2);
                 bytes.push(player_id);
                 bytes.extend_from_slice(color);
                 bytes.extend_from_slice(name.as_bytes());
             }
-            &Self::YourPlayerInfo {player_id, ref color, ref name} => {
+            Self::YourPlayerInfo {player_id, ref color, ref name} => {
                 bytes.reserve_exact(1 + 1 + 4);
                 bytes.push(3);
                 bytes.push(player_id);
                 bytes.extend_from_slice(color);
                 bytes.extend_from_slice(name.as_bytes());
             }
-            &Self::BoardLoss { loser_id, ref hit_mines, ref wrong_flags } => {
-                bytes.reserve_exact(1 + 1 + 1 + 4*hit_mines.len() + 4*wrong_flags.len());
+            Self::BoardLoss { loser_id, duration_ms, ref hit_mines, ref wrong_flags } => {
+                bytes.reserve_exact(1 + 1 + 8 + 2 + 4*hit_mines.len() + 4*wrong_flags.len());
                 bytes.push(4);
                 bytes.push(loser_id);
-                assert!(hit_mines.len() < u8::MAX as usize);
-                bytes.push(u8::try_from(hit_mines.len()).unwrap());
+                if protocol.version >= 2 {
+                    bytes.extend_from_slice(&duration_ms.to_le_bytes());
+                }
+                // A board lost once everyone is eliminated or out of lives lists every mine hit on
+                // it, which can be more than older clients can be told about. They still see those
+                // mines on the board.
+                let hit_mines = if protocol.version >= 3 {
+                    let count = hit_mines.len().min(u16::MAX as usize);
+                    bytes.extend_from_slice(&(count as u16).to_le_bytes());
+                    &hit_mines[..count]
+                } else {
+                    let count = hit_mines.len().min(u8::MAX as usize);
+                    bytes.push(count as u8);
+                    &hit_mines[..count]
+                };
                 for &(i, j) in hit_mines {
                     bytes.extend_from_slice(&i.to_le_bytes());
                     bytes.extend_from_slice(&j.to_le_bytes());
@@ -169,31 +565,628 @@ impl ServerToClientPacket {
                     bytes.extend_from_slice(&j.to_le_bytes());
                 }
             }
-            &Self::BoardWin => {
-                bytes.reserve_exact(1);
+            Self::BoardWin { duration_ms } => {
+                bytes.reserve_exact(1 + 8);
                 bytes.push(5);
+                if protocol.version >= 2 {
+                    bytes.extend_from_slice(&duration_ms.to_le_bytes());
+                }
             }
-            &Self::NextBoardConfig { width, height, num_mines } => {
+            Self::NextBoardConfig { width, height, num_mines } => {
                 bytes.reserve_exact(9);
                 bytes.push(6);
                 bytes.extend_from_slice(&width.to_le_bytes());
                 bytes.extend_from_slice(&height.to_le_bytes());
                 bytes.extend_from_slice(&num_mines.to_le_bytes());
             }
+            Self::Welcome { version, capabilities } => {
+                bytes.reserve_exact(7);
+                bytes.push(7);
+                bytes.extend_from_slice(&version.to_le_bytes());
+                bytes.extend_from_slice(&capabilities.to_le_bytes());
+            }
+            Self::Error { code, ref message } => {
+                bytes.reserve_exact(1 + 1 + message.len());
+                bytes.push(8);
+                bytes.push(code as u8);
+                bytes.extend_from_slice(message.as_bytes());
+            }
+            Self::ActionRejected { action, coords: (i, j), rejection } => {
+                bytes.reserve_exact(1 + 1 + 2 + 2 + 1 + 2);
+                bytes.push(9);
+                bytes.push(action as u8);
+                bytes.extend_from_slice(&i.to_le_bytes());
+                bytes.extend_from_slice(&j.to_le_bytes());
+                let (reason, num_mines, num_flags) = match rejection {
+                    board::Rejection::OutOfBounds => (0, 0, 0),
+                    board::Rejection::GameOver => (1, 0, 0),
+                    board::Rejection::NotStarted => (2, 0, 0),
+                    board::Rejection::AlreadyRevealed => (3, 0, 0),
+                    board::Rejection::NotRevealed => (4, 0, 0),
+                    board::Rejection::FlagCountMismatch { num_mines, num_flags } => (5, num_mines, num_flags),
+                    board::Rejection::NothingToReveal => (6, 0, 0),
+                    board::Rejection::Eliminated => (7, 0, 0),
+                    board::Rejection::Frozen => (8, 0, 0),
+                    board::Rejection::NotYourTurn => (9, 0, 0),
+                    board::Rejection::NotFlagOwner => (10, 0, 0),
+                    board::Rejection::FlagDisproved => (11, 0, 0),
+                };
+                bytes.push(reason);
+                bytes.push(num_mines);
+                bytes.push(num_flags);
+            }
+            Self::GameRecorded { game_id } => {
+                bytes.reserve_exact(9);
+                bytes.push(10);
+                bytes.extend_from_slice(&game_id.to_le_bytes());
+            }
+            Self::GameHistory { ref games } => {
+                bytes.reserve_exact(1 + 1 + 27 * games.len());
+                bytes.push(11);
+                assert!(games.len() <= u8::MAX as usize);
+                bytes.push(games.len() as u8);
+                for game in games {
+                    bytes.extend_from_slice(&game.game_id().to_le_bytes());
+                    bytes.extend_from_slice(&game.duration_ms.to_le_bytes());
+                    bytes.extend_from_slice(&game.width.to_le_bytes());
+                    bytes.extend_from_slice(&game.height.to_le_bytes());
+                    bytes.extend_from_slice(&game.num_mines.to_le_bytes());
+                    bytes.push(game.result as u8);
+                    bytes.push(game.loser_id);
+                    bytes.push(game.players.len() as u8);
+                }
+            }
+            Self::GameDetails { ref game } => {
+                bytes.push(12);
+                bytes.extend_from_slice(&game.game_id().to_le_bytes());
+                bytes.extend_from_slice(&game.end_ms.to_le_bytes());
+                bytes.extend_from_slice(&game.width.to_le_bytes());
+                bytes.extend_from_slice(&game.height.to_le_bytes());
+                bytes.extend_from_slice(&game.num_mines.to_le_bytes());
+                bytes.extend_from_slice(&game.seed.to_le_bytes());
+                bytes.push(game.result as u8);
+                bytes.push(game.loser_id);
+                for player in &game.players {
+                    bytes.push(player.id);
+                    bytes.extend_from_slice(&player.color);
+                    bytes.extend_from_slice(&player.squares_revealed.to_le_bytes());
+                    bytes.extend_from_slice(&player.flags.to_le_bytes());
+                    let name = &player.name.as_bytes()[..player.name.len().min(u8::MAX as usize)];
+                    bytes.push(name.len() as u8);
+                    bytes.extend_from_slice(name);
+                }
+            }
+            Self::TrueBoard { ref mines } => {
+                bytes.reserve_exact(1 + 4 * mines.len());
+                bytes.push(13);
+                for &(i, j) in mines {
+                    bytes.extend_from_slice(&i.to_le_bytes());
+                    bytes.extend_from_slice(&j.to_le_bytes());
+                }
+            }
+            Self::GameClock { first_click_ms, finish_ms, elapsed_ms } => {
+                bytes.reserve_exact(1 + 8 + 8 + 8);
+                bytes.push(14);
+                bytes.extend_from_slice(&first_click_ms.to_le_bytes());
+                bytes.extend_from_slice(&finish_ms.to_le_bytes());
+                bytes.extend_from_slice(&elapsed_ms.to_le_bytes());
+            }
+            Self::GameStats(ref stats) => {
+                bytes.reserve_exact(1 + 4 + 4 + 8 + 4 + 4 + 13 * stats.clicks.len());
+                bytes.push(15);
+                bytes.extend_from_slice(&stats.three_bv.to_le_bytes());
+                bytes.extend_from_slice(&stats.solved_three_bv.to_le_bytes());
+                bytes.extend_from_slice(&stats.elapsed_ms.to_le_bytes());
+                bytes.extend_from_slice(&stats.three_bv_per_second.to_le_bytes());
+                bytes.extend_from_slice(&stats.efficiency.to_le_bytes());
+                for &(player_id, clicks) in &stats.clicks {
+                    bytes.push(player_id);
+                    bytes.extend_from_slice(&clicks.effective.to_le_bytes());
+                    bytes.extend_from_slice(&clicks.wasted.to_le_bytes());
+                    bytes.extend_from_slice(&clicks.flags.to_le_bytes());
+                }
+            }
+            Self::GameSummary { ref players } => {
+                bytes.reserve_exact(1 + 25 * players.len());
+                bytes.push(16);
+                for &(player_id, stats) in players {
+                    bytes.push(player_id);
+                    bytes.extend_from_slice(&stats.squares_revealed.to_le_bytes());
+                    bytes.extend_from_slice(&stats.chords.to_le_bytes());
+                    bytes.extend_from_slice(&stats.correct_flags.to_le_bytes());
+                    bytes.extend_from_slice(&stats.wrong_flags.to_le_bytes());
+                    bytes.extend_from_slice(&stats.mines_hit.to_le_bytes());
+                    bytes.extend_from_slice(&stats.hints_used.to_le_bytes());
+                }
+            }
+            Self::Leaderboard { period, metric, config: (width, height, num_mines), ref rows } => {
+                bytes.push(17);
+                bytes.push(period as u8);
+                bytes.push(metric as u8);
+                bytes.extend_from_slice(&width.to_le_bytes());
+                bytes.extend_from_slice(&height.to_le_bytes());
+                bytes.extend_from_slice(&num_mines.to_le_bytes());
+                for row in rows {
+                    bytes.extend_from_slice(&row.games_played.to_le_bytes());
+                    bytes.extend_from_slice(&row.wins.to_le_bytes());
+                    bytes.extend_from_slice(&row.squares_cleared.to_le_bytes());
+                    bytes.extend_from_slice(&row.fastest_win_ms.unwrap_or(0).to_le_bytes());
+                    let name = &row.name.as_bytes()[..row.name.len().min(u8::MAX as usize)];
+                    bytes.push(name.len() as u8);
+                    bytes.extend_from_slice(name);
+                }
+            }
+            Self::DailyChallenge { day, preset, safe_square: (i, j) } => {
+                bytes.reserve_exact(10);
+                bytes.push(18);
+                bytes.extend_from_slice(&day.to_le_bytes());
+                bytes.push(preset as u8);
+                bytes.extend_from_slice(&i.to_le_bytes());
+                bytes.extend_from_slice(&j.to_le_bytes());
+            }
+            Self::DailyRanking { day, preset, ref results } => {
+                bytes.push(19);
+                bytes.extend_from_slice(&day.to_le_bytes());
+                bytes.push(preset as u8);
+                for result in results {
+                    bytes.push(result.outcome as u8);
+                    bytes.extend_from_slice(&result.duration_ms.to_le_bytes());
+                    bytes.extend_from_slice(&result.solved_three_bv.to_le_bytes());
+                    let name = &result.name.as_bytes()[..result.name.len().min(u8::MAX as usize)];
+                    bytes.push(name.len() as u8);
+                    bytes.extend_from_slice(name);
+                }
+            }
+            Self::GameMode(mode) | Self::NextGameMode(mode) => {
+                bytes.reserve_exact(6);
+                bytes.push(if let Self::GameMode(..) = self { 20 } else { 21 });
+                bytes.push(mode.code());
+                bytes.extend_from_slice(&mode.param().to_le_bytes());
+            }
+            Self::SafeSquare { coords: (i, j) } => {
+                bytes.reserve_exact(5);
+                bytes.push(22);
+                bytes.extend_from_slice(&i.to_le_bytes());
+                bytes.extend_from_slice(&j.to_le_bytes());
+            }
+            Self::RaceProgress { player_id, squares_cleared, squares_to_clear, board_state } => {
+                bytes.reserve_exact(11);
+                bytes.push(23);
+                bytes.push(player_id);
+                bytes.extend_from_slice(&squares_cleared.to_le_bytes());
+                bytes.extend_from_slice(&squares_to_clear.to_le_bytes());
+                bytes.push(encode_board_state(board_state));
+            }
+            Self::RaceFinished { winner_id, duration_ms } => {
+                bytes.reserve_exact(10);
+                bytes.push(24);
+                bytes.push(winner_id);
+                bytes.extend_from_slice(&duration_ms.to_le_bytes());
+            }
+            Self::PlayerTeam { player_id, team } => {
+                bytes.reserve_exact(3);
+                bytes.push(25);
+                bytes.push(player_id);
+                bytes.push(team);
+            }
+            Self::TeamScores { winning_team, ref scores } => {
+                bytes.reserve_exact(2 + 5 * scores.len());
+                bytes.push(26);
+                bytes.push(winning_team);
+                for &(team, squares) in scores {
+                    bytes.push(team);
+                    bytes.extend_from_slice(&squares.to_le_bytes());
+                }
+            }
+            Self::PlayerEliminated { player_id } => {
+                bytes.reserve_exact(2);
+                bytes.push(27);
+                bytes.push(player_id);
+            }
+            Self::Standings { ref player_ids } => {
+                bytes.reserve_exact(1 + player_ids.len());
+                bytes.push(28);
+                bytes.extend_from_slice(player_ids);
+            }
+            Self::Lives { lives_left, lives } => {
+                bytes.reserve_exact(3);
+                bytes.push(29);
+                bytes.push(lives_left);
+                bytes.push(lives);
+            }
+            Self::PlayerFrozen { player_id, until_ms, remaining_ms, score } => {
+                bytes.reserve_exact(18);
+                bytes.push(30);
+                bytes.push(player_id);
+                bytes.extend_from_slice(&until_ms.to_le_bytes());
+                bytes.extend_from_slice(&remaining_ms.to_le_bytes());
+                bytes.extend_from_slice(&score.to_le_bytes());
+            }
+            Self::TurnChanged { player_id, until_ms, remaining_ms } => {
+                bytes.reserve_exact(14);
+                bytes.push(31);
+                bytes.push(player_id);
+                bytes.extend_from_slice(&until_ms.to_le_bytes());
+                bytes.extend_from_slice(&remaining_ms.to_le_bytes());
+            }
+            Self::PrivateMark { coords, mark } => {
+                bytes.reserve_exact(6);
+                bytes.push(33);
+                bytes.extend_from_slice(&coords.0.to_le_bytes());
+                bytes.extend_from_slice(&coords.1.to_le_bytes());
+                bytes.push(match mark {
+                    None => 0,
+                    // Private flags are never shown to be wrong, but one would still be a flag.
+                    Some(board::Mark::Flag | board::Mark::WrongFlag) => 1,
+                    Some(board::Mark::Question) => 2,
+                    Some(board::Mark::Safe) => 3,
+                });
+            }
+            Self::Territory { winner_id, ref areas } => {
+                bytes.reserve_exact(2 + 5 * areas.len());
+                bytes.push(32);
+                bytes.push(winner_id);
+                for &(player_id, area) in areas {
+                    bytes.push(player_id);
+                    bytes.extend_from_slice(&area.to_le_bytes());
+                }
+            }
+            Self::FlagRules(rules) | Self::NextFlagRules(rules) => {
+                bytes.reserve_exact(3);
+                bytes.push(if let Self::FlagRules(..) = self { 34 } else { 35 });
+                bytes.push(rules.removal_code());
+                bytes.push(rules.options());
+            }
+            Self::FlagRemovalRequested { coords, player_id } => {
+                bytes.reserve_exact(6);
+                bytes.push(36);
+                bytes.extend_from_slice(&coords.0.to_le_bytes());
+                bytes.extend_from_slice(&coords.1.to_le_bytes());
+                bytes.push(player_id);
+            }
         }
-        bytes
+        Some(bytes)
+    }
+
+    // Oldest protocol version in which this packet exists.
+    fn min_version(&self) -> u16 {
+        match self {
+            Self::Welcome { .. }
+            | Self::Error { .. }
+            | Self::ActionRejected { .. }
+            | Self::GameRecorded { .. }
+            | Self::GameHistory { .. }
+            | Self::GameDetails { .. }
+            | Self::TrueBoard { .. } => 1,
+            Self::GameClock { .. }
+            | Self::GameStats(..)
+            | Self::GameSummary { .. }
+            | Self::Leaderboard { .. }
+            | Self::DailyChallenge { .. }
+            | Self::DailyRanking { .. }
+            | Self::GameMode(..)
+            | Self::NextGameMode(..)
+            | Self::SafeSquare { .. }
+            | Self::RaceProgress { .. }
+            | Self::RaceFinished { .. }
+            | Self::PlayerTeam { .. }
+            | Self::TeamScores { .. }
+            | Self::PlayerEliminated { .. }
+            | Self::Standings { .. }
+            | Self::Lives { .. }
+            | Self::PlayerFrozen { .. }
+            | Self::TurnChanged { .. }
+            | Self::Territory { .. }
+            | Self::PrivateMark { .. }
+            | Self::FlagRules(..)
+            | Self::NextFlagRules(..)
+            | Self::FlagRemovalRequested { .. } => 2,
+            _ => 0,
+        }
+    }
+
+    // Capability the client must have advertised to be sent this packet.
+    fn required_capability(&self) -> u32 {
+        match self {
+            Self::ActionRejected { .. } => CAPABILITY_ACTION_REJECTED,
+            Self::GameRecorded { .. } => CAPABILITY_REPLAYS,
+            Self::TrueBoard { .. } => CAPABILITY_TRUE_BOARD,
+            Self::GameClock { .. } => CAPABILITY_GAME_CLOCK,
+            Self::GameStats(..) => CAPABILITY_GAME_STATS,
+            Self::GameSummary { .. } => CAPABILITY_GAME_SUMMARY,
+            Self::GameMode(..)
+            | Self::NextGameMode(..)
+            | Self::SafeSquare { .. }
+            | Self::RaceProgress { .. }
+            | Self::RaceFinished { .. }
+            | Self::PlayerTeam { .. }
+            | Self::TeamScores { .. }
+            | Self::PlayerEliminated { .. }
+            | Self::Standings { .. }
+            | Self::Lives { .. }
+            | Self::PlayerFrozen { .. }
+            | Self::TurnChanged { .. }
+            | Self::Territory { .. }
+            | Self::FlagRules(..)
+            | Self::NextFlagRules(..)
+            | Self::FlagRemovalRequested { .. } => CAPABILITY_GAME_MODES,
+            Self::PrivateMark { .. } => CAPABILITY_MARKS,
+            _ => 0,
+        }
+    }
+}
+
+pub fn encode_board_state(board_state: board::BoardState) -> u8 {
+    match board_state {
+        board::BoardState::Uninitialized | board::BoardState::Ongoing => 1,
+        board::BoardState::Lost => 2,
+        board::BoardState::Won => 3,
     }
 }
 
 pub fn encode_entry(entry: &board::Entry) -> u8 {
     match *entry {
-        board::Entry::Unknown { flagged: false } => 253,
-        board::Entry::Unknown { flagged: true } => 254,
+        board::Entry::Unknown { mark: None } => 253,
+        board::Entry::Unknown { mark: Some(board::Mark::Flag) } => 254,
+        board::Entry::Unknown { mark: Some(board::Mark::Question) } => 251,
+        board::Entry::Unknown { mark: Some(board::Mark::Safe) } => 252,
+        board::Entry::Unknown { mark: Some(board::Mark::WrongFlag) } => 250,
         board::Entry::Mine => 255,
         board::Entry::Revealed { num_mines } => num_mines,
     }
 }
 
+// Question and safe marks look like unmarked squares to clients which don't know them, and flags
+// shown to be wrong look like any other flag.
+fn entry_for(entry: u8, protocol: Protocol) -> u8 {
+    match entry {
+        250 if !protocol.has_capability(CAPABILITY_WRONG_FLAGS) => 254,
+        251..=252 if !protocol.has_capability(CAPABILITY_MARKS) => 253,
+        _ => entry,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mode::FlagRemoval;
+
+    #[test]
+    fn negotiates_the_lower_version_and_the_shared_capabilities() {
+        assert_eq!(
+            Protocol::negotiate(PROTOCOL_VERSION + 1, u32::MAX),
+            Some(Protocol { version: PROTOCOL_VERSION, capabilities: SERVER_CAPABILITIES })
+        );
+        assert_eq!(Protocol::negotiate(MIN_PROTOCOL_VERSION, 0), Some(Protocol { version: MIN_PROTOCOL_VERSION, capabilities: 0 }));
+        assert_eq!(Protocol::negotiate(MIN_PROTOCOL_VERSION - 1, u32::MAX), None);
+    }
+
+    #[test]
+    fn decodes_hello() {
+        assert!(matches!(
+            ClientToServerPacket::decode(&[5, 3, 0, 0x21, 0, 0, 0x80]),
+            Ok(ClientToServerPacket::Hello { version: 3, capabilities: 0x8000_0021 })
+        ));
+        assert!(ClientToServerPacket::decode(&[5, 3, 0]).is_err());
+    }
+
+    #[test]
+    fn sends_legacy_clients_only_the_original_packets() {
+        let v1 = Protocol { version: 1, capabilities: 0 };
+        let welcome = ServerToClientPacket::Welcome { version: 1, capabilities: 0 };
+        assert_eq!(welcome.encode(Protocol::LEGACY), None);
+        assert_eq!(welcome.encode(v1), Some(vec![7, 1, 0, 0, 0, 0, 0]));
+
+        let config = ServerToClientPacket::NextBoardConfig { width: 30, height: 16, num_mines: 99 };
+        assert_eq!(config.encode(Protocol::LEGACY), Some(vec![6, 30, 0, 16, 0, 99, 0, 0, 0]));
+        assert_eq!(config.encode(v1), config.encode(Protocol::LEGACY));
+    }
+
+    #[test]
+    fn types_malformed_client_packets() {
+        assert_eq!(ClientToServerPacket::decode(&[]).unwrap_err(), PacketError::Empty);
+        assert_eq!(ClientToServerPacket::decode(&[99]).unwrap_err(), PacketError::UnknownPacketType(99));
+        assert_eq!(
+            ClientToServerPacket::decode(&[0, 1, 0]).unwrap_err(),
+            PacketError::WrongLength { packet_type: 0, expected: 5, actual: 3 }
+        );
+        assert_eq!(
+            ClientToServerPacket::decode(&[4, 0, 0, 10, 0, 1, 0, 0, 0]).unwrap_err(),
+            PacketError::InvalidConfig { width: 0, height: 10, num_mines: 1 }
+        );
+    }
+
+    #[test]
+    fn disconnects_only_clients_out_of_sync_with_the_protocol() {
+        assert_eq!(PacketError::WrongLength { packet_type: 0, expected: 5, actual: 3 }.response(), ErrorResponse::Disconnect);
+        assert_eq!(PacketError::UnsupportedVersion(0).response(), ErrorResponse::Disconnect);
+        assert_eq!(PacketError::UnknownPacketType(99).response(), ErrorResponse::Warn);
+        assert_eq!(PacketError::UnexpectedHello.response(), ErrorResponse::Warn);
+    }
+
+    #[test]
+    fn encodes_error_packets_with_their_code_and_message() {
+        let bytes = PacketError::UnknownPacketType(99).to_packet().encode(Protocol { version: 1, capabilities: 0 }).unwrap();
+        assert_eq!(bytes[..2], [8, ErrorCode::UnknownPacketType as u8]);
+        assert_eq!(bytes[2..], *b"Unknown packet type 99.");
+    }
+
+    #[test]
+    fn sends_action_rejections_only_to_clients_which_asked_for_them() {
+        let packet = ServerToClientPacket::ActionRejected {
+            action: Action::Chord,
+            coords: (1, 2),
+            rejection: board::Rejection::FlagCountMismatch { num_mines: 3, num_flags: 1 },
+        };
+        assert_eq!(packet.encode(Protocol { version: 1, capabilities: 0 }), None);
+        assert_eq!(
+            packet.encode(Protocol { version: 1, capabilities: CAPABILITY_ACTION_REJECTED }),
+            Some(vec![9, 1, 1, 0, 2, 0, 5, 3, 1])
+        );
+    }
+
+    #[test]
+    fn decodes_replay_controls() {
+        assert!(matches!(
+            ClientToServerPacket::decode(&[6, 1, 2, 0, 0, 0, 0, 0, 0]),
+            Ok(ClientToServerPacket::StartReplay { game_id: 0x0201 })
+        ));
+        assert!(matches!(ClientToServerPacket::decode(&[7, 200, 0]), Ok(ClientToServerPacket::ReplaySpeed { speed_percent: 200 })));
+        assert!(matches!(ClientToServerPacket::decode(&[9, 5, 0, 0, 0]), Ok(ClientToServerPacket::SeekReplay { num_actions: 5 })));
+        assert!(matches!(ClientToServerPacket::decode(&[10]), Ok(ClientToServerPacket::StopReplay)));
+    }
+
+    #[test]
+    fn announces_recorded_games_only_to_clients_which_can_replay_them() {
+        let packet = ServerToClientPacket::GameRecorded { game_id: 7 };
+        assert_eq!(packet.encode(Protocol { version: 1, capabilities: 0 }), None);
+        assert_eq!(packet.encode(Protocol { version: 1, capabilities: CAPABILITY_REPLAYS }), Some(vec![10, 7, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn summarises_each_game_of_a_history_page() {
+        let game = crate::history::GameRecord {
+            width: 30,
+            height: 16,
+            num_mines: 99,
+            seed: 7,
+            start_ms: 1_000,
+            end_ms: 4_000,
+            duration_ms: 2_500,
+            result: crate::game_log::GameResult::Won,
+            loser_id: 0,
+            players: vec![],
+        };
+        let bytes = ServerToClientPacket::GameHistory { games: vec![game] }.encode(Protocol { version: 1, capabilities: 0 }).unwrap();
+        assert_eq!(bytes[..2], [11, 1]);
+        assert_eq!(bytes[2..10], 1_000u64.to_le_bytes());
+        assert_eq!(bytes[10..18], 2_500u64.to_le_bytes());
+        assert_eq!(bytes[18..], [30, 0, 16, 0, 99, 0, 0, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn adds_game_durations_from_protocol_version_2() {
+        let win = ServerToClientPacket::BoardWin { duration_ms: 1_500 };
+        assert_eq!(win.encode(Protocol { version: 1, capabilities: 0 }), Some(vec![5]));
+        assert_eq!(win.encode(Protocol { version: 2, capabilities: 0 }), Some([vec![5], 1_500u64.to_le_bytes().to_vec()].concat()));
+
+        let clock = ServerToClientPacket::GameClock { first_click_ms: 1, finish_ms: 0, elapsed_ms: 2 };
+        assert_eq!(clock.encode(Protocol { version: 1, capabilities: CAPABILITY_GAME_CLOCK }), None);
+        assert_eq!(clock.encode(Protocol { version: 2, capabilities: CAPABILITY_GAME_CLOCK }).map(|bytes| bytes.len()), Some(25));
+    }
+
+    #[test]
+    fn encodes_a_record_per_player_in_game_summaries() {
+        let stats = PlayerStats { squares_revealed: 1, chords: 2, correct_flags: 3, wrong_flags: 4, mines_hit: 5, hints_used: 6 };
+        let packet = ServerToClientPacket::GameSummary { players: vec![(7, stats)] };
+        let bytes = packet.encode(Protocol { version: 2, capabilities: CAPABILITY_GAME_SUMMARY }).unwrap();
+        let fields: Vec<u32> = bytes[2..].chunks(4).map(|field| u32::from_le_bytes(field.try_into().unwrap())).collect();
+        assert_eq!(bytes[..2], [16, 7]);
+        assert_eq!(fields, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn decodes_only_known_game_modes() {
+        assert!(matches!(ClientToServerPacket::decode(&[17, 1, 0, 0, 0, 0]), Ok(ClientToServerPacket::NextGameMode(GameMode::Race))));
+        assert!(matches!(
+            ClientToServerPacket::decode(&[17, 1, 3, 0, 0, 0]),
+            Err(PacketError::InvalidGameMode { mode: 1, param: 3 })
+        ));
+        assert!(matches!(ClientToServerPacket::decode(&[17, 9, 0, 0, 0, 0]), Err(PacketError::InvalidGameMode { mode: 9, .. })));
+        assert!(matches!(
+            ClientToServerPacket::decode(&[17, 4, 255, 0, 0, 0]),
+            Ok(ClientToServerPacket::NextGameMode(GameMode::Lives { lives: 255 }))
+        ));
+        assert!(matches!(ClientToServerPacket::decode(&[17, 4, 0, 1, 0, 0]), Err(PacketError::InvalidGameMode { mode: 4, .. })));
+    }
+
+    #[test]
+    fn sends_team_scores_only_to_clients_which_play_game_modes() {
+        assert!(matches!(ClientToServerPacket::decode(&[18, 2]), Ok(ClientToServerPacket::ChooseTeam { team: 2 })));
+        let packet = ServerToClientPacket::TeamScores { winning_team: 1, scores: vec![(1, 9), (2, 0x0102)] };
+        assert_eq!(packet.encode(Protocol { version: 2, capabilities: 0 }), None);
+        assert_eq!(
+            packet.encode(Protocol { version: 2, capabilities: CAPABILITY_GAME_MODES }),
+            Some(vec![26, 1, 1, 9, 0, 0, 0, 2, 2, 1, 0, 0])
+        );
+    }
+
+    #[test]
+    fn encodes_eliminations_and_standings() {
+        let protocol = Protocol { version: 2, capabilities: CAPABILITY_GAME_MODES };
+        assert_eq!(ServerToClientPacket::PlayerEliminated { player_id: 4 }.encode(protocol), Some(vec![27, 4]));
+        assert_eq!(ServerToClientPacket::Standings { player_ids: vec![3, 1, 2] }.encode(protocol), Some(vec![28, 3, 1, 2]));
+        let rejected = ServerToClientPacket::ActionRejected { action: Action::Reveal, coords: (1, 2), rejection: board::Rejection::Eliminated };
+        let protocol = Protocol { version: 2, capabilities: CAPABILITY_ACTION_REJECTED };
+        assert_eq!(rejected.encode(protocol).unwrap()[6], 7);
+    }
+
+    #[test]
+    fn widens_the_hit_mine_count_from_protocol_version_3() {
+        let loss = ServerToClientPacket::BoardLoss { loser_id: 1, duration_ms: 0, hit_mines: vec![(0, 0); 300], wrong_flags: vec![] };
+        let bytes = loss.encode(Protocol { version: 3, capabilities: 0 }).unwrap();
+        assert_eq!((bytes[10..12].to_vec(), bytes.len()), (300u16.to_le_bytes().to_vec(), 12 + 4 * 300));
+        let bytes = loss.encode(Protocol { version: 2, capabilities: 0 }).unwrap();
+        assert_eq!((bytes[10], bytes.len()), (255, 11 + 4 * 255));
+    }
+
+    #[test]
+    fn encodes_frozen_players() {
+        let packet = ServerToClientPacket::PlayerFrozen { player_id: 3, until_ms: 0x0102, remaining_ms: 5, score: 7 };
+        let mut expected = vec![30, 3];
+        expected.extend_from_slice(&0x0102u64.to_le_bytes());
+        expected.extend_from_slice(&[5, 0, 0, 0, 7, 0, 0, 0]);
+        assert_eq!(packet.encode(Protocol { version: 3, capabilities: CAPABILITY_GAME_MODES }), Some(expected));
+    }
+
+    #[test]
+    fn adds_cascade_owners_from_protocol_version_4() {
+        let change = board::SquareChange { coords: (1, 2), entry: board::Entry::Revealed { num_mines: 0 }, player_id: 0, cascade_owner: 6 };
+        let packet = ServerToClientPacket::sparse_board(&[change]);
+        assert_eq!(packet.encode(Protocol { version: 3, capabilities: 0 }).map(|bytes| bytes.len()), Some(1 + 6));
+        assert_eq!(packet.encode(Protocol { version: 4, capabilities: 0 }).unwrap()[1..], [1, 0, 2, 0, 0, 0, 6]);
+
+        let mut board = board::MultiplayerBoard::with_mines(3, 2, 0, &[(0, 0)]);
+        board.reveal_square((1, 2), 6, 0).unwrap();
+        let packet = ServerToClientPacket::full_board(&board);
+        let v3 = packet.encode(Protocol { version: 3, capabilities: 0 }).unwrap();
+        let v4 = packet.encode(Protocol { version: 4, capabilities: 0 }).unwrap();
+        assert_eq!(v4[..v3.len()], v3[..]);
+        assert_eq!(v4[v3.len()..], [0, 6, 6, 0, 6, 6]);
+    }
+
+    #[test]
+    fn shows_question_and_safe_marks_only_to_clients_which_know_them() {
+        assert!(matches!(
+            ClientToServerPacket::decode(&[19, 1, 0, 2, 0, 1]),
+            Ok(ClientToServerPacket::CycleMark { coords: (1, 2), private: true })
+        ));
+        let change = board::SquareChange { coords: (0, 0), entry: board::Entry::Unknown { mark: Some(board::Mark::Safe) }, player_id: 1, cascade_owner: 0 };
+        let packet = ServerToClientPacket::sparse_board(&[change]);
+        assert_eq!(packet.encode(Protocol { version: 4, capabilities: 0 }).unwrap()[5], 253);
+        assert_eq!(packet.encode(Protocol { version: 4, capabilities: CAPABILITY_MARKS }).unwrap()[5], 252);
+
+        let private = ServerToClientPacket::PrivateMark { coords: (1, 2), mark: Some(board::Mark::Question) };
+        assert_eq!(private.encode(Protocol { version: 4, capabilities: 0 }), None);
+        assert_eq!(private.encode(Protocol { version: 4, capabilities: CAPABILITY_MARKS }), Some(vec![33, 1, 0, 2, 0, 2]));
+    }
+
+    #[test]
+    fn decodes_only_known_flag_rules_and_shows_wrong_flags_as_flags_to_other_clients() {
+        let rules = FlagRules { removal: FlagRemoval::Confirmed, show_wrong_flags: true, marks_after_start: false };
+        assert!(matches!(ClientToServerPacket::decode(&[20, 2, 1]), Ok(ClientToServerPacket::NextFlagRules(decoded)) if decoded == rules));
+        for bytes in [[20, 3, 0], [20, 0, 4]] {
+            let err = ClientToServerPacket::decode(&bytes).unwrap_err();
+            assert_eq!(err.code() as u8, 14);
+        }
+
+        let change = board::SquareChange { coords: (0, 0), entry: board::Entry::Unknown { mark: Some(board::Mark::WrongFlag) }, player_id: 1, cascade_owner: 0 };
+        let packet = ServerToClientPacket::sparse_board(&[change]);
+        assert_eq!(packet.encode(Protocol { version: 4, capabilities: CAPABILITY_MARKS }).unwrap()[5], 254);
+        assert_eq!(packet.encode(Protocol { version: 4, capabilities: CAPABILITY_WRONG_FLAGS }).unwrap()[5], 250);
+    }
+}
+
 /*
  * Server -> Client Message Format:
  *   Little Endian.
@@ -205,6 +1198,36 @@ pub fn encode_entry(entry: &board::Entry) -> u8 {
  *     4: board loss.
  *     5: board win.
  *     6: next board config.
+ *     7: welcome.
+ *     8: error.
+ *     9: action rejected.
+ *    10: game recorded.
+ *    11: game history.
+ *    12: game details.
+ *    13: true board.
+ *    14: game clock.
+ *    15: game stats.
+ *    16: game summary.
+ *    17: leaderboard.
+ *    18: daily challenge.
+ *    19: daily ranking.
+ *    20: game mode.
+ *    21: next game mode.
+ *    22: safe square.
+ *    23: race progress.
+ *    24: race finished.
+ *    25: player team.
+ *    26: team scores.
+ *    27: player eliminated.
+ *    28: standings.
+ *    29: lives.
+ *    30: player frozen.
+ *    31: turn changed.
+ *    32: territory.
+ *    33: private mark.
+ *    34: flag rules.
+ *    35: next flag rules.
+ *    36: flag removal requested.
  *   remainder: packet.
  * 
  * Full Board packet:
@@ -218,17 +1241,25 @@ pub fn encode_entry(entry: &board::Entry) -> u8 {
  *     3: won.
  *   width*height u8s: entries. Index goes top to bottom, left to right.
  *     [0, 9]: Number of mines in/adjacent to square.
- *     [10, 252]: Unused.
+ *     [10, 249]: Unused.
+ *     250: Flag shown to be wrong. Only sent with the wrong flags capability (bit 8), otherwise
+ *          sent as 254.
+ *     251: Question mark. Only sent with the marks capability (bit 7), otherwise sent as 253.
+ *     252: Safe mark. Only sent with the marks capability (bit 7), otherwise sent as 253.
  *     253: Unknown.
  *     254: Flag.
  *     255: Mine.
  *   width*height u8s: player ID. Index goes top to bottom, left to right.
  *     0: No player (e.g. initial clearing).
  *     [1, 255]: Player ID.
+ *   width*height u8s: cascade owner, the player whose reveal or chord opened the square, including
+ *     by a cascade or the initial clearing. 0 for squares not revealed. Index goes top to bottom,
+ *     left to right. Only in protocol version 4 on.
  * 
  * Sparse Board packet:
  *   Represents an update of the board the client already has.  
- *   Series of quadruples (u16: i, u16: j, u8: entry at index, u8: player ID)
+ *   Series of quadruples (u16: i, u16: j, u8: entry at index, u8: player ID), each followed by
+ *   u8: cascade owner as in the Full Board packet in protocol version 4 on.
  *
  * Player Info packet:
  *   Info associated with a player ID for a client to record or update. 
@@ -251,23 +1282,382 @@ pub fn encode_entry(entry: &board::Entry) -> u8 {
  * Board Loss packet:
  *   Players have lost the game by clicking on a mine. 
  *   u8: player_id of player who clicked on mine.
- *   u8: number of clicked mines (more than 1 possible due to chording, but still less than 9).
+ *   u64: time from the first reveal until the loss in milliseconds. Only in protocol version 2 on.
+ *   u16: number of clicked mines in protocol version 3 on, u8 before. More than 1 is possible due
+ *     to chording, and when everyone is eliminated or the last life is lost every mine hit on the
+ *     board is listed. Before protocol version 3 only the first 255 are listed.
  *   (number of clicked mines) * (u16, u16): series of (i, j) coordinates of the clicked mines.
  *   remaining in (u16, u16): series of (i, j) coordinates of incorrect flags.
  *
  * Board Win packet:
  *   Players have revealed all non-mine squares. 
- *   Empty. 
+ *   u64: time from the first reveal until the win in milliseconds. Only in protocol version 2 on.
+ *   Empty before protocol version 2.
  * 
  * Next Board Config packet:
  *   Informs clients with what the configuration of the next board will be.
  *   u16: width.
  *   u16: height.
  *   u32: number of mines.
+ *
+ * Welcome packet:
+ *   Reply to a Hello packet with what the server will speak from now on.
+ *   u16: protocol version (the lower of the client's and the server's).
+ *   u32: capability flags (those advertised by both the client and the server).
+ *
+ * Error packet:
+ *   Something the client sent could not be handled.
+ *   u8: error code.
+ *     0: unsupported protocol version.
+ *     1: Hello sent after the protocol was already settled.
+ *     2: unknown packet type.
+ *     3: packet has the wrong length for its type.
+ *     4: unused. Was coordinates not on the board, which are now reported with an Action Rejected
+ *        packet.
+ *     5: invalid board config.
+ *     6: no replay of the requested game is available.
+ *     7: replay control sent while not watching a replay.
+ *     8: no record of the requested game exists.
+ *     9: unknown leaderboard period or metric.
+ *    10: unknown difficulty preset.
+ *    11: today's challenge on the requested preset has already been attempted.
+ *    12: unknown game mode, or a setting out of range for the mode.
+ *    13: team can't be joined, because there is no such team or the board has been started.
+ *    14: unknown flag removal rule or option.
+ *   remaining: human-readable message in UTF-8.
+ *   Depending on the error, the server may close the connection after sending this.
+ *
+ * Action Rejected packet:
+ *   A reveal, chord or flag sent by this client did nothing. Only sent to clients with the
+ *   action rejected capability (bit 0).
+ *   u8: action.
+ *     0: reveal.
+ *     1: chord.
+ *     2: flag.
+ *     3: cycle mark.
+ *   u16: i of the square acted on.
+ *   u16: j of the square acted on.
+ *   u8: reason.
+ *     0: square is not on the board.
+ *     1: game is over.
+ *     2: chord before the board has been started, or a shared mark before then where the flag
+ *        rules wait for the start.
+ *     3: square is already revealed.
+ *     4: chord on a square which is not a revealed number.
+ *     5: chorded square's number does not match its adjacent flags.
+ *     6: chord had no unflagged squares left to reveal.
+ *     7: player has been eliminated.
+ *     8: player is frozen.
+ *     9: it is not the player's turn.
+ *    10: flag belongs to another player, and only they may remove it.
+ *    11: square's flag has been shown to be wrong, so it can't be marked again.
+ *   u8: number on the chorded square if reason is 5, else 0.
+ *   u8: number of flags adjacent to the chorded square if reason is 5, else 0.
+ *
+ * Game Recorded packet:
+ *   A game has finished and can be replayed with a Start Replay packet for as long as the server
+ *   keeps it. Only sent to clients with the replays capability (bit 1).
+ *   u64: game ID.
+ *
+ * Game History packet:
+ *   Reply to a Request Game History packet. Lists finished games, newest first.
+ *   u8: number of games, followed by that many game summaries:
+ *     u64: game ID, which is also the time the board was created in milliseconds since the Unix epoch.
+ *     u64: time from the first reveal until the game was lost or won, in milliseconds.
+ *     u16: board width.
+ *     u16: board height.
+ *     u32: number of mines.
+ *     u8: result.
+ *       1: lost.
+ *       2: won.
+ *     u8: ID the player who hit a mine had in that game, or 0 if the game was won.
+ *     u8: number of players who acted.
+ *
+ * Game Details packet:
+ *   Reply to a Request Game packet.
+ *   u64: game ID, which is also the time the board was created in milliseconds since the Unix epoch.
+ *   u64: time the game was lost or won, in milliseconds since the Unix epoch.
+ *   u16: board width.
+ *   u16: board height.
+ *   u32: number of mines.
+ *   u64: seed the board was generated from.
+ *   u8: result.
+ *     1: lost.
+ *     2: won.
+ *   u8: ID the player who hit a mine had in that game, or 0 if the game was won.
+ *   remaining: series of records for the players who acted:
+ *     u8: player ID in that game.
+ *     u8: R
+ *     u8: G
+ *     u8: B
+ *     u8: A
+ *     u32: number of revealed squares attributed to the player when the game ended.
+ *     u32: number of flags the player had placed when the game ended.
+ *     u8: length of name.
+ *     (length of name) bytes: player name in UTF-8.
+ *
+ * True Board packet:
+ *   Where every mine on the board is. Sent after the Board Loss or Board Win packet when a game
+ *   ends, and after them to clients which connect while a finished board is shown. Only sent to
+ *   clients with the true board capability (bit 2).
+ *   remaining in (u16, u16): series of (i, j) coordinates of every mine.
+ *
+ * Game Clock packet:
+ *   The game clock runs from the first reveal on a board until it is lost or won. Sent when the
+ *   clock starts and stops, when a new board is made, and on connecting, so that every client shows
+ *   the same time. Clients should count up from the elapsed time while the clock is running, rather
+ *   than comparing the timestamps with their own clock. Only sent to clients speaking protocol
+ *   version 2 on with the game clock capability (bit 3).
+ *   u64: time of the first reveal in milliseconds since the Unix epoch, or 0 if the board has not
+ *     been started.
+ *   u64: time the board was lost or won in milliseconds since the Unix epoch, or 0 if it is not over.
+ *   u64: time on the clock in milliseconds when the packet was sent.
+ *
+ * Game Stats packet:
+ *   How efficiently a finished game was played. Sent when a game ends, and to clients which
+ *   connect while a finished board is shown. Only sent to clients speaking protocol version 2 on
+ *   with the game stats capability (bit 4).
+ *   u32: 3BV of the board, the fewest clicks which clear it: one for each area of squares with no
+ *     adjacent mines together with its border, and one for each other numbered square.
+ *   u32: part of the 3BV which was revealed.
+ *   u64: time on the game clock in milliseconds.
+ *   f32: revealed 3BV per second.
+ *   f32: efficiency, the revealed 3BV per click by all players as a percentage.
+ *   remaining: series of records for each player who clicked, in increasing order of ID:
+ *     u8: player ID.
+ *     u32: reveals and chords which revealed something.
+ *     u32: reveals, chords and flags which did nothing.
+ *     u32: flags placed or removed.
+ *
+ * Game Summary packet:
+ *   What each player did in a finished game. Sent after the Game Stats packet when a game ends,
+ *   and to clients which connect while a finished board is shown. Only sent to clients speaking
+ *   protocol version 2 on with the game summary capability (bit 5).
+ *   remaining: series of records for each player who acted, in increasing order of ID:
+ *     u8: player ID.
+ *     u32: squares revealed, including those revealed by cascades and chords.
+ *     u32: accepted chords.
+ *     u32: flags left on mines when the game ended.
+ *     u32: flags left on squares which are not mines when the game ended.
+ *     u32: mines hit.
+ *     u32: hints used. Always 0, as the server does not give out hints yet.
+ *
+ * Leaderboard packet:
+ *   Reply to a Request Leaderboard packet. Only sent to clients speaking protocol version 2 on.
+ *   u8: period, as requested.
+ *   u8: metric, as requested.
+ *   u16: board width, as requested.
+ *   u16: board height, as requested.
+ *   u32: number of mines, as requested.
+ *   remaining: series of records for up to 20 players, best first:
+ *     u32: games played.
+ *     u32: games won.
+ *     u64: squares cleared, including those revealed by cascades and chords.
+ *     u64: fastest win on the requested board config in milliseconds on the game clock, or 0 if the
+ *       player has not won on it.
+ *     u8: length of name.
+ *     (length of name) bytes: name the player had in their latest game, in UTF-8.
+ *
+ * Daily Challenge packet:
+ *   The client has left the room to attempt a daily challenge, and is sent that board instead until
+ *   it sends a Leave Daily packet. Everyone gets the same board for a day and preset. Followed by a
+ *   Full Board and Game Clock packet, and by the Board Loss or Board Win and True Board packets if
+ *   the attempt is over. Board updates and Player Info packets from the room are not sent meanwhile.
+ *   Only sent to clients speaking protocol version 2 on.
+ *   u32: day of the challenge, in days since the Unix epoch (UTC).
+ *   u8: preset.
+ *     0: beginner, 9x9 with 10 mines.
+ *     1: intermediate, 16x16 with 40 mines.
+ *     2: expert, 30x16 with 99 mines.
+ *   u16: i of a square which is guaranteed not to be a mine.
+ *   u16: j of a square which is guaranteed not to be a mine.
+ *
+ * Daily Ranking packet:
+ *   Finished attempts at a daily challenge, best first: wins by time, then losses by how much of
+ *   the board was solved. Reply to a Request Daily Ranking packet, and sent when the client's own
+ *   attempt ends. Only sent to clients speaking protocol version 2 on.
+ *   u32: day of the challenge, in days since the Unix epoch (UTC).
+ *   u8: preset.
+ *   remaining: series of records for each finished attempt:
+ *     u8: outcome.
+ *       1: lost.
+ *       2: won.
+ *     u64: time on the game clock in milliseconds.
+ *     u32: part of the board's 3BV which was revealed.
+ *     u8: length of name.
+ *     (length of name) bytes: name the player had during the attempt, in UTF-8.
+ *
+ * Game Mode packet:
+ *   The rules the current board is played under. Sent when a new board is made and on connecting.
+ *   Only sent to clients speaking protocol version 2 on with the game modes capability (bit 6),
+ *   as are the other packets below which only some modes use.
+ *   u8: mode.
+ *     0: co-op. Everyone plays the same board and wins or loses together.
+ *     1: race. Everyone plays their own copy of the same board, and the first to clear theirs
+ *        wins. Hitting a mine only ends that player's run. The race is over once someone wins or
+ *        every player who has started has hit a mine. Races are not recorded in the game history.
+ *     2: teams. Co-op, but players are put on teams and shown in their team's colour in Player
+ *        Info packets. When the board is solved, the team whose players cleared the most squares
+ *        wins. Setting is the number of teams, 2 to 4.
+ *     3: elimination. Hitting a mine reveals it and knocks the player out instead of losing the
+ *        board. Players who are out may only watch. The board is lost once every player in the room
+ *        who has acted on it is out. Revealed mines count as flags when chording.
+ *     4: lives. Co-op, but hitting a mine reveals it and costs one of the players' shared lives
+ *        instead of losing the board. The board is lost when the last life is lost. Revealed mines
+ *        count as flags when chording. Setting is the number of lives, 1 to 255.
+ *     5: freeze. Hitting a mine reveals it and stops the player acting for a while instead of
+ *        losing the board, and takes 10 squares off their score. A player's score is the squares
+ *        they revealed, less the penalties. Revealed mines count as flags when chording. Setting
+ *        is the number of seconds a player is frozen for, 1 to 255.
+ *     6: turn-based. Co-op, but players take turns in order of player ID, and only the player
+ *        whose turn it is may act. A reveal or chord ends the turn, as does placing or removing a
+ *        flag unless flags are free. Players who are not connected or are playing a daily
+ *        challenge are skipped, and a turn which runs out of time passes to the next player.
+ *        Setting:
+ *          bits 0-15: seconds per turn, or 0 for no time limit.
+ *          bit 16: flags are free, so any player may place or remove them at any time.
+ *     7: fog of war. Co-op, but players only see squares within some distance of the squares they
+ *        revealed, including by cascades and chords, and their own flags. Squares out of sight
+ *        are sent as unrevealed with player ID and cascade owner 0 in Full Board packets, and
+ *        changes to them are not sent at all. The whole board is sent in a Full Board packet once
+ *        it is over. Replays are not fogged. Setting is the distance, 1 to 255, counting diagonal
+ *        steps as one.
+ *     8: territory. Revealing a square claims it for the player, and so do the squares opened by
+ *        a cascade the player sets off, including the first reveal. A square belongs to its
+ *        cascade owner in board packets. The player with the most area when the board is solved
+ *        wins.
+ *   u32: setting of the mode. 0 for modes without one.
+ *
+ * Next Game Mode packet:
+ *   Informs clients what the mode of the next board will be. Same layout as the Game Mode packet.
+ *
+ * Safe Square packet:
+ *   A square which is guaranteed not to be a mine. Sent with the board in race mode, where every
+ *   copy of the board has the same mines, so the first reveal is not otherwise safe.
+ *   u16: i
+ *   u16: j
+ *
+ * Race Progress packet:
+ *   How far a player has got on their own copy of the race board. Sent whenever a player's reveal
+ *   or chord is accepted, and for every player who has started on connecting.
+ *   u8: player ID.
+ *   u32: squares the player has revealed.
+ *   u32: squares which have to be revealed to win.
+ *   u8: state of the player's board, as in the Full Board packet.
+ *
+ * Race Finished packet:
+ *   The race is over and the boards can no longer be played. Sent when it ends and on connecting.
+ *   u8: ID of the winner, or 0 if every player hit a mine.
+ *   u64: time the winner took on their game clock in milliseconds, or 0 if nobody won.
+ *
+ * Player Team packet:
+ *   The team a player is on. Sent for every player when teams are formed and on connecting, and
+ *   when a player joins or changes team.
+ *   u8: player ID.
+ *   u8: team, from 1.
+ *
+ * Team Scores packet:
+ *   Squares cleared by each team's players, including those revealed by cascades and chords. Sent
+ *   after every accepted reveal or chord in team mode, when a new board is made and on connecting.
+ *   u8: team which won, or 0 if the board has not been solved or teams tied for the most.
+ *   remaining: series of records for every team:
+ *     u8: team.
+ *     u32: squares cleared.
+ *
+ * Player Eliminated packet:
+ *   A player hit a mine in elimination mode and may no longer act on the board. Sent after the
+ *   packets for the reveal or chord which hit the mine, and for every eliminated player on
+ *   connecting.
+ *   u8: player ID.
+ *
+ * Standings packet:
+ *   Final placings when a board ends in elimination or freeze mode: players who survived, by
+ *   squares revealed less any freeze penalties, then those who were eliminated, from last out to
+ *   first out. Sent before the Game Stats packet when the board ends, and on connecting while a
+ *   finished board is shown.
+ *   remaining: series of u8 player IDs, first place first.
+ *
+ * Lives packet:
+ *   Lives the players have left in lives mode. Sent when a new board is made, after the packets
+ *   for a reveal or chord which hit a mine, and on connecting.
+ *   u8: lives left.
+ *   u8: lives at the start of the board.
+ *
+ * Player Frozen packet:
+ *   A player hit a mine in freeze mode and may not act until the freeze expires. Sent after the
+ *   packets for the reveal or chord which hit the mine, and for every frozen player on
+ *   connecting.
+ *   u8: player ID.
+ *   u64: time the freeze expires, on the same clock as the Game Clock packet.
+ *   u32: milliseconds left of the freeze.
+ *   u32: player's score after the penalty.
+ *
+ * Turn Changed packet:
+ *   Whose turn it is in turn-based mode. Sent when a new board is made, whenever the turn passes,
+ *   when the board ends (with no player) and on connecting.
+ *   u8: player ID, or 0 if nobody may act.
+ *   u64: time the turn runs out, on the same clock as the Game Clock packet, or 0 if turns are not
+ *        timed.
+ *   u32: milliseconds left of the turn, or 0 if turns are not timed.
+ *
+ * Territory packet:
+ *   Area claimed by each player in territory mode. Each revealed square counts as one more than
+ *   its number, so squares next to more mines are worth more. Sent after every accepted reveal or
+ *   chord in territory mode, when a new board is made and on connecting.
+ *   u8: player who won, or 0 if the board has not been solved or players tied for the most.
+ *   remaining: series of records for every player who has acted on the board:
+ *     u8: player ID.
+ *     u32: area.
+ *
+ * Private Mark packet:
+ *   A mark only the player can see, on a square of the room's board. Sent to every connection of
+ *   the player when they change it, and for each of their marks on squares which are still
+ *   unrevealed on connecting. Marks are forgotten when a new board is made. Only sent to clients
+ *   with the marks capability (bit 7).
+ *   u16: i
+ *   u16: j
+ *   u8: mark.
+ *     0: none.
+ *     1: flag.
+ *     2: question mark.
+ *     3: safe mark.
+ *
+ * Flag Rules packet:
+ *   How flags may be used on the current board. Sent when a new board is made and on connecting.
+ *   u8: who may remove a flag other than the player who placed it.
+ *     0: anyone.
+ *     1: nobody.
+ *     2: anyone, once asked for by two different players. The first asker's request is announced
+ *        with a Flag Removal Requested packet, and the flag stays until a second player asks.
+ *   u8: options.
+ *     bit 0: a flag is shown as wrong (entry 250) once the revealed squares prove there is no
+ *       mine under it: a number next to it already has all its mines revealed, or another
+ *       number with as many mines left to find has all its unrevealed squares among the first
+ *       number's but not the flag's. Other flags prove nothing. Checked when a flag is placed
+ *       and whenever squares near it are revealed. A wrong flag can't be marked again, no
+ *       longer counts when chording, and stays until its square is revealed.
+ *     bit 1: shared marks may not be placed until the board has been started.
+ *
+ * Next Flag Rules packet:
+ *   Informs clients what the flag rules of the next board will be. Same layout as the Flag Rules
+ *   packet.
+ *
+ * Flag Removal Requested packet:
+ *   A player asked to remove someone else's flag where removal needs a second player. Sending a
+ *   flag or Cycle Mark packet for the square from another player removes it. Requests are dropped
+ *   once the flag goes. In fog of war mode only sent to players who can see the square.
+ *   u16: i
+ *   u16: j
+ *   u8: ID of the player who asked.
  */
 
 /*
  * Client -> Server Message Format:
+ *   Clients may connect with a token in the query string of the WebSocket URL, as in ?token=...,
+ *   of 8 to 64 letters, digits, '-' and '_'. Connections with the same token are the same player.
+ *   Connections without a valid token are told apart by IP address, so clients behind one address
+ *   share a player. The token is also the player's identity on the leaderboard and for daily
+ *   challenges, so it should be random and kept by the client.
  *   Little Endian.
  *   u8: packet type.
  *     0: reveal square.
@@ -275,6 +1665,22 @@ pub fn encode_entry(entry: &board::Entry) -> u8 {
  *     2: flag.
  *     3: new board.
  *     4: next board config.
+ *     5: hello.
+ *     6: start replay.
+ *     7: replay speed.
+ *     8: step replay.
+ *     9: seek replay.
+ *    10: stop replay.
+ *    11: request game history.
+ *    12: request game.
+ *    13: request leaderboard.
+ *    14: start daily.
+ *    15: leave daily.
+ *    16: request daily ranking.
+ *    17: next game mode.
+ *    18: choose team.
+ *    19: cycle mark.
+ *    20: next flag rules.
  *
  * Reveal Square packet:
  *   Tells server that the player is requesting a square be revealed.
@@ -299,4 +1705,112 @@ pub fn encode_entry(entry: &board::Entry) -> u8 {
  *   u16: width.
  *   u16: height.
  *   u32: number of mines.
+ *
+ * Hello packet:
+ *   Should be the first packet a client sends. The layouts of the Hello, Welcome and Error packets
+ *   are the same in every protocol version. Clients which send anything else first are treated as
+ *   speaking protocol version 0 and never receive packets introduced after it. Until the server has
+ *   received either, it sends packets as in protocol version 0 and holds back those which don't
+ *   exist in it. The server replies with a Welcome packet, after which every packet is sent in the
+ *   negotiated protocol, or with an Error packet followed by closing the connection.
+ *   u16: highest protocol version the client speaks.
+ *   u32: capability flags the client supports.
+ *
+ * Start Replay packet:
+ *   Switches this connection from the live game to watching a replay of a finished game. The
+ *   replay is sent as the Player Info, Full Board, Sparse Board, Board Loss, Board Win and True Board
+ *   packets the game caused when it was live, starting from the empty board and playing at
+ *   original speed. Pauses between actions are capped at 5 seconds. Packets from the live game are
+ *   not sent until the replay is stopped.
+ *   u64: game ID, as sent in a Game Recorded packet.
+ *
+ * Replay Speed packet:
+ *   u16: playback speed as a percentage of the original game's speed. 0 pauses the replay.
+ *
+ * Step Replay packet:
+ *   Pauses the replay and plays its next action.
+ *   Empty.
+ *
+ * Seek Replay packet:
+ *   Jumps to the point in the replay where the given number of actions have been played, sending a
+ *   Full Board packet followed by the Board Loss or Board Win and True Board packets if the game is
+ *   over by then.
+ *   Playback continues at the current speed.
+ *   u32: number of actions.
+ *
+ * Stop Replay packet:
+ *   Stops watching the replay and returns to the live game, which is sent afresh as on connecting.
+ *   Empty.
+ *
+ * Request Game History packet:
+ *   Asks for a Game History packet listing finished games, newest first. Games are kept across
+ *   server restarts if the server has a history file.
+ *   u64: only list games older than this game ID. 0 lists from the newest game.
+ *   u8: most games to list. The server lists at most 50.
+ *
+ * Request Game packet:
+ *   Asks for a Game Details packet about one finished game.
+ *   u64: game ID.
+ *
+ * Request Leaderboard packet:
+ *   Asks for a Leaderboard packet ranking players across games. Players are told apart by their
+ *   token, or IP address without one, and kept across server restarts if the server has a
+ *   leaderboard file. In co-op games everyone who acted on a won board shares the win.
+ *   u8: period.
+ *     0: every game.
+ *     1: games finished this week, which starts on Monday (UTC).
+ *   u8: metric to rank by.
+ *     0: games played.
+ *     1: games won.
+ *     2: squares cleared.
+ *     3: fastest win on the given board config. Players who have not won on it are left out.
+ *   u16: board width.
+ *   u16: board height.
+ *   u32: number of mines.
+ *
+ * Start Daily packet:
+ *   Leaves the room to attempt today's (UTC) challenge on a preset, which the server answers with a
+ *   Daily Challenge packet. Each player gets one attempt per day and preset, used up as soon as it
+ *   starts; a second is answered with an Error packet. Reveals, chords and flags go to the
+ *   challenge board until a Leave Daily packet is sent.
+ *   u8: preset.
+ *     0: beginner, 9x9 with 10 mines.
+ *     1: intermediate, 16x16 with 40 mines.
+ *     2: expert, 30x16 with 99 mines.
+ *
+ * Leave Daily packet:
+ *   Returns from a daily challenge to the room, which is sent afresh as on connecting. An
+ *   unfinished attempt is recorded as unfinished and cannot be resumed.
+ *   Empty.
+ *
+ * Request Daily Ranking packet:
+ *   Asks for a Daily Ranking packet for today's (UTC) challenge on a preset.
+ *   u8: preset.
+ *
+ * Next Game Mode packet:
+ *   Requests that the next board be played under the following mode, as in the Game Mode packet.
+ *   In race mode, reveals, chords and flags act on the sender's own copy of the board.
+ *   u8: mode.
+ *   u32: setting of the mode. Must be 0 for modes without one.
+ *
+ * Choose Team packet:
+ *   Moves the sender to another team in team mode. Players are put on the smallest team when they
+ *   connect, and can change until the board is started.
+ *   u8: team, from 1.
+ *
+ * Cycle Mark packet:
+ *   Moves the mark on a square on to the next of flag, question mark, safe mark and none. Shared
+ *   marks are seen by everyone and attributed to the sender like flags, but only flags count when
+ *   chording. Private marks are only sent back to the sender's connections in Private Mark
+ *   packets and have no effect on the game. Marks on the sender's own board in race mode or in a
+ *   daily challenge are always shared.
+ *   u16: i
+ *   u16: j
+ *   u8: 1 if the mark is private, 0 if shared with the room.
+ *
+ * Next Flag Rules packet:
+ *   Requests that the next board be played under the following flag rules, as in the Flag Rules
+ *   packet. The rules only apply to the room's shared board.
+ *   u8: who may remove a flag other than the player who placed it.
+ *   u8: options. Unknown bits must be 0.
  */
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+use crate::board;
+use crate::encoding::ServerToClientPacket;
+
+// What each player can see in fog of war mode: the squares within some distance of the squares
+// they have revealed. Squares others have revealed or flagged elsewhere look untouched to them.
+pub struct Fog {
+    radius: u16,
+    visible_by_player: BTreeMap<u8, Vec<Vec<bool>>>, // Indexed like the board, for players who have revealed something.
+}
+
+impl Fog {
+    pub fn new(radius: u8) -> Fog {
+        Fog { radius: radius as u16, visible_by_player: BTreeMap::new() }
+    }
+
+    pub fn is_visible(&self, player_id: u8, coords: (u16, u16)) -> bool {
+        self.visible_by_player.get(&player_id).is_some_and(|visible| visible[coords.0 as usize][coords.1 as usize])
+    }
+
+    // Lets the player see around the squares their action revealed, including those revealed by a
+    // cascade, which the board attributes to nobody. Returns the squares which were hidden from
+    // them until now.
+    pub fn reveal(&mut self, board: &board::MultiplayerBoard, player_id: u8, changes: &[board::SquareChange]) -> Vec<(u16, u16)> {
+        let (height, width) = (board.height(), board.width());
+        let visible = self.visible_by_player.entry(player_id)
+            .or_insert_with(|| vec![vec![false; width as usize]; height as usize]);
+        let mut newly_visible = vec![];
+        let revealed = changes.iter().filter(|change| matches!(change.entry, board::Entry::Revealed { .. }));
+        for change in revealed {
+            let (i, j) = change.coords;
+            for ni in i.saturating_sub(self.radius)..=i.saturating_add(self.radius).min(height - 1) {
+                for nj in j.saturating_sub(self.radius)..=j.saturating_add(self.radius).min(width - 1) {
+                    if !visible[ni as usize][nj as usize] {
+                        visible[ni as usize][nj as usize] = true;
+                        newly_visible.push((ni, nj));
+                    }
+                }
+            }
+        }
+        newly_visible
+    }
+
+    // Hides everything from a player who is gone, so whoever is handed their ID starts afresh.
+    pub fn forget_player(&mut self, player_id: u8) {
+        self.visible_by_player.remove(&player_id);
+    }
+
+    // Whether the player should be told of the change. Players always see what they did themselves.
+    pub fn shows(&self, player_id: u8, change: &board::SquareChange) -> bool {
+        change.player_id == player_id || self.is_visible(player_id, change.coords)
+    }
+
+    // The board as the player sees it.
+    pub fn full_board(&self, board: &board::MultiplayerBoard, player_id: u8) -> ServerToClientPacket {
+        let mut entries = board.revealed_board().clone();
+        let mut player_ids = board.player_ids().clone();
+        let mut cascade_owners = board.cascade_owners().clone();
+        for i in 0..board.height() {
+            for j in 0..board.width() {
+                let (i, j) = (i as usize, j as usize);
+                if player_ids[i][j] != player_id && !self.is_visible(player_id, (i as u16, j as u16)) {
+                    entries[i][j] = board::Entry::Unknown { mark: None };
+                    player_ids[i][j] = 0;
+                    cascade_owners[i][j] = 0;
+                }
+            }
+        }
+        ServerToClientPacket::FullBoard {
+            width: board.width(),
+            height: board.height(),
+            num_mines: board.num_mines(),
+            board_state: *board.board_state(),
+            entries,
+            player_ids,
+            cascade_owners,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revealed(coords: (u16, u16), player_id: u8) -> board::SquareChange {
+        board::SquareChange { coords, entry: board::Entry::Revealed { num_mines: 1 }, player_id, cascade_owner: player_id }
+    }
+
+    #[test]
+    fn sees_as_far_as_the_radius_around_revealed_squares() {
+        let board = board::MultiplayerBoard::new(6, 6, 1, 0);
+        let mut fog = Fog::new(1);
+        assert_eq!(fog.reveal(&board, 1, &[revealed((0, 0), 1)]), vec![(0, 0), (0, 1), (1, 0), (1, 1)]);
+        assert_eq!(fog.reveal(&board, 1, &[revealed((1, 1), 0)]).len(), 5);
+        assert!(fog.is_visible(1, (2, 2)) && !fog.is_visible(1, (3, 3)) && !fog.is_visible(2, (0, 0)));
+
+        // Players always see their own squares, wherever they are.
+        assert!(fog.shows(2, &revealed((5, 5), 2)));
+        assert!(!fog.shows(2, &revealed((5, 5), 1)));
+    }
+
+    #[test]
+    fn hides_everything_from_a_player_who_is_gone() {
+        let board = board::MultiplayerBoard::new(6, 6, 1, 0);
+        let mut fog = Fog::new(2);
+        fog.reveal(&board, 1, &[revealed((3, 3), 1)]);
+        fog.forget_player(1);
+        assert!(!fog.is_visible(1, (3, 3)));
+    }
+}
@@ -0,0 +1,1817 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::board;
+use crate::daily::{self, DailyOutcome, DailyResult, DailyResults, DailySeeds};
+use crate::encoding::{Action, PacketError, ServerToClientPacket};
+use crate::fog::Fog;
+use crate::game_log::{GameLog, GameRecorder, LoggedPlayer};
+use crate::history::GameRecord;
+use crate::leaderboard::PlayerResult;
+use crate::mode::{FlagRemoval, FlagRules, GameMode};
+use crate::player_list::{Player, PlayerList};
+use crate::race::Race;
+use crate::rotation::Rotation;
+use crate::teams::Teams;
+
+const MIN_DELAY_TILL_NEW_BOARD_MS: u64 = 500;
+const FREEZE_PENALTY_SQUARES: u32 = 10; // Taken off a player's score for each mine they hit in freeze mode.
+
+// Source of time for the game, so that it can be controlled outside of a running server.
+pub trait Clock: Send {
+    // Milliseconds since the Unix epoch.
+    fn now_ms(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("system clock is after 1970");
+        since_epoch.as_millis() as u64
+    }
+}
+
+// One clock shared by the game and the connections, so they agree on the time.
+impl<C: Clock + Sync + ?Sized> Clock for std::sync::Arc<C> {
+    fn now_ms(&self) -> u64 {
+        (**self).now_ms()
+    }
+}
+
+// Something that happened in a room. Players are identified by their address key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Connect { player: String },
+    Disconnect { player: String }, // The player's last connection has closed.
+    RevealSquare { player: String, coords: (u16, u16) },
+    ChordSquare { player: String, coords: (u16, u16) },
+    ToggleFlagSquare { player: String, coords: (u16, u16) },
+    CycleMark { player: String, coords: (u16, u16), private: bool },
+    NewBoard,
+    NextBoardConfig { width: u16, height: u16, num_mines: u32 },
+    NextGameMode(GameMode),
+    NextFlagRules(FlagRules),
+    ChooseTeam { player: String, team: u8 },
+    StartDaily { player: String, preset: daily::Preset },
+    LeaveDaily { player: String },
+    RequestDailyRanking { preset: daily::Preset },
+    TurnTimedOut,
+}
+
+// Something the game wants done outside of it.
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+    ToAll(ServerToClientPacket),
+    Reply(ServerToClientPacket), // To the connection the event came from.
+    ToPlayer { player: String, packet: ServerToClientPacket }, // To every connection of the player.
+    GameFinished { log: GameLog, record: GameRecord, results: Vec<PlayerResult> },
+    Evicted { player: String }, // The player was dropped to make room for a new one, so is no longer sent anything.
+    DailyResult(DailyResult), // An attempt at a daily challenge started or finished.
+}
+
+#[derive(Debug)]
+struct BoardConfig {
+    width: u16,
+    height: u16,
+    num_mines: u32,
+}
+
+// What one player did on the current board.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlayerStats {
+    pub squares_revealed: u32, // Including those revealed by cascades and chords.
+    pub chords: u32,
+    pub correct_flags: u32, // Flags on mines, counted when the game ends.
+    pub wrong_flags: u32,   // Flags on squares which are not mines, counted when the game ends.
+    pub mines_hit: u32,
+    pub hints_used: u32, // Always 0, as the game has no hints to ask for yet.
+}
+
+// A player's attempt at a daily challenge, which they play away from the room.
+struct DailySession {
+    day: u32,
+    preset: daily::Preset,
+    board: board::MultiplayerBoard,
+    safe_square: (u16, u16),
+    end_packets: Vec<ServerToClientPacket>, // What was sent when the attempt ended, for reconnecting clients.
+}
+
+impl DailySession {
+    // Packets which show the attempt as it is.
+    fn packets(&self, now_ms: u64) -> Vec<ServerToClientPacket> {
+        let mut packets = vec![
+            ServerToClientPacket::DailyChallenge { day: self.day, preset: self.preset, safe_square: self.safe_square },
+            ServerToClientPacket::full_board(&self.board),
+            ServerToClientPacket::game_clock(&self.board, now_ms),
+        ];
+        packets.extend(self.end_packets.iter().cloned());
+        packets
+    }
+
+    fn result(&self, identity: &str, name: &str, outcome: DailyOutcome, now_ms: u64) -> DailyResult {
+        DailyResult {
+            day: self.day,
+            preset: self.preset,
+            identity: identity.to_string(),
+            name: name.to_string(),
+            outcome,
+            duration_ms: self.board.elapsed_ms(now_ms),
+            solved_three_bv: self.board.stats(now_ms).map_or(0, |stats| stats.solved_three_bv),
+        }
+    }
+}
+
+enum StateInfo {
+    Ongoing,
+    Lost {loser_id: u8, hit_mines: Vec<(u16, u16)>, wrong_flags: Vec<(u16, u16)>},
+    Won,
+}
+
+// Packets telling clients what an accepted reveal or chord by the given player at now_ms did to the
+// board.
+pub fn action_packets(
+    player_id: u8,
+    result: &board::ActionResult,
+    board: &board::MultiplayerBoard,
+    now_ms: u64,
+) -> Vec<ServerToClientPacket> {
+    let mut packets = vec![ServerToClientPacket::sparse_board(&result.changes)];
+    packets.extend(result_packets(player_id, result, board, now_ms));
+    packets
+}
+
+// What follows the changed squares after a reveal or chord: the clock if the board just started,
+// and the outcome.
+fn result_packets(
+    player_id: u8,
+    result: &board::ActionResult,
+    board: &board::MultiplayerBoard,
+    now_ms: u64,
+) -> Vec<ServerToClientPacket> {
+    let mut packets = vec![];
+    if result.started {
+        packets.push(ServerToClientPacket::game_clock(board, now_ms));
+    }
+    packets.extend(outcome_packets(player_id, &result.outcome, board, now_ms));
+    packets
+}
+
+// Packets telling clients that the board was lost by the given player or won, if it was.
+pub fn outcome_packets(
+    player_id: u8,
+    outcome: &board::Outcome,
+    board: &board::MultiplayerBoard,
+    now_ms: u64,
+) -> Vec<ServerToClientPacket> {
+    let mut packets = vec![];
+    match *outcome {
+        board::Outcome::Ongoing => {}
+        board::Outcome::Lost { ref hit_mines, ref wrong_flags } => {
+            packets.push(ServerToClientPacket::BoardLoss {
+                loser_id: player_id,
+                duration_ms: board.elapsed_ms(now_ms),
+                hit_mines: hit_mines.clone(),
+                wrong_flags: wrong_flags.clone(),
+            });
+            packets.push(ServerToClientPacket::true_board(board));
+            packets.push(ServerToClientPacket::game_clock(board, now_ms));
+        }
+        board::Outcome::Won => {
+            packets.push(ServerToClientPacket::BoardWin { duration_ms: board.elapsed_ms(now_ms) });
+            packets.push(ServerToClientPacket::true_board(board));
+            packets.push(ServerToClientPacket::game_clock(board, now_ms));
+        }
+    }
+    packets
+}
+
+// Plays an action on a board which only the given player sees, returning the packets to send them.
+pub fn play_action(
+    board: &mut board::MultiplayerBoard,
+    player_id: u8,
+    action: Action,
+    coords: (u16, u16),
+    now_ms: u64,
+) -> Result<Vec<ServerToClientPacket>, board::Rejection> {
+    match action {
+        Action::Reveal => board.reveal_square(coords, player_id, now_ms)
+            .map(|result| action_packets(player_id, &result, board, now_ms)),
+        Action::Chord => board.chord_square(coords, player_id, now_ms)
+            .map(|result| action_packets(player_id, &result, board, now_ms)),
+        Action::ToggleFlag | Action::CycleMark => board.cycle_mark_square(coords, player_id, mark_cycle(action))
+            .map(|change| vec![ServerToClientPacket::sparse_board(&[change])]),
+    }
+}
+
+// Marks a marking action cycles through. Flagging only toggles a flag.
+fn mark_cycle(action: Action) -> &'static [board::Mark] {
+    match action {
+        Action::ToggleFlag => &[board::Mark::Flag],
+        _ => &board::MARK_CYCLE,
+    }
+}
+
+// All the rules of a room. Events go in and the packets they cause come out.
+pub struct GameState {
+    board_config: BoardConfig,
+    mode: GameMode,      // Of the current board.
+    next_mode: GameMode, // For the next board.
+    flag_rules: FlagRules,      // Of the current board.
+    next_flag_rules: FlagRules, // For the next board.
+    removal_requests: BTreeMap<(u16, u16), u8>, // Flags someone has asked to remove, by who asked.
+    board: board::MultiplayerBoard, // Stays blank during a race.
+    race: Option<Race>,  // Only in race mode.
+    teams: Option<Teams>, // Only in team mode.
+    eliminated: Vec<u8>, // Players knocked out of the current board, in the order they went out.
+    lives_left: u8,      // Shared by everyone in lives mode.
+    frozen_until_ms: BTreeMap<u8, u64>, // By player ID, in freeze mode.
+    rotation: Option<Rotation>, // Only in turn-based mode.
+    connected: BTreeSet<u8>,    // Player IDs.
+    fog: Option<Fog>,           // Only in fog of war mode.
+    private_marks: HashMap<u8, BTreeMap<(u16, u16), board::Mark>>, // By player ID, on the room's board.
+    players: PlayerList,
+    state_info: StateInfo,
+    player_stats: BTreeMap<u8, PlayerStats>, // Players who have acted on the current board.
+    recorder: GameRecorder,
+    daily_sessions: HashMap<String, DailySession>, // By address key of the player.
+    daily_results: DailyResults,
+    daily_seeds: DailySeeds,
+    clock: Box<dyn Clock>,
+    rng: StdRng, // Seeds new boards.
+}
+
+impl GameState {
+    pub fn new(
+        nouns: Vec<String>,
+        adjectives: Vec<String>,
+        daily_results: DailyResults,
+        daily_seeds: DailySeeds,
+        clock: Box<dyn Clock>,
+        mut rng: StdRng,
+    ) -> GameState {
+        let board_config = BoardConfig {
+            width: 20,
+            height: 20,
+            num_mines: 80,
+        };
+        assert!(board::MultiplayerBoard::possible_config(board_config.width, board_config.height, board_config.num_mines));
+        let board = board::MultiplayerBoard::new(
+            board_config.width,
+            board_config.height,
+            board_config.num_mines,
+            rng.gen(),
+        );
+        let players = PlayerList::new(nouns, adjectives, StdRng::from_rng(&mut rng).expect("seeded"));
+        let recorder = GameRecorder::new(clock.now_ms());
+
+        GameState {
+            board_config,
+            mode: GameMode::Coop,
+            next_mode: GameMode::Coop,
+            flag_rules: FlagRules::FREE,
+            next_flag_rules: FlagRules::FREE,
+            removal_requests: BTreeMap::new(),
+            board,
+            race: None,
+            teams: None,
+            eliminated: vec![],
+            lives_left: 0,
+            frozen_until_ms: BTreeMap::new(),
+            rotation: None,
+            connected: BTreeSet::new(),
+            fog: None,
+            private_marks: HashMap::new(),
+            players,
+            state_info: StateInfo::Ongoing,
+            player_stats: BTreeMap::new(),
+            recorder,
+            daily_sessions: HashMap::new(),
+            daily_results,
+            daily_seeds,
+            clock,
+            rng,
+        }
+    }
+
+    // Whether the player is away from the room playing a daily challenge, so should not be sent
+    // what happens in the room.
+    pub fn in_daily(&self, player: &str) -> bool {
+        self.daily_sessions.contains_key(player)
+    }
+
+    // Time until the current turn runs out, if turns are timed.
+    pub fn turn_time_left_ms(&self) -> Option<u64> {
+        let until_ms = self.rotation.as_ref()?.until_ms()?;
+        Some(until_ms.saturating_sub(self.clock.now_ms()))
+    }
+
+    pub fn apply(&mut self, event: Event) -> Vec<Outgoing> {
+        let mut out = vec![];
+        let now_ms = self.clock.now_ms();
+        match event {
+            Event::RevealSquare { ref player, coords } | Event::ChordSquare { ref player, coords } => {
+                let Some(this_player_id) = self.players.keep_addr_alive(player, now_ms) else {
+                    println!("Ignoring action from unknown player {player}.");
+                    return out;
+                };
+                let action = if let Event::RevealSquare { .. } = event { Action::Reveal } else { Action::Chord };
+                if self.in_daily(player) {
+                    return self.daily_action(player, this_player_id, action, coords, now_ms);
+                }
+                if self.race.is_some() {
+                    return self.race_action(player, this_player_id, action, coords, now_ms);
+                }
+                if let Err(rejection) = self.may_act(this_player_id, action, now_ms) {
+                    out.push(Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection }));
+                    return out;
+                }
+
+                let result = match action {
+                    Action::Reveal => self.board.reveal_square(coords, this_player_id, now_ms),
+                    _ => self.board.chord_square(coords, this_player_id, now_ms),
+                };
+                let mut result = match result {
+                    Ok(result) => result,
+                    Err(rejection) => {
+                        out.push(Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection }));
+                        return out;
+                    }
+                };
+                self.recorder.record(now_ms, this_player_id, action, coords);
+                // Flags the action revealed or showed to be wrong can no longer be asked to be removed.
+                let board = &self.board;
+                self.removal_requests.retain(|&coords, _| board.revealed_entry_at(coords).is_flagged());
+
+                let stats = self.player_stats.entry(this_player_id).or_default();
+                stats.squares_revealed += result.changes.iter()
+                    .filter(|change| matches!(change.entry, board::Entry::Revealed { .. }))
+                    .count() as u32;
+                if action == Action::Chord {
+                    stats.chords += 1;
+                }
+                if let board::Outcome::Lost { ref hit_mines, .. } = result.outcome {
+                    stats.mines_hit += hit_mines.len() as u32;
+                }
+                stats.mines_hit += result.exploded.len() as u32;
+
+                let eliminated = self.mode == GameMode::Elimination && !result.exploded.is_empty();
+                if eliminated {
+                    self.eliminated.push(this_player_id);
+                    if self.no_survivors() {
+                        result.outcome = self.board.lose(now_ms);
+                    }
+                }
+
+                let lost_lives = matches!(self.mode, GameMode::Lives { .. }) && !result.exploded.is_empty();
+                if lost_lives {
+                    self.lives_left = self.lives_left.saturating_sub(result.exploded.len() as u8);
+                    if self.lives_left == 0 {
+                        result.outcome = self.board.lose(now_ms);
+                    }
+                }
+
+                let frozen = match self.mode {
+                    GameMode::Freeze { seconds } if !result.exploded.is_empty() => {
+                        self.frozen_until_ms.insert(this_player_id, now_ms + 1000 * seconds as u64);
+                        true
+                    }
+                    _ => false,
+                };
+
+                out.extend(self.change_packets(this_player_id, &result.changes));
+                out.extend(result_packets(this_player_id, &result, &self.board, now_ms).into_iter().map(Outgoing::ToAll));
+                if frozen {
+                    out.push(Outgoing::ToAll(self.player_frozen(this_player_id, now_ms)));
+                }
+                if eliminated {
+                    out.push(Outgoing::ToAll(ServerToClientPacket::PlayerEliminated { player_id: this_player_id }));
+                }
+                if lost_lives {
+                    out.push(Outgoing::ToAll(self.lives()));
+                }
+                if self.teams.is_some() {
+                    out.push(Outgoing::ToAll(self.team_scores()));
+                }
+                if self.mode == GameMode::Territory {
+                    out.push(Outgoing::ToAll(self.territory()));
+                }
+
+                match result.outcome {
+                    board::Outcome::Ongoing => {
+                        out.extend(self.pass_turn(now_ms));
+                    }
+                    board::Outcome::Lost { hit_mines, wrong_flags } => {
+                        self.state_info = StateInfo::Lost { loser_id: this_player_id, hit_mines, wrong_flags };
+                        out.extend(self.finish_game(now_ms));
+                    }
+                    board::Outcome::Won => {
+                        self.state_info = StateInfo::Won;
+                        out.extend(self.finish_game(now_ms));
+                    }
+                }
+            }
+            Event::ToggleFlagSquare { ref player, coords } | Event::CycleMark { ref player, coords, .. } => {
+                let Some(this_player_id) = self.players.keep_addr_alive(player, now_ms) else {
+                    println!("Ignoring action from unknown player {player}.");
+                    return out;
+                };
+                let action = if let Event::ToggleFlagSquare { .. } = event { Action::ToggleFlag } else { Action::CycleMark };
+                if self.in_daily(player) {
+                    return self.daily_action(player, this_player_id, action, coords, now_ms);
+                }
+                if self.race.is_some() {
+                    return self.race_action(player, this_player_id, action, coords, now_ms);
+                }
+                if let Event::CycleMark { private: true, .. } = event {
+                    return self.private_mark(player, this_player_id, coords);
+                }
+                if let Err(rejection) = self.may_act(this_player_id, action, now_ms) {
+                    out.push(Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection }));
+                    return out;
+                }
+                match self.flag_rules_allow(this_player_id, coords) {
+                    Ok(true) => {}
+                    Ok(false) => return self.removal_request_packets(this_player_id, coords),
+                    Err(rejection) => {
+                        out.push(Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection }));
+                        return out;
+                    }
+                }
+
+                match self.board.cycle_mark_square(coords, this_player_id, mark_cycle(action)) {
+                    Ok(change) => {
+                        self.removal_requests.remove(&coords);
+                        self.recorder.record(now_ms, this_player_id, action, coords);
+                        self.player_stats.entry(this_player_id).or_default();
+                        out.extend(self.change_packets(this_player_id, &[change]));
+                        if let GameMode::Turns { free_flags: false, .. } = self.mode {
+                            out.extend(self.pass_turn(now_ms));
+                        }
+                    }
+                    Err(rejection) => {
+                        out.push(Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection }));
+                    }
+                }
+            }
+            Event::Connect { player } => {
+                if let Some(evicted) = self.players.construct_player(&player, now_ms) {
+                    // They are no longer sent anything, so can't play on.
+                    self.daily_sessions.remove(&evicted);
+                    out.push(Outgoing::Evicted { player: evicted });
+                    // The newcomer was handed the dropped player's ID, and must not inherit what
+                    // they did.
+                    let evicted_id = self.players.get_player(&player).unwrap().id();
+                    self.forget_player(evicted_id);
+                    out.extend(self.full_board_packets());
+                    // They may have been the last one still playing, or had the turn.
+                    out.extend(self.left_play(evicted_id, now_ms));
+                }
+                let this_player_id = self.players.get_player(&player).unwrap().id();
+                self.connected.insert(this_player_id);
+                // Someone has turned up to take the turn nobody could.
+                let takes_turn = self.rotation.as_ref().is_some_and(|rotation| rotation.player_id().is_none())
+                    && self.board.finish_ms().is_none();
+                if takes_turn {
+                    let player_ids = self.players_in_room();
+                    self.rotation.as_mut().unwrap().advance(&player_ids, now_ms);
+                }
+                let joined_team = self.teams.as_mut().is_some_and(|teams| teams.assign(this_player_id));
+                let this_player = self.players.get_player(&player).unwrap();
+
+                // Send the new connection their player info.
+                let this_player_info = ServerToClientPacket::YourPlayerInfo {
+                    player_id: this_player_id,
+                    color: self.player_color(this_player),
+                    name: this_player.name().to_string(),
+                };
+                out.push(Outgoing::Reply(this_player_info));
+
+                // Send the new connection the room, or the daily challenge the player is away playing.
+                let state = match self.daily_sessions.get(&player) {
+                    Some(session) => session.packets(now_ms),
+                    None => self.room_packets(this_player_id, now_ms),
+                };
+                out.extend(state.into_iter().map(Outgoing::Reply));
+
+                // Send the new player's info to all the other players.
+                out.push(Outgoing::ToAll(self.player_info(this_player)));
+                if joined_team {
+                    out.push(Outgoing::ToAll(self.player_team(this_player_id)));
+                    out.push(Outgoing::ToAll(self.team_scores()));
+                }
+                if takes_turn {
+                    out.push(Outgoing::ToAll(self.rotation.as_ref().unwrap().packet(now_ms)));
+                }
+            }
+            Event::Disconnect { player } => {
+                let Some(this_player_id) = self.players.get_player(&player).map(Player::id) else {
+                    return out;
+                };
+                self.connected.remove(&this_player_id);
+                out.extend(self.left_play(this_player_id, now_ms));
+            }
+            Event::TurnTimedOut => {
+                let timed_out = self.rotation.as_ref()
+                    .and_then(Rotation::until_ms)
+                    .is_some_and(|until_ms| until_ms <= now_ms);
+                if timed_out {
+                    out.extend(self.pass_turn(now_ms));
+                }
+            }
+            Event::NewBoard => {
+                // Only reset board if the current one is finished.
+                let finish_ms = match self.race {
+                    Some(ref race) => race.finish_ms(),
+                    None => self.board.finish_ms(),
+                };
+                if let Some(finish_ms) = finish_ms {
+                    if now_ms.saturating_sub(finish_ms) > MIN_DELAY_TILL_NEW_BOARD_MS {
+                        // Reset board.
+                        let seed = self.rng.gen();
+                        self.board = board::MultiplayerBoard::new(
+                            self.board_config.width,
+                            self.board_config.height,
+                            self.board_config.num_mines,
+                            seed,
+                        );
+                        self.recorder = GameRecorder::new(now_ms);
+                        self.player_stats.clear();
+                        self.state_info = StateInfo::Ongoing;
+                        self.eliminated.clear();
+                        self.frozen_until_ms.clear();
+                        self.private_marks.clear();
+                        self.removal_requests.clear();
+                        self.flag_rules = self.next_flag_rules;
+                        self.board.set_show_wrong_flags(self.flag_rules.show_wrong_flags);
+                        self.rotation = match self.next_mode {
+                            GameMode::Turns { turn_seconds, .. } => Some(Rotation::new(turn_seconds)),
+                            _ => None,
+                        };
+                        let player_ids = self.players_in_room();
+                        if let Some(ref mut rotation) = self.rotation {
+                            rotation.advance(&player_ids, now_ms);
+                        }
+                        self.fog = match self.next_mode {
+                            GameMode::Fog { radius } => Some(Fog::new(radius)),
+                            _ => None,
+                        };
+                        self.lives_left = match self.next_mode {
+                            GameMode::Lives { lives } => lives,
+                            _ => 0,
+                        };
+
+                        let mode_changed = self.mode != self.next_mode;
+                        self.mode = self.next_mode;
+                        self.board.set_survive_mines(self.mode.survives_mines());
+                        self.race = match self.mode {
+                            GameMode::Race => Some(Race::new(self.board.width(), self.board.height(), self.board.num_mines(), seed)),
+                            _ => None,
+                        };
+                        if mode_changed {
+                            // Teams are kept from board to board while the mode stays the same.
+                            self.teams = match self.mode {
+                                GameMode::Teams { num_teams } => Some(Teams::new(num_teams)),
+                                _ => None,
+                            };
+                        }
+                        if let Some(ref mut teams) = self.teams {
+                            for player in self.players.players() {
+                                teams.assign(player.id());
+                            }
+                        }
+                        out.push(Outgoing::ToAll(ServerToClientPacket::GameMode(self.mode)));
+                        out.push(Outgoing::ToAll(ServerToClientPacket::FlagRules(self.flag_rules)));
+                        if mode_changed {
+                            // Players are shown in their team's colour only in team mode.
+                            out.extend(self.players.players().map(|player| Outgoing::ToAll(self.player_info(player))));
+                        }
+                        out.extend(self.mode_packets(now_ms).into_iter().map(Outgoing::ToAll));
+                        out.push(Outgoing::ToAll(ServerToClientPacket::full_board(&self.board)));
+                        out.push(Outgoing::ToAll(ServerToClientPacket::game_clock(&self.board, now_ms)));
+                        if let Some(ref race) = self.race {
+                            out.push(Outgoing::ToAll(ServerToClientPacket::SafeSquare { coords: race.safe_square() }));
+                        }
+                    }
+                }
+            }
+            Event::StartDaily { player, preset } => {
+                let Some(this_player_id) = self.players.keep_addr_alive(&player, now_ms) else {
+                    println!("Ignoring daily challenge from unknown player {player}.");
+                    return out;
+                };
+                if self.in_daily(&player) {
+                    println!("Player {player} is already playing a daily challenge.");
+                    return out;
+                }
+                let day = daily::day_of(now_ms);
+                if self.daily_results.get(day, preset, &player).is_some() {
+                    out.push(Outgoing::Reply(PacketError::DailyAlreadyAttempted.to_packet()));
+                    return out;
+                }
+
+                let (board, safe_square) = self.daily_seeds.board(day, preset);
+                let session = DailySession { day, preset, board, safe_square, end_packets: vec![] };
+                let name = self.players.get_player_by_id(this_player_id).unwrap().name();
+                // Starting uses up the attempt, so that a bad start can't be abandoned for another go.
+                let result = session.result(&player, name, DailyOutcome::Unfinished, now_ms);
+                self.daily_results.record(result.clone());
+                out.push(Outgoing::DailyResult(result));
+                out.extend(session.packets(now_ms).into_iter().map(|packet| Outgoing::ToPlayer { player: player.clone(), packet }));
+                self.daily_sessions.insert(player, session);
+                out.extend(self.left_play(this_player_id, now_ms));
+            }
+            Event::LeaveDaily { player } => {
+                let Some(this_player_id) = self.players.keep_addr_alive(&player, now_ms) else {
+                    return out;
+                };
+                if self.daily_sessions.remove(&player).is_some() {
+                    let packets = self.room_packets(this_player_id, now_ms);
+                    out.extend(packets.into_iter().map(|packet| Outgoing::ToPlayer { player: player.clone(), packet }));
+                }
+            }
+            Event::RequestDailyRanking { preset } => {
+                out.push(Outgoing::Reply(self.daily_ranking(daily::day_of(now_ms), preset)));
+            }
+            Event::ChooseTeam { player, team } => {
+                let Some(this_player_id) = self.players.keep_addr_alive(&player, now_ms) else {
+                    return out;
+                };
+                // Teams are settled once the board has been started.
+                let started = *self.board.board_state() != board::BoardState::Uninitialized;
+                let Some(ref mut teams) = self.teams else {
+                    out.push(Outgoing::Reply(PacketError::InvalidTeam(team).to_packet()));
+                    return out;
+                };
+                if started || !teams.choose(this_player_id, team) {
+                    out.push(Outgoing::Reply(PacketError::InvalidTeam(team).to_packet()));
+                    return out;
+                }
+                let this_player = self.players.get_player(&player).unwrap();
+                out.push(Outgoing::ToAll(self.player_info(this_player)));
+                out.push(Outgoing::ToAll(self.player_team(this_player_id)));
+                out.push(Outgoing::ToAll(self.team_scores()));
+            }
+            Event::NextGameMode(mode) => {
+                self.next_mode = mode;
+                out.push(Outgoing::ToAll(ServerToClientPacket::NextGameMode(mode)));
+                println!("New game mode: {mode:?}");
+            }
+            Event::NextFlagRules(rules) => {
+                self.next_flag_rules = rules;
+                out.push(Outgoing::ToAll(ServerToClientPacket::NextFlagRules(rules)));
+                println!("New flag rules: {rules:?}");
+            }
+            Event::NextBoardConfig { width, height, num_mines } => {
+                if board::MultiplayerBoard::possible_config(width, height, num_mines) {
+                    self.board_config.width = width;
+                    self.board_config.height = height;
+                    self.board_config.num_mines = num_mines;
+
+                    out.push(Outgoing::ToAll(ServerToClientPacket::NextBoardConfig { width, height, num_mines }));
+                    println!("New board config: {:?}", self.board_config);
+                } else {
+                    println!("Invalid board config received: {width}x{height} with {num_mines} mines.");
+                }
+            }
+        }
+        out
+    }
+
+    // Packets which show the room as it is to the given player.
+    fn room_packets(&self, player_id: u8, now_ms: u64) -> Vec<ServerToClientPacket> {
+        // All the players' infos.
+        let mut packets: Vec<ServerToClientPacket> = self.players.players()
+            .map(|player| self.player_info(player))
+            .collect();
+        packets.push(ServerToClientPacket::GameMode(self.mode));
+        packets.push(ServerToClientPacket::FlagRules(self.flag_rules));
+        packets.extend(self.mode_packets(now_ms));
+
+        // The player's own board in a race.
+        if let Some(ref race) = self.race {
+            packets.extend(race.packets(player_id, now_ms));
+            packets.push(self.next_config_packet());
+            packets.push(ServerToClientPacket::NextGameMode(self.next_mode));
+            packets.push(ServerToClientPacket::NextFlagRules(self.next_flag_rules));
+            return packets;
+        }
+
+        // The full board, as far as the player can see it.
+        packets.push(match self.fog {
+            Some(ref fog) if self.board.finish_ms().is_none() => fog.full_board(&self.board, player_id),
+            _ => ServerToClientPacket::full_board(&self.board),
+        });
+        packets.push(ServerToClientPacket::game_clock(&self.board, now_ms));
+        if let Some(marks) = self.private_marks.get(&player_id) {
+            packets.extend(marks.iter()
+                .filter(|&(&coords, _)| self.board.revealed_entry_at(coords).is_unknown())
+                .map(|(&coords, &mark)| ServerToClientPacket::PrivateMark { coords, mark: Some(mark) }));
+        }
+        packets.extend(self.eliminated.iter().map(|&player_id| ServerToClientPacket::PlayerEliminated { player_id }));
+        packets.extend(self.frozen_until_ms.iter()
+            .filter(|&(_, &until_ms)| until_ms > now_ms)
+            .map(|(&player_id, _)| self.player_frozen(player_id, now_ms)));
+
+        // The loss/win info if applicable.
+        match self.state_info {
+            StateInfo::Lost { loser_id, ref hit_mines, ref wrong_flags } => {
+                packets.push(ServerToClientPacket::BoardLoss {
+                    loser_id,
+                    duration_ms: self.board.elapsed_ms(now_ms),
+                    hit_mines: hit_mines.clone(),
+                    wrong_flags: wrong_flags.clone(),
+                });
+                packets.push(ServerToClientPacket::true_board(&self.board));
+            }
+            StateInfo::Won => {
+                packets.push(ServerToClientPacket::BoardWin { duration_ms: self.board.elapsed_ms(now_ms) });
+                packets.push(ServerToClientPacket::true_board(&self.board));
+            }
+            _ => {}
+        }
+        if let StateInfo::Lost { .. } | StateInfo::Won = self.state_info {
+            if self.has_standings() {
+                packets.push(self.standings());
+            }
+            packets.push(ServerToClientPacket::GameStats(self.board.stats(now_ms).expect("finished board has been started")));
+            packets.push(self.game_summary());
+        }
+
+        // The next board configuration.
+        packets.push(self.next_config_packet());
+        packets.push(ServerToClientPacket::NextGameMode(self.next_mode));
+        packets.push(ServerToClientPacket::NextFlagRules(self.next_flag_rules));
+        packets
+    }
+
+    // Whether the mode's rules let the player act on the room's board.
+    fn may_act(&self, player_id: u8, action: Action, now_ms: u64) -> Result<(), board::Rejection> {
+        if self.eliminated.contains(&player_id) {
+            return Err(board::Rejection::Eliminated);
+        }
+        if self.frozen_until_ms.get(&player_id).is_some_and(|&until_ms| until_ms > now_ms) {
+            return Err(board::Rejection::Frozen);
+        }
+        if let Some(ref rotation) = self.rotation {
+            let is_mark = matches!(action, Action::ToggleFlag | Action::CycleMark);
+            let free = is_mark && matches!(self.mode, GameMode::Turns { free_flags: true, .. });
+            if !free && rotation.player_id() != Some(player_id) {
+                return Err(board::Rejection::NotYourTurn);
+            }
+        }
+        Ok(())
+    }
+
+    // Sends everyone the whole board again, or in fog of war mode as much of it as each can see.
+    fn full_board_packets(&self) -> Vec<Outgoing> {
+        let Some(fog) = self.fog.as_ref().filter(|_| self.board.finish_ms().is_none()) else {
+            return vec![Outgoing::ToAll(ServerToClientPacket::full_board(&self.board))];
+        };
+        self.players.players()
+            .filter_map(|player| {
+                let addr = self.players.get_addr_by_id(player.id())?;
+                let packet = fog.full_board(&self.board, player.id());
+                (!self.in_daily(addr)).then(|| Outgoing::ToPlayer { player: addr.to_string(), packet })
+            })
+            .collect()
+    }
+
+    // Whether the flag rules let the player change the mark on a square of the room's board.
+    // Ok(false) if they asked to remove someone else's flag and it waits for a second player.
+    fn flag_rules_allow(&mut self, player_id: u8, coords: (u16, u16)) -> Result<bool, board::Rejection> {
+        if self.flag_rules.marks_after_start && *self.board.board_state() == board::BoardState::Uninitialized {
+            return Err(board::Rejection::NotStarted);
+        }
+        // The board rejects what is out of bounds or too late by itself. Flags of players who have
+        // been forgotten (ID 0) have nobody left to own them.
+        let others_flag = self.board.in_bounds(coords)
+            && self.board.finish_ms().is_none()
+            && self.board.revealed_entry_at(coords).is_flagged()
+            && ![0, player_id].contains(&self.board.player_at(coords));
+        if !others_flag {
+            return Ok(true);
+        }
+        match self.flag_rules.removal {
+            FlagRemoval::Anyone => Ok(true),
+            FlagRemoval::Owner => Err(board::Rejection::NotFlagOwner),
+            FlagRemoval::Confirmed => match self.removal_requests.get(&coords) {
+                Some(&asker) if asker != player_id => Ok(true),
+                _ => {
+                    self.removal_requests.insert(coords, player_id);
+                    Ok(false)
+                }
+            },
+        }
+    }
+
+    // Announces a request to remove a flag. In fog of war mode only players who can see the flag
+    // are told.
+    fn removal_request_packets(&self, player_id: u8, coords: (u16, u16)) -> Vec<Outgoing> {
+        let packet = ServerToClientPacket::FlagRemovalRequested { coords, player_id };
+        let Some(ref fog) = self.fog else {
+            return vec![Outgoing::ToAll(packet)];
+        };
+        let owner = self.board.player_at(coords);
+        self.players.players()
+            .filter(|player| player.id() == player_id || player.id() == owner || fog.is_visible(player.id(), coords))
+            .filter_map(|player| self.players.get_addr_by_id(player.id()))
+            .filter(|addr| !self.in_daily(addr))
+            .map(|addr| Outgoing::ToPlayer { player: addr.to_string(), packet: packet.clone() })
+            .collect()
+    }
+
+    // Tells players of squares changed by an action. In fog of war mode each player is only told of
+    // those they can see, and the player who acted also of the squares they can now see.
+    fn change_packets(&mut self, player_id: u8, changes: &[board::SquareChange]) -> Vec<Outgoing> {
+        let Some(ref mut fog) = self.fog else {
+            return vec![Outgoing::ToAll(ServerToClientPacket::sparse_board(changes))];
+        };
+        let newly_visible = fog.reveal(&self.board, player_id, changes);
+        let fog = self.fog.as_ref().unwrap();
+        self.players.players()
+            .filter_map(|player| {
+                let addr = self.players.get_addr_by_id(player.id())?;
+                if self.in_daily(addr) {
+                    return None;
+                }
+                let mut view: Vec<board::SquareChange> = changes.iter()
+                    .filter(|change| fog.shows(player.id(), change))
+                    .copied()
+                    .collect();
+                if player.id() == player_id {
+                    view.extend(newly_visible.iter()
+                        .filter(|&&coords| !changes.iter().any(|change| change.coords == coords))
+                        .map(|&coords| self.board.square_change(coords)));
+                }
+                let packet = ServerToClientPacket::sparse_board(&view);
+                (!view.is_empty()).then(|| Outgoing::ToPlayer { player: addr.to_string(), packet })
+            })
+            .collect()
+    }
+
+    // Passes the turn on in turn-based mode.
+    fn pass_turn(&mut self, now_ms: u64) -> Option<Outgoing> {
+        let player_ids = self.players_in_room();
+        let rotation = self.rotation.as_mut()?;
+        rotation.advance(&player_ids, now_ms);
+        Some(Outgoing::ToAll(rotation.packet(now_ms)))
+    }
+
+    fn has_standings(&self) -> bool {
+        matches!(self.mode, GameMode::Elimination | GameMode::Freeze { .. })
+    }
+
+    // Squares revealed, less the penalty for mines hit in freeze mode.
+    fn score(&self, stats: &PlayerStats) -> u32 {
+        match self.mode {
+            GameMode::Freeze { .. } => stats.squares_revealed.saturating_sub(FREEZE_PENALTY_SQUARES * stats.mines_hit),
+            _ => stats.squares_revealed,
+        }
+    }
+
+    fn player_frozen(&self, player_id: u8, now_ms: u64) -> ServerToClientPacket {
+        let until_ms = self.frozen_until_ms[&player_id];
+        ServerToClientPacket::PlayerFrozen {
+            player_id,
+            until_ms,
+            remaining_ms: until_ms.saturating_sub(now_ms) as u32,
+            score: self.player_stats.get(&player_id).map_or(0, |stats| self.score(stats)),
+        }
+    }
+
+    // Players who survived by score, then those eliminated from last out to first out.
+    fn standings(&self) -> ServerToClientPacket {
+        let mut survivors: Vec<(u8, u32)> = self.player_stats.iter()
+            .filter(|(player_id, _)| !self.eliminated.contains(player_id))
+            .map(|(&player_id, stats)| (player_id, self.score(stats)))
+            .collect();
+        survivors.sort_by_key(|&(_, squares)| std::cmp::Reverse(squares));
+        let mut player_ids: Vec<u8> = survivors.into_iter().map(|(player_id, _)| player_id).collect();
+        player_ids.extend(self.eliminated.iter().rev());
+        ServerToClientPacket::Standings { player_ids }
+    }
+
+    // Players are shown in their team's colour in team mode.
+    fn player_color(&self, player: &Player) -> [u8; 4] {
+        match self.teams.as_ref().and_then(|teams| teams.team_of(player.id())) {
+            Some(team) => Teams::color(team),
+            None => *player.color(),
+        }
+    }
+
+    fn player_info(&self, player: &Player) -> ServerToClientPacket {
+        ServerToClientPacket::PlayerInfo {
+            player_id: player.id(),
+            color: self.player_color(player),
+            name: player.name().to_string(),
+        }
+    }
+
+    fn player_team(&self, player_id: u8) -> ServerToClientPacket {
+        let team = self.teams.as_ref().and_then(|teams| teams.team_of(player_id)).unwrap_or(0);
+        ServerToClientPacket::PlayerTeam { player_id, team }
+    }
+
+    // What the mode adds to the board: every player's team and the scores in team mode, and the
+    // lives left in lives mode, and whose turn it is in turn-based mode.
+    fn mode_packets(&self, now_ms: u64) -> Vec<ServerToClientPacket> {
+        let mut packets = vec![];
+        if self.teams.is_some() {
+            packets.extend(self.players.players().map(|player| self.player_team(player.id())));
+            packets.push(self.team_scores());
+        }
+        if let GameMode::Lives { .. } = self.mode {
+            packets.push(self.lives());
+        }
+        if let Some(ref rotation) = self.rotation {
+            packets.push(rotation.packet(now_ms));
+        }
+        if self.mode == GameMode::Territory {
+            packets.push(self.territory());
+        }
+        packets
+    }
+
+    fn lives(&self) -> ServerToClientPacket {
+        let GameMode::Lives { lives } = self.mode else {
+            panic!("not in lives mode");
+        };
+        ServerToClientPacket::Lives { lives_left: self.lives_left, lives }
+    }
+
+    // Area claimed by each player, and the winner once the board is solved.
+    fn territory(&self) -> ServerToClientPacket {
+        let mut area_by_player: BTreeMap<u8, u32> = self.player_stats.keys().map(|&player_id| (player_id, 0)).collect();
+        for i in 0..self.board.height() {
+            for j in 0..self.board.width() {
+                let player_id = self.board.cascade_owner_at((i, j));
+                if player_id == 0 {
+                    continue;
+                }
+                if let board::Entry::Revealed { num_mines } = self.board.revealed_entry_at((i, j)) {
+                    *area_by_player.entry(player_id).or_default() += 1 + num_mines as u32;
+                }
+            }
+        }
+        let areas: Vec<(u8, u32)> = area_by_player.into_iter().collect();
+        let winner_id = match self.board.board_state() {
+            board::BoardState::Won => Teams::leader(&areas).unwrap_or(0),
+            _ => 0,
+        };
+        ServerToClientPacket::Territory { winner_id, areas }
+    }
+
+    // Squares cleared by each team, and the winner once the board is solved.
+    fn team_scores(&self) -> ServerToClientPacket {
+        let teams = self.teams.as_ref().expect("in team mode");
+        let scores = teams.scores(self.player_stats.iter().map(|(&player_id, stats)| (player_id, stats.squares_revealed)));
+        let winning_team = match self.board.board_state() {
+            board::BoardState::Won => Teams::leader(&scores).unwrap_or(0),
+            _ => 0,
+        };
+        ServerToClientPacket::TeamScores { winning_team, scores }
+    }
+
+    fn next_config_packet(&self) -> ServerToClientPacket {
+        ServerToClientPacket::NextBoardConfig {
+            width: self.board_config.width,
+            height: self.board_config.height,
+            num_mines: self.board_config.num_mines
+        }
+    }
+
+    // Cycles a mark on the room's board which only the player sees, on all of their connections.
+    fn private_mark(&mut self, player: &str, player_id: u8, coords: (u16, u16)) -> Vec<Outgoing> {
+        let action = Action::CycleMark;
+        if !self.board.in_bounds(coords) {
+            return vec![Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection: board::Rejection::OutOfBounds })];
+        }
+        if !self.board.revealed_entry_at(coords).is_unknown() {
+            return vec![Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection: board::Rejection::AlreadyRevealed })];
+        }
+        let marks = self.private_marks.entry(player_id).or_default();
+        let mark = board::Mark::next(marks.get(&coords).copied(), &board::MARK_CYCLE);
+        match mark {
+            Some(mark) => marks.insert(coords, mark),
+            None => marks.remove(&coords),
+        };
+        vec![Outgoing::ToPlayer { player: player.to_string(), packet: ServerToClientPacket::PrivateMark { coords, mark } }]
+    }
+
+    // Plays an action on the player's own copy of the race board.
+    fn race_action(&mut self, player: &str, player_id: u8, action: Action, coords: (u16, u16), now_ms: u64) -> Vec<Outgoing> {
+        let in_room = self.players_in_room();
+        let race = self.race.as_mut().unwrap();
+        let packets = match race.act(player_id, action, coords, &in_room, now_ms) {
+            Ok(packets) => packets,
+            Err(rejection) => return vec![Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection })],
+        };
+        let mut out: Vec<Outgoing> = packets.into_iter()
+            .map(|packet| Outgoing::ToPlayer { player: player.to_string(), packet })
+            .collect();
+        if let Action::Reveal | Action::Chord = action {
+            out.push(Outgoing::ToAll(race.progress(player_id)));
+        }
+        if let Some(packet) = race.finished_packet() {
+            out.push(Outgoing::ToAll(packet));
+        }
+        out
+    }
+
+    // Plays an action on the player's daily challenge board instead of the room's.
+    fn daily_action(&mut self, player: &str, player_id: u8, action: Action, coords: (u16, u16), now_ms: u64) -> Vec<Outgoing> {
+        let session = self.daily_sessions.get_mut(player).unwrap();
+        let packets = match play_action(&mut session.board, player_id, action, coords, now_ms) {
+            Ok(packets) => packets,
+            Err(rejection) => return vec![Outgoing::Reply(ServerToClientPacket::ActionRejected { action, coords, rejection })],
+        };
+        let mut out: Vec<Outgoing> = packets.iter()
+            .map(|packet| Outgoing::ToPlayer { player: player.to_string(), packet: packet.clone() })
+            .collect();
+
+        let outcome = match session.board.board_state() {
+            board::BoardState::Lost => DailyOutcome::Lost,
+            board::BoardState::Won => DailyOutcome::Won,
+            _ => return out,
+        };
+        session.end_packets = packets.into_iter()
+            .filter(|packet| !matches!(packet, ServerToClientPacket::SparseBoard { .. }))
+            .collect();
+        let name = self.players.get_player_by_id(player_id).unwrap().name();
+        let result = session.result(player, name, outcome, now_ms);
+        let (day, preset) = (session.day, session.preset);
+        self.daily_results.record(result.clone());
+        out.push(Outgoing::DailyResult(result));
+        out.push(Outgoing::ToPlayer { player: player.to_string(), packet: self.daily_ranking(day, preset) });
+        out
+    }
+
+    fn daily_ranking(&self, day: u32, preset: daily::Preset) -> ServerToClientPacket {
+        ServerToClientPacket::DailyRanking {
+            day,
+            preset,
+            results: self.daily_results.ranking(day, preset).into_iter().cloned().collect(),
+        }
+    }
+
+    fn finish_game(&mut self, end_ms: u64) -> Vec<Outgoing> {
+        // Flags only count once it is known whether they were right.
+        for i in 0..self.board.height() {
+            for j in 0..self.board.width() {
+                let coords = (i, j);
+                let entry = self.board.revealed_entry_at(coords);
+                if !entry.is_flagged() && !entry.is_wrong_flag() {
+                    continue;
+                }
+                let stats = self.player_stats.entry(self.board.player_at(coords)).or_default();
+                if self.board.true_entry_at(coords).is_some_and(|entry| entry.is_mine()) {
+                    stats.correct_flags += 1;
+                } else {
+                    stats.wrong_flags += 1;
+                }
+            }
+        }
+
+        let log = self.game_log(end_ms);
+        let loser_id = match self.state_info {
+            StateInfo::Lost { loser_id, .. } => loser_id,
+            _ => 0,
+        };
+        let record = GameRecord::new(&log, loser_id, &self.board);
+        let stats = self.board.stats(end_ms).expect("finished board has been started");
+        let results = self.player_stats.iter()
+            .filter_map(|(&player_id, stats)| {
+                let player = self.players.get_player_by_id(player_id)?;
+                Some(PlayerResult {
+                    identity: self.players.get_addr_by_id(player_id)?.to_string(),
+                    name: player.name().to_string(),
+                    squares_cleared: stats.squares_revealed,
+                })
+            })
+            .collect();
+        let mut out = vec![];
+        if self.fog.is_some() {
+            // The fog lifts once the board is over.
+            out.push(Outgoing::ToAll(ServerToClientPacket::full_board(&self.board)));
+        }
+        if let Some(ref mut rotation) = self.rotation {
+            rotation.stop();
+            out.push(Outgoing::ToAll(rotation.packet(end_ms)));
+        }
+        if self.has_standings() {
+            out.push(Outgoing::ToAll(self.standings()));
+        }
+        out.extend([
+            Outgoing::ToAll(ServerToClientPacket::GameStats(stats)),
+            Outgoing::ToAll(self.game_summary()),
+            Outgoing::ToAll(ServerToClientPacket::GameRecorded { game_id: log.start_ms }),
+            Outgoing::GameFinished { log, record, results },
+        ]);
+        out
+    }
+
+    fn game_summary(&self) -> ServerToClientPacket {
+        ServerToClientPacket::GameSummary {
+            players: self.player_stats.iter().map(|(&player_id, &stats)| (player_id, stats)).collect(),
+        }
+    }
+
+    fn game_log(&self, end_ms: u64) -> GameLog {
+        self.recorder.finish(&self.board, end_ms, |id| {
+            self.players.get_player_by_id(id).map(|player| LoggedPlayer {
+                id,
+                color: *player.color(),
+                name: player.name().to_string(),
+            })
+        })
+    }
+
+    // Drops everything kept under the ID of a player who is gone, as it will be handed to someone
+    // else.
+    fn forget_player(&mut self, player_id: u8) {
+        self.board.forget_player(player_id);
+        self.recorder.forget_player(player_id);
+        self.player_stats.remove(&player_id);
+        if let Some(ref mut race) = self.race {
+            race.forget_player(player_id);
+        }
+        if let Some(ref mut teams) = self.teams {
+            teams.forget_player(player_id);
+        }
+        self.eliminated.retain(|&other_id| other_id != player_id);
+        self.frozen_until_ms.remove(&player_id);
+        self.connected.remove(&player_id);
+        self.private_marks.remove(&player_id);
+        self.removal_requests.retain(|_, &mut asker| asker != player_id);
+        if let Some(ref mut fog) = self.fog {
+            fog.forget_player(player_id);
+        }
+    }
+
+    // Players in the room: those connected and not away on a daily challenge. Only they can take a
+    // turn.
+    fn players_in_room(&self) -> Vec<u8> {
+        let away: Vec<u8> = self.daily_sessions.keys()
+            .filter_map(|player| self.players.get_player(player))
+            .map(|player| player.id())
+            .collect();
+        self.connected.iter().copied().filter(|player_id| !away.contains(player_id)).collect()
+    }
+
+    // Called once a player stops taking part in the room, by leaving, going off to a daily challenge
+    // or being dropped to make room. Passes their turn on, and ends a race that only they were still
+    // running or an elimination board that only they had survived.
+    fn left_play(&mut self, player_id: u8, now_ms: u64) -> Vec<Outgoing> {
+        let mut out = vec![];
+        if self.rotation.as_ref().is_some_and(|rotation| rotation.player_id() == Some(player_id)) {
+            out.extend(self.pass_turn(now_ms));
+        }
+        let in_room = self.players_in_room();
+        if let Some(ref mut race) = self.race {
+            if race.check_all_lost(&in_room, now_ms) {
+                out.extend(race.finished_packet().map(Outgoing::ToAll));
+            }
+        }
+        if *self.board.board_state() == board::BoardState::Ongoing && self.no_survivors() {
+            let outcome = self.board.lose(now_ms);
+            let loser_id = *self.eliminated.last().unwrap();
+            out.extend(outcome_packets(loser_id, &outcome, &self.board, now_ms).into_iter().map(Outgoing::ToAll));
+            if let board::Outcome::Lost { hit_mines, wrong_flags } = outcome {
+                self.state_info = StateInfo::Lost { loser_id, hit_mines, wrong_flags };
+            }
+            out.extend(self.finish_game(now_ms));
+        }
+        out
+    }
+
+    // Whether everyone in the room who has acted on the board has been eliminated, which ends an
+    // elimination board. Players who are only watching are not waited for.
+    fn no_survivors(&self) -> bool {
+        let in_room = self.players_in_room();
+        self.mode == GameMode::Elimination
+            && !self.eliminated.is_empty()
+            && self.player_stats.keys()
+                .filter(|player_id| in_room.contains(player_id))
+                .all(|player_id| self.eliminated.contains(player_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::game_log::GameResult;
+    use crate::player_list::Player;
+
+    const START_MS: u64 = 1_700_000_000_000;
+
+    // Time which only moves when a test moves it.
+    #[derive(Clone)]
+    struct FakeClock(Arc<AtomicU64>);
+
+    impl Clock for FakeClock {
+        fn now_ms(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    struct Room {
+        state: GameState,
+        clock: FakeClock,
+    }
+
+    impl Room {
+        fn new() -> Room {
+            let clock = FakeClock(Arc::new(AtomicU64::new(START_MS)));
+            let state = GameState::new(
+                vec!["Noun".to_string()],
+                vec!["Adjective".to_string()],
+                DailyResults::new(),
+                DailySeeds::new([0; daily::SECRET_LEN]),
+                Box::new(clock.clone()),
+                StdRng::seed_from_u64(1),
+            );
+            Room { state, clock }
+        }
+
+        fn advance(&self, ms: u64) {
+            self.clock.0.fetch_add(ms, Ordering::SeqCst);
+        }
+
+        fn apply(&mut self, event: Event) -> Vec<Outgoing> {
+            self.state.apply(event)
+        }
+
+        // Players are numbered by the order they connect in, which is also their player ID.
+        fn connect(&mut self, player: u8) -> Vec<Outgoing> {
+            let out = self.apply(Event::Connect { player: key(player) });
+            assert_eq!(self.state.players.get_player(&key(player)).map(Player::id), Some(player));
+            out
+        }
+
+        fn reveal(&mut self, player: u8, coords: (u16, u16)) -> Vec<Outgoing> {
+            self.apply(Event::RevealSquare { player: key(player), coords })
+        }
+
+        fn chord(&mut self, player: u8, coords: (u16, u16)) -> Vec<Outgoing> {
+            self.apply(Event::ChordSquare { player: key(player), coords })
+        }
+
+        fn flag(&mut self, player: u8, coords: (u16, u16)) -> Vec<Outgoing> {
+            self.apply(Event::ToggleFlagSquare { player: key(player), coords })
+        }
+
+        // Starts a race, and returns one of the mines on its board.
+        fn start_race(&mut self) -> (u16, u16) {
+            let (width, height, num_mines) = (self.state.board.width(), self.state.board.height(), self.state.board.num_mines());
+            let race = Race::new(width, height, num_mines, 1);
+            let board = board::MultiplayerBoard::with_safe_square(width, height, num_mines, 1, race.safe_square());
+            self.state.mode = GameMode::Race;
+            self.state.race = Some(race);
+            board.mines().unwrap()[0]
+        }
+
+        // Swaps the room's unstarted board for one with the given mines.
+        fn set_mines(&mut self, mines: &[(u16, u16)]) {
+            self.state.board = board::MultiplayerBoard::with_mines(self.state.board.width(), self.state.board.height(), 0, mines);
+        }
+    }
+
+    fn key(player: u8) -> String {
+        format!("player{player}")
+    }
+
+    fn to_all(out: &[Outgoing]) -> Vec<&ServerToClientPacket> {
+        out.iter()
+            .filter_map(|outgoing| match outgoing {
+                Outgoing::ToAll(packet) => Some(packet),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn replies(out: &[Outgoing]) -> Vec<&ServerToClientPacket> {
+        out.iter()
+            .filter_map(|outgoing| match outgoing {
+                Outgoing::Reply(packet) => Some(packet),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn rejection(out: &[Outgoing]) -> Option<board::Rejection> {
+        replies(out).into_iter().find_map(|packet| match packet {
+            ServerToClientPacket::ActionRejected { rejection, .. } => Some(*rejection),
+            _ => None,
+        })
+    }
+
+    // Whether the outgoing packets announce a race ending without a winner.
+    fn race_finished(out: &[Outgoing]) -> bool {
+        to_all(out).iter().any(|packet| matches!(packet, ServerToClientPacket::RaceFinished { winner_id: 0, .. }))
+    }
+
+    // Whose turn the outgoing packets say it is.
+    fn turn(out: &[Outgoing]) -> Option<u8> {
+        to_all(out).into_iter().find_map(|packet| match packet {
+            ServerToClientPacket::TurnChanged { player_id, .. } => Some(*player_id),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn sends_a_newcomer_the_room_and_everyone_else_the_newcomer() {
+        let mut room = Room::new();
+        room.connect(1);
+        let out = room.connect(2);
+
+        let replies = replies(&out);
+        assert!(matches!(replies[0], ServerToClientPacket::YourPlayerInfo { player_id: 2, .. }));
+        for player in [1, 2] {
+            assert!(replies.iter().any(|packet| matches!(packet, ServerToClientPacket::PlayerInfo { player_id, .. } if *player_id == player)));
+        }
+        assert!(replies.iter().any(|packet| matches!(packet, ServerToClientPacket::FullBoard { width: 20, height: 20, .. })));
+        assert!(matches!(to_all(&out)[..], [ServerToClientPacket::PlayerInfo { player_id: 2, .. }]));
+    }
+
+    #[test]
+    fn replies_with_rejections_only_to_the_acting_player() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+
+        let out = room.chord(1, (1, 1));
+        assert_eq!(rejection(&out), Some(board::Rejection::NotStarted));
+        assert!(to_all(&out).is_empty());
+
+        assert!(matches!(
+            to_all(&room.reveal(1, (1, 1)))[..],
+            [ServerToClientPacket::SparseBoard { .. }, ServerToClientPacket::GameClock { .. }]
+        ));
+        let out = room.reveal(1, (1, 1));
+        assert_eq!(rejection(&out), Some(board::Rejection::AlreadyRevealed));
+        assert!(to_all(&out).is_empty());
+    }
+
+    #[test]
+    fn starts_a_new_board_only_once_the_last_has_been_over_for_a_while() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.reveal(1, (1, 1));
+        let out = room.reveal(1, (0, 0));
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::BoardLoss { loser_id: 1, .. })));
+
+        room.apply(Event::NextBoardConfig { width: 9, height: 9, num_mines: 10 });
+        room.advance(MIN_DELAY_TILL_NEW_BOARD_MS);
+        assert!(room.apply(Event::NewBoard).is_empty());
+        room.advance(1);
+        let out = room.apply(Event::NewBoard);
+        assert!(matches!(
+            to_all(&out)[..],
+            [
+                ServerToClientPacket::GameMode(GameMode::Coop),
+                ServerToClientPacket::FlagRules(_),
+                ServerToClientPacket::FullBoard { width: 9, height: 9, num_mines: 10, .. },
+                ServerToClientPacket::GameClock { .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn hands_the_least_recently_active_players_id_to_a_newcomer_once_full() {
+        let mut room = Room::new();
+        for player in 1..=u8::MAX {
+            room.connect(player);
+            room.advance(1);
+        }
+        room.reveal(1, (0, 0));
+
+        let out = room.apply(Event::Connect { player: "newcomer".to_string() });
+        assert!(matches!(&out[0], Outgoing::Evicted { player } if *player == key(2)));
+        assert_eq!(room.state.players.get_player("newcomer").map(Player::id), Some(2));
+        assert!(room.state.players.get_player(&key(2)).is_none());
+    }
+
+    #[test]
+    fn forgets_what_a_player_did_once_their_id_is_handed_on() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Teams { num_teams: 2 };
+        room.state.teams = Some(Teams::new(2));
+        for player in 1..=u8::MAX {
+            room.connect(player);
+            if player == 2 {
+                room.apply(Event::ToggleFlagSquare { player: key(2), coords: (5, 5) });
+                room.apply(Event::StartDaily { player: key(2), preset: daily::Preset::Beginner });
+            }
+            room.advance(1);
+        }
+        room.apply(Event::ToggleFlagSquare { player: key(1), coords: (0, 0) });
+        assert_eq!(room.state.board.player_at((5, 5)), 2);
+        assert!(room.state.player_stats.contains_key(&2));
+        room.state.eliminated.push(2);
+        room.state.frozen_until_ms.insert(2, START_MS + 1_000_000);
+        room.apply(Event::CycleMark { player: key(1), coords: (6, 6), private: true });
+        room.state.private_marks.insert(2, BTreeMap::from([((6, 6), board::Mark::Question)]));
+        room.state.removal_requests.insert((0, 0), 2);
+        let mut rotation = Rotation::new(0);
+        rotation.advance(&[2], START_MS);
+        room.state.rotation = Some(rotation);
+
+        let out = room.apply(Event::Connect { player: "newcomer".to_string() });
+        assert_eq!(room.state.board.player_at((5, 5)), 0);
+        assert!(!room.state.player_stats.contains_key(&2));
+        assert!(!room.state.in_daily(&key(2)));
+        assert!(matches!(to_all(&out)[0], ServerToClientPacket::FullBoard { .. }));
+        assert!(room.state.eliminated.is_empty());
+        assert!(room.state.frozen_until_ms.is_empty());
+        assert_eq!(room.state.private_marks.keys().collect::<Vec<_>>(), vec![&1]);
+        assert!(room.state.removal_requests.is_empty());
+        assert_eq!(room.state.rotation.as_ref().unwrap().player_id(), Some(3));
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::PlayerTeam { player_id: 2, .. })));
+
+        // A flag left by the evicted player has nobody to own it, so whoever has the turn may take
+        // it down.
+        room.state.flag_rules.removal = FlagRemoval::Owner;
+        room.apply(Event::ToggleFlagSquare { player: key(3), coords: (5, 5) });
+        assert!(!room.state.board.revealed_entry_at((5, 5)).is_flagged());
+    }
+
+    #[test]
+    fn logs_every_accepted_action_once_the_board_is_finished() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.connect(2);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.advance(10);
+        room.reveal(1, (1, 1));
+        room.reveal(2, (1, 1)); // Rejected, so not logged.
+        room.advance(10);
+        let out = room.reveal(2, (0, 0));
+
+        let log = out.iter()
+            .find_map(|outgoing| match outgoing {
+                Outgoing::GameFinished { log, .. } => Some(log),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(log.result, GameResult::Lost);
+        assert_eq!((log.start_ms, log.end_ms), (START_MS, START_MS + 20));
+        assert_eq!(log.players.iter().map(|player| player.id).collect::<Vec<_>>(), vec![1, 2]);
+        let actions: Vec<(u64, u8, (u16, u16))> = log.actions.iter().map(|action| (action.time_ms, action.player_id, action.coords)).collect();
+        assert_eq!(actions, vec![(START_MS + 10, 1, (1, 1)), (START_MS + 20, 2, (0, 0))]);
+    }
+
+    #[test]
+    fn records_what_each_player_revealed_and_who_lost() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.connect(2);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.reveal(1, (1, 1)); // Breaking the board is attributed to no one.
+        room.reveal(1, (1, 2));
+        room.reveal(2, (2, 1));
+        let out = room.reveal(2, (0, 0));
+
+        let record = out.iter()
+            .find_map(|outgoing| match outgoing {
+                Outgoing::GameFinished { record, .. } => Some(record),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(record.loser_id, 2);
+        let squares: Vec<(u8, u32)> = record.players.iter().map(|player| (player.id, player.squares_revealed)).collect();
+        assert_eq!(squares, vec![(1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn sends_every_mine_once_the_game_is_over() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.reveal(1, (1, 1));
+        let out = room.reveal(1, (3, 3));
+
+        let mut mines = to_all(&out).into_iter()
+            .find_map(|packet| match packet {
+                ServerToClientPacket::TrueBoard { mines } => Some(mines.clone()),
+                _ => None,
+            })
+            .unwrap();
+        mines.sort();
+        assert_eq!(mines, vec![(0, 0), (0, 3), (3, 0), (3, 3)]);
+
+        let out = room.connect(2);
+        assert!(replies(&out).windows(2).any(|packets| matches!(
+            packets,
+            [ServerToClientPacket::BoardLoss { .. }, ServerToClientPacket::TrueBoard { .. }]
+        )));
+    }
+
+    #[test]
+    fn runs_the_game_clock_from_the_first_reveal_until_the_game_ends() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.advance(1_000);
+        let out = room.reveal(1, (1, 1));
+        assert!(matches!(
+            to_all(&out)[1],
+            ServerToClientPacket::GameClock { first_click_ms, finish_ms: 0, elapsed_ms: 0 } if *first_click_ms == START_MS + 1_000
+        ));
+
+        room.advance(2_500);
+        let out = room.reveal(1, (0, 0));
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::BoardLoss { duration_ms: 2_500, .. })));
+
+        room.advance(10_000);
+        let out = room.connect(2);
+        assert!(replies(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::GameClock { elapsed_ms: 2_500, .. })));
+    }
+
+    #[test]
+    fn sums_up_what_each_player_did_once_the_game_ends() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.connect(2);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.reveal(1, (1, 1));
+        room.reveal(1, (1, 2));
+        room.flag(2, (0, 0));
+        room.flag(2, (3, 1));
+        room.chord(2, (1, 1));
+        let out = room.reveal(1, (3, 3));
+
+        let summary = to_all(&out).into_iter()
+            .find_map(|packet| match packet {
+                ServerToClientPacket::GameSummary { players } => Some(players.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            summary,
+            vec![
+                (1, PlayerStats { squares_revealed: 2, mines_hit: 1, ..Default::default() }),
+                (2, PlayerStats { squares_revealed: 6, chords: 1, correct_flags: 1, wrong_flags: 1, ..Default::default() }),
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_up_the_daily_attempt_as_soon_as_it_starts() {
+        let mut room = Room::new();
+        room.connect(1);
+        let out = room.apply(Event::StartDaily { player: key(1), preset: daily::Preset::Beginner });
+        assert!(out.iter().any(|outgoing| matches!(outgoing, Outgoing::DailyResult(DailyResult { outcome: DailyOutcome::Unfinished, .. }))));
+        assert!(room.state.in_daily(&key(1)));
+
+        room.apply(Event::LeaveDaily { player: key(1) });
+        assert!(!room.state.in_daily(&key(1)));
+        let out = room.apply(Event::StartDaily { player: key(1), preset: daily::Preset::Beginner });
+        assert!(matches!(
+            replies(&out)[..],
+            [ServerToClientPacket::Error { code: crate::encoding::ErrorCode::DailyAlreadyAttempted, .. }]
+        ));
+        assert!(!room.state.in_daily(&key(1)));
+    }
+
+    #[test]
+    fn ends_a_race_without_waiting_for_players_who_never_act() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.connect(2);
+        let mine = room.start_race();
+
+        assert!(race_finished(&room.reveal(1, mine)));
+    }
+
+    #[test]
+    fn waits_for_every_runner_still_in_the_room_before_ending_a_race() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.connect(2);
+        let mine = room.start_race();
+        let safe_square = room.state.race.as_ref().unwrap().safe_square();
+        room.reveal(2, safe_square);
+
+        assert!(!race_finished(&room.reveal(1, mine)));
+        assert!(race_finished(&room.apply(Event::StartDaily { player: key(2), preset: daily::Preset::Beginner })));
+    }
+
+    #[test]
+    fn settles_teams_once_the_board_is_started() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Teams { num_teams: 2 };
+        room.state.teams = Some(Teams::new(2));
+        room.connect(1);
+        let out = room.connect(2);
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::PlayerTeam { player_id: 2, team: 2 })));
+
+        let out = room.apply(Event::ChooseTeam { player: key(2), team: 1 });
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::PlayerTeam { player_id: 2, team: 1 })));
+        room.set_mines(&[(0, 0)]);
+        room.reveal(1, (3, 3));
+        let out = room.apply(Event::ChooseTeam { player: key(2), team: 2 });
+        assert!(matches!(
+            replies(&out)[..],
+            [ServerToClientPacket::Error { code: crate::encoding::ErrorCode::InvalidTeam, .. }]
+        ));
+    }
+
+    #[test]
+    fn loses_an_elimination_board_without_waiting_for_players_only_watching() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Elimination;
+        for player in 1..=3 {
+            room.connect(player);
+        }
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.state.board.set_survive_mines(true);
+        room.reveal(1, (1, 1));
+        let out = room.reveal(2, (0, 0));
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::PlayerEliminated { player_id: 2 })));
+        assert_eq!(rejection(&room.reveal(2, (1, 2))), Some(board::Rejection::Eliminated));
+
+        // Player 3 never acted, so player 1 was the last one playing.
+        let out = room.reveal(1, (3, 3));
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::BoardLoss { loser_id: 1, .. })));
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::Standings { player_ids } if *player_ids == [1, 2])));
+    }
+
+    #[test]
+    fn loses_an_elimination_board_once_its_last_survivor_leaves() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Elimination;
+        room.connect(1);
+        room.connect(2);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.state.board.set_survive_mines(true);
+        room.reveal(1, (1, 1));
+        room.reveal(2, (0, 0));
+
+        let out = room.apply(Event::StartDaily { player: key(1), preset: daily::Preset::Beginner });
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::BoardLoss { loser_id: 2, .. })));
+    }
+
+    #[test]
+    fn loses_a_lives_board_only_once_the_last_life_is_gone() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Lives { lives: 2 };
+        room.state.lives_left = 2;
+        room.connect(1);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.state.board.set_survive_mines(true);
+        room.reveal(1, (1, 1));
+        let out = room.reveal(1, (0, 0));
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::Lives { lives_left: 1, lives: 2 })));
+        assert_eq!(*room.state.board.board_state(), board::BoardState::Ongoing);
+
+        let out = room.reveal(1, (3, 3));
+        let loss = to_all(&out).into_iter().find_map(|packet| match packet {
+            ServerToClientPacket::BoardLoss { loser_id: 1, hit_mines, .. } => Some(hit_mines.clone()),
+            _ => None,
+        });
+        assert_eq!(loss, Some(vec![(0, 0), (3, 3)]));
+    }
+
+    #[test]
+    fn freezes_a_player_who_hits_a_mine_and_takes_it_off_their_score() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Freeze { seconds: 2 };
+        room.connect(1);
+        room.connect(2);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.state.board.set_survive_mines(true);
+        room.reveal(2, (1, 1));
+        let out = room.reveal(1, (0, 0));
+        assert!(to_all(&out).iter().any(|packet| matches!(
+            packet,
+            ServerToClientPacket::PlayerFrozen { player_id: 1, until_ms, remaining_ms: 2_000, score: 0 } if *until_ms == START_MS + 2_000
+        )));
+
+        // Only the player who hit the mine is stopped, and only until the freeze expires.
+        assert_eq!(rejection(&room.reveal(1, (1, 2))), Some(board::Rejection::Frozen));
+        assert_eq!(rejection(&room.reveal(2, (1, 2))), None);
+        room.advance(2_000);
+        assert_eq!(rejection(&room.reveal(1, (2, 1))), None);
+    }
+
+    #[test]
+    fn passes_the_turn_when_it_is_played_runs_out_or_its_player_leaves() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Turns { turn_seconds: 5, free_flags: false };
+        room.state.rotation = Some(Rotation::new(5));
+        room.connect(1);
+        room.connect(2);
+        room.connect(3);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        assert_eq!(rejection(&room.reveal(2, (1, 1))), Some(board::Rejection::NotYourTurn));
+        assert_eq!(turn(&room.reveal(1, (1, 1))), Some(2));
+
+        room.advance(4_999);
+        assert_eq!(turn(&room.apply(Event::TurnTimedOut)), None);
+        room.advance(1);
+        assert_eq!(turn(&room.apply(Event::TurnTimedOut)), Some(3));
+        assert_eq!(turn(&room.apply(Event::Disconnect { player: key(3) })), Some(1));
+    }
+
+    #[test]
+    fn shows_each_player_only_the_board_near_what_they_revealed_until_it_is_over() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Fog { radius: 1 };
+        room.state.fog = Some(Fog::new(1));
+        room.connect(1);
+        room.connect(2);
+        room.set_mines(&[(0, 0), (0, 5), (5, 0), (5, 5)]);
+        let to_player = |out: &[Outgoing], player: u8| -> Vec<ServerToClientPacket> {
+            out.iter()
+                .filter_map(|outgoing| match outgoing {
+                    Outgoing::ToPlayer { player: to, packet } if *to == key(player) => Some(packet.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let out = room.reveal(1, (1, 1));
+        assert!(matches!(to_player(&out, 1)[..], [ServerToClientPacket::SparseBoard { .. }]));
+        assert!(to_player(&out, 2).is_empty());
+
+        let out = room.reveal(2, (5, 5));
+        assert!(to_player(&out, 1).is_empty());
+        assert!(to_all(&out).iter().any(|packet| matches!(packet, ServerToClientPacket::FullBoard { .. })));
+    }
+
+    #[test]
+    fn counts_each_square_a_player_claims_as_one_more_than_its_number() {
+        let mut room = Room::new();
+        room.state.mode = GameMode::Territory;
+        room.connect(1);
+        room.connect(2);
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.reveal(1, (1, 1));
+        room.reveal(2, (1, 2));
+        let out = room.reveal(2, (2, 2));
+        assert!(to_all(&out).iter().any(|packet| matches!(
+            packet,
+            ServerToClientPacket::Territory { winner_id: 0, areas } if *areas == [(1, 2), (2, 4)]
+        )));
+    }
+
+    #[test]
+    fn keeps_private_marks_to_the_player_who_made_them() {
+        let mut room = Room::new();
+        room.connect(1);
+        room.connect(2);
+        let out = room.apply(Event::CycleMark { player: key(1), coords: (2, 2), private: true });
+        assert!(to_all(&out).is_empty());
+        assert!(matches!(
+            &out[..],
+            [Outgoing::ToPlayer { player, packet: ServerToClientPacket::PrivateMark { coords: (2, 2), mark: Some(board::Mark::Flag) } }]
+                if *player == key(1)
+        ));
+        assert_eq!(room.state.board.revealed_entry_at((2, 2)), board::Entry::Unknown { mark: None });
+
+        // Only their own marks are sent back when they reconnect.
+        let private_marks = |out: &[Outgoing]| replies(out).iter().filter(|packet| matches!(packet, ServerToClientPacket::PrivateMark { .. })).count();
+        assert_eq!(private_marks(&room.connect(1)), 1);
+        assert_eq!(private_marks(&room.connect(2)), 0);
+
+        let out = room.apply(Event::CycleMark { player: key(2), coords: (3, 3), private: false });
+        assert!(matches!(to_all(&out)[..], [ServerToClientPacket::SparseBoard { .. }]));
+    }
+
+    #[test]
+    fn lets_only_the_owner_or_two_other_players_take_down_a_flag() {
+        let mut room = Room::new();
+        for player in 1..=3 {
+            room.connect(player);
+        }
+        room.set_mines(&[(0, 0), (0, 3), (3, 0), (3, 3)]);
+        room.state.flag_rules = FlagRules { removal: FlagRemoval::Owner, show_wrong_flags: false, marks_after_start: true };
+        assert_eq!(rejection(&room.flag(1, (2, 2))), Some(board::Rejection::NotStarted));
+
+        room.reveal(1, (1, 1));
+        room.flag(1, (2, 2));
+        assert_eq!(rejection(&room.flag(2, (2, 2))), Some(board::Rejection::NotFlagOwner));
+        room.flag(1, (2, 2));
+        assert!(!room.state.board.revealed_entry_at((2, 2)).is_flagged());
+
+        room.state.flag_rules.removal = FlagRemoval::Confirmed;
+        room.flag(1, (2, 2));
+        let out = room.flag(2, (2, 2));
+        assert!(matches!(to_all(&out)[..], [ServerToClientPacket::FlagRemovalRequested { coords: (2, 2), player_id: 2 }]));
+        // Asking twice doesn't make it two players.
+        room.flag(2, (2, 2));
+        assert!(room.state.board.revealed_entry_at((2, 2)).is_flagged());
+        room.flag(3, (2, 2));
+        assert!(!room.state.board.revealed_entry_at((2, 2)).is_flagged());
+        assert!(room.state.removal_requests.is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+use crate::board;
+use crate::encoding::Action;
+
+// Version of the file format described at the bottom of this file.
+pub const GAME_LOG_VERSION: u16 = 2;
+const MAGIC: &[u8; 4] = b"MINE";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoggedAction {
+    pub time_ms: u64, // Milliseconds since the Unix epoch.
+    pub player_id: u8,
+    pub action: Action,
+    pub coords: (u16, u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedPlayer {
+    pub id: u8,
+    pub color: [u8; 4],
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GameResult {
+    Lost = 1,
+    Won = 2,
+}
+
+// Everything needed to replay one board: its layout and every accepted action on it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameLog {
+    pub width: u16,
+    pub height: u16,
+    pub num_mines: u32,
+    pub seed: u64,
+    pub start_ms: u64, // When the board was created.
+    pub end_ms: u64,   // When the board was lost or won.
+    pub result: GameResult,
+    pub survive_mines: bool, // Whether hitting a mine left play going.
+    pub show_wrong_flags: bool, // Whether flags were shown as wrong once the revealed squares proved there was no mine under them.
+    pub mines: Vec<(u16, u16)>,
+    pub players: Vec<LoggedPlayer>, // Players who acted on the board.
+    pub actions: Vec<LoggedAction>,
+}
+
+impl GameLog {
+    // File name to write this log to, unique per board.
+    pub fn file_name(&self) -> String {
+        format!("{}-{:016x}.mines", self.start_ms, self.seed)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&GAME_LOG_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.width.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(&self.num_mines.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.start_ms.to_le_bytes());
+        bytes.extend_from_slice(&self.end_ms.to_le_bytes());
+        bytes.push(self.result as u8);
+        bytes.push(self.survive_mines as u8 | (self.show_wrong_flags as u8) << 1);
+
+        bytes.extend_from_slice(&(self.mines.len() as u32).to_le_bytes());
+        for &(i, j) in &self.mines {
+            bytes.extend_from_slice(&i.to_le_bytes());
+            bytes.extend_from_slice(&j.to_le_bytes());
+        }
+
+        assert!(self.players.len() <= u8::MAX as usize);
+        bytes.push(self.players.len() as u8);
+        for player in &self.players {
+            bytes.push(player.id);
+            bytes.extend_from_slice(&player.color);
+            let name = &player.name.as_bytes()[..player.name.len().min(u8::MAX as usize)];
+            bytes.push(name.len() as u8);
+            bytes.extend_from_slice(name);
+        }
+
+        bytes.extend_from_slice(&(self.actions.len() as u32).to_le_bytes());
+        for action in &self.actions {
+            bytes.extend_from_slice(&action.time_ms.to_le_bytes());
+            bytes.push(action.player_id);
+            bytes.push(action.action as u8);
+            bytes.extend_from_slice(&action.coords.0.to_le_bytes());
+            bytes.extend_from_slice(&action.coords.1.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+// Collects the actions on the current board until it is finished.
+pub struct GameRecorder {
+    start_ms: u64,
+    actions: Vec<LoggedAction>,
+}
+
+impl GameRecorder {
+    pub fn new(start_ms: u64) -> GameRecorder {
+        GameRecorder { start_ms, actions: vec![] }
+    }
+
+    pub fn record(&mut self, time_ms: u64, player_id: u8, action: Action, coords: (u16, u16)) {
+        self.actions.push(LoggedAction { time_ms, player_id, action, coords });
+    }
+
+    // Attributes the actions of a player who is gone to no one.
+    pub fn forget_player(&mut self, player_id: u8) {
+        for action in &mut self.actions {
+            if action.player_id == player_id {
+                action.player_id = 0;
+            }
+        }
+    }
+
+    // Produces the log of a finished board. The players are looked up by ID.
+    pub fn finish(
+        &self,
+        board: &board::MultiplayerBoard,
+        end_ms: u64,
+        players: impl Fn(u8) -> Option<LoggedPlayer>,
+    ) -> GameLog {
+        let result = match board.board_state() {
+            board::BoardState::Lost => GameResult::Lost,
+            board::BoardState::Won => GameResult::Won,
+            state => panic!("board is not finished: {state:?}"),
+        };
+        let mut player_ids: Vec<u8> = self.actions.iter().map(|action| action.player_id).collect();
+        player_ids.sort_unstable();
+        player_ids.dedup();
+        GameLog {
+            width: board.width(),
+            height: board.height(),
+            num_mines: board.num_mines(),
+            seed: board.seed(),
+            start_ms: self.start_ms,
+            end_ms,
+            result,
+            survive_mines: board.survive_mines(),
+            show_wrong_flags: board.show_wrong_flags(),
+            mines: board.mines().expect("finished board has been constructed"),
+            players: player_ids.into_iter().filter_map(players).collect(),
+            actions: self.actions.clone(),
+        }
+    }
+}
+
+/*
+ * Game Log File Format:
+ *   One finished board per file. Little Endian.
+ *   4 bytes: "MINE".
+ *   u16: format version, currently 2.
+ *   u16: board width.
+ *   u16: board height.
+ *   u32: number of mines.
+ *   u64: seed the board was generated from.
+ *   u64: time the board was created, in milliseconds since the Unix epoch.
+ *   u64: time the board was lost or won, in milliseconds since the Unix epoch.
+ *   u8: result.
+ *     1: lost.
+ *     2: won.
+ *   u8: rules. Not in version 1 files.
+ *     bit 0: hitting a mine revealed it and play went on, rather than losing the board. Such a
+ *       board may be lost after its last action, once no one was left to play it.
+ *     bit 1: a flag was shown as wrong once the revealed squares proved there was no mine under
+ *       it.
+ *   u32: number of mines again, followed by that many (u16: i, u16: j) mine coordinates. The
+ *     layout is stored as well as the seed so that logs stay readable if mine placement changes.
+ *   u8: number of players, followed by that many player records:
+ *     u8: player ID.
+ *     u8: R
+ *     u8: G
+ *     u8: B
+ *     u8: A
+ *     u8: length of name.
+ *     (length of name) bytes: player name in UTF-8.
+ *   u32: number of actions, followed by that many action records in the order they were applied:
+ *     u64: time of the action, in milliseconds since the Unix epoch.
+ *     u8: player ID.
+ *     u8: action.
+ *       0: reveal.
+ *       1: chord.
+ *       2: flag.
+ *       3: cycle mark, shared with the room.
+ *     u16: i
+ *     u16: j
+ *   Only accepted actions are recorded. The first reveal is recorded under the player who made it,
+ *   even though the squares it opens are attributed to no one.
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_logs_in_the_documented_layout() {
+        let log = GameLog {
+            width: 4,
+            height: 3,
+            num_mines: 1,
+            seed: 0x0102,
+            start_ms: 1_000,
+            end_ms: 2_000,
+            result: GameResult::Won,
+            survive_mines: true,
+            show_wrong_flags: true,
+            mines: vec![(2, 3)],
+            players: vec![LoggedPlayer { id: 7, color: [1, 2, 3, 4], name: "Ab".to_string() }],
+            actions: vec![LoggedAction { time_ms: 1_500, player_id: 7, action: Action::Chord, coords: (1, 2) }],
+        };
+        let mut expected = b"MINE".to_vec();
+        expected.extend_from_slice(&[2, 0, 4, 0, 3, 0, 1, 0, 0, 0]);
+        expected.extend_from_slice(&0x0102u64.to_le_bytes());
+        expected.extend_from_slice(&1_000u64.to_le_bytes());
+        expected.extend_from_slice(&2_000u64.to_le_bytes());
+        expected.extend_from_slice(&[2, 3]);
+        expected.extend_from_slice(&[1, 0, 0, 0, 2, 0, 3, 0]);
+        expected.extend_from_slice(&[1, 7, 1, 2, 3, 4, 2, b'A', b'b']);
+        expected.extend_from_slice(&[1, 0, 0, 0]);
+        expected.extend_from_slice(&1_500u64.to_le_bytes());
+        expected.extend_from_slice(&[7, 1, 1, 0, 2, 0]);
+        assert_eq!(log.encode(), expected);
+        assert_eq!(log.file_name(), "1000-0000000000000102.mines");
+    }
+}
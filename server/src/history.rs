@@ -0,0 +1,364 @@
+use crate::board;
+use crate::game_log::{GameLog, GameResult};
+
+// Version of the record format described at the bottom of this file.
+pub const HISTORY_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerRecord {
+    pub id: u8,
+    pub color: [u8; 4],
+    pub name: String,
+    pub squares_revealed: u32, // Revealed squares attributed to the player when the game ended.
+    pub flags: u32,            // Flags the player had standing when the game ended.
+}
+
+// What is remembered about a finished game once its board is gone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameRecord {
+    pub width: u16,
+    pub height: u16,
+    pub num_mines: u32,
+    pub seed: u64,
+    pub start_ms: u64, // When the board was created. Doubles as the game's ID.
+    pub end_ms: u64,   // When the board was lost or won.
+    pub duration_ms: u64, // Time on the game clock, from the first reveal until the end.
+    pub result: GameResult,
+    pub loser_id: u8, // Player who hit a mine, or 0 if the game was won.
+    pub players: Vec<PlayerRecord>,
+}
+
+impl GameRecord {
+    pub fn new(log: &GameLog, loser_id: u8, board: &board::MultiplayerBoard) -> GameRecord {
+        let players = log.players.iter()
+            .map(|player| {
+                let mut squares_revealed = 0;
+                let mut flags = 0;
+                for (entries, player_ids) in board.revealed_board().iter().zip(board.player_ids()) {
+                    for (entry, &player_id) in entries.iter().zip(player_ids) {
+                        if player_id != player.id {
+                            continue;
+                        }
+                        if let board::Entry::Revealed { .. } = entry {
+                            squares_revealed += 1;
+                        } else if entry.is_flagged() {
+                            flags += 1;
+                        }
+                    }
+                }
+                PlayerRecord {
+                    id: player.id,
+                    color: player.color,
+                    name: player.name.clone(),
+                    squares_revealed,
+                    flags,
+                }
+            })
+            .collect();
+        GameRecord {
+            width: log.width,
+            height: log.height,
+            num_mines: log.num_mines,
+            seed: log.seed,
+            start_ms: log.start_ms,
+            end_ms: log.end_ms,
+            duration_ms: board.elapsed_ms(log.end_ms),
+            result: log.result,
+            loser_id,
+            players,
+        }
+    }
+
+    pub fn game_id(&self) -> u64 {
+        self.start_ms
+    }
+
+    // One record of the history file, including its length prefix.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 4]; // Length, filled in at the end.
+        bytes.extend_from_slice(&HISTORY_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.width.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(&self.num_mines.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.start_ms.to_le_bytes());
+        bytes.extend_from_slice(&self.end_ms.to_le_bytes());
+        bytes.push(self.result as u8);
+        bytes.push(self.loser_id);
+        bytes.extend_from_slice(&self.duration_ms.to_le_bytes());
+
+        assert!(self.players.len() <= u8::MAX as usize);
+        bytes.push(self.players.len() as u8);
+        for player in &self.players {
+            bytes.push(player.id);
+            bytes.extend_from_slice(&player.color);
+            bytes.extend_from_slice(&player.squares_revealed.to_le_bytes());
+            bytes.extend_from_slice(&player.flags.to_le_bytes());
+            let name = &player.name.as_bytes()[..player.name.len().min(u8::MAX as usize)];
+            bytes.push(name.len() as u8);
+            bytes.extend_from_slice(name);
+        }
+
+        let length = (bytes.len() - 4) as u32;
+        bytes[..4].copy_from_slice(&length.to_le_bytes());
+        bytes
+    }
+
+    // Decodes a record without its length prefix. Returns None if it is malformed or of an unknown
+    // version.
+    fn decode(bytes: &[u8]) -> Option<GameRecord> {
+        let mut reader = Reader::new(bytes);
+        let version = reader.u16()?;
+        if version == 0 || version > HISTORY_VERSION {
+            return None;
+        }
+        let width = reader.u16()?;
+        let height = reader.u16()?;
+        let num_mines = reader.u32()?;
+        let seed = reader.u64()?;
+        let start_ms = reader.u64()?;
+        let end_ms = reader.u64()?;
+        let result = match reader.u8()? {
+            1 => GameResult::Lost,
+            2 => GameResult::Won,
+            _ => return None,
+        };
+        let loser_id = reader.u8()?;
+        let duration_ms = if version >= 2 {
+            reader.u64()?
+        } else {
+            // Version 1 had no game clock, so count from when the board was made.
+            end_ms.saturating_sub(start_ms)
+        };
+        let num_players = reader.u8()?;
+        let mut players = Vec::with_capacity(num_players as usize);
+        for _ in 0..num_players {
+            let id = reader.u8()?;
+            let color = reader.take(4)?.try_into().unwrap();
+            let squares_revealed = reader.u32()?;
+            let flags = reader.u32()?;
+            let name_len = reader.u8()?;
+            let name = String::from_utf8_lossy(reader.take(name_len as usize)?).into_owned();
+            players.push(PlayerRecord { id, color, name, squares_revealed, flags });
+        }
+        Some(GameRecord { width, height, num_mines, seed, start_ms, end_ms, duration_ms, result, loser_id, players })
+    }
+}
+
+// Reads little endian values from the front of a byte slice.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    pub fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+// Every finished game, oldest first.
+pub struct GameHistory {
+    records: Vec<GameRecord>,
+}
+
+impl GameHistory {
+    pub fn new() -> GameHistory {
+        GameHistory { records: vec![] }
+    }
+
+    // Reads the history file at the given path, if there is one. A record cut short by the server
+    // stopping mid-write is dropped from the file so that new records can be appended after the
+    // last whole one.
+    pub fn load(path: &std::path::Path) -> std::io::Result<GameHistory> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(GameHistory::new()),
+            Err(err) => return Err(err),
+        };
+        let mut records = vec![];
+        let mut offset = 0;
+        while offset + 4 <= bytes.len() {
+            let length = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            let Some(record_bytes) = bytes.get(offset + 4..offset + 4 + length) else {
+                break;
+            };
+            match GameRecord::decode(record_bytes) {
+                Some(record) => records.push(record),
+                None => println!("Skipping unreadable game record at byte {offset} of {}.", path.display()),
+            }
+            offset += 4 + length;
+        }
+        if offset < bytes.len() {
+            println!("Dropping incomplete game record at byte {offset} of {}.", path.display());
+            std::fs::OpenOptions::new().write(true).open(path)?.set_len(offset as u64)?;
+        }
+        Ok(GameHistory { records })
+    }
+
+    pub fn push(&mut self, record: GameRecord) {
+        self.records.push(record);
+    }
+
+    pub fn get(&self, game_id: u64) -> Option<&GameRecord> {
+        self.records.iter().find(|record| record.game_id() == game_id)
+    }
+
+    // Up to max_games of the games before the given one, newest first. A game ID of 0 starts from
+    // the newest game.
+    pub fn recent(&self, before_game_id: u64, max_games: usize) -> Vec<&GameRecord> {
+        self.records.iter()
+            .rev()
+            .filter(|record| before_game_id == 0 || record.game_id() < before_game_id)
+            .take(max_games)
+            .collect()
+    }
+}
+
+/*
+ * Game History File Format:
+ *   Every finished game, appended to one file as it ends. Little Endian.
+ *   The file is a series of records, each of which is:
+ *   u32: length of the rest of the record.
+ *   u16: record version, currently 2. Readers skip records of versions they don't know.
+ *   u16: board width.
+ *   u16: board height.
+ *   u32: number of mines.
+ *   u64: seed the board was generated from.
+ *   u64: time the board was created, in milliseconds since the Unix epoch. Also the game's ID.
+ *   u64: time the board was lost or won, in milliseconds since the Unix epoch.
+ *   u8: result.
+ *     1: lost.
+ *     2: won.
+ *   u8: ID of the player who hit a mine, or 0 if the game was won.
+ *   u64: time from the first reveal until the board was lost or won, in milliseconds. Not in
+ *     version 1 records.
+ *   u8: number of players, followed by that many player records for the players who acted:
+ *     u8: player ID.
+ *     u8: R
+ *     u8: G
+ *     u8: B
+ *     u8: A
+ *     u32: number of revealed squares attributed to the player when the game ended.
+ *     u32: number of flags the player had placed when the game ended.
+ *     u8: length of name.
+ *     (length of name) bytes: player name in UTF-8.
+ *   Player IDs are only meaningful within the game. The same person may have a different ID in
+ *   another game.
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(start_ms: u64) -> GameRecord {
+        GameRecord {
+            width: 30,
+            height: 16,
+            num_mines: 99,
+            seed: 7,
+            start_ms,
+            end_ms: start_ms + 5_000,
+            duration_ms: 4_000,
+            result: GameResult::Lost,
+            loser_id: 2,
+            players: vec![
+                PlayerRecord { id: 1, color: [1, 2, 3, 255], name: "One".to_string(), squares_revealed: 40, flags: 3 },
+                PlayerRecord { id: 2, color: [4, 5, 6, 255], name: "Two".to_string(), squares_revealed: 0, flags: 0 },
+            ],
+        }
+    }
+
+    // A file in the temp directory which is removed again when the test ends.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn reloads_appended_records_and_drops_one_cut_short() {
+        let file = TempFile::new("history-reload");
+        let mut bytes = [record(1).encode(), record(2).encode()].concat();
+        let whole_len = bytes.len();
+        bytes.extend_from_slice(&record(3).encode()[..10]);
+        std::fs::write(&file.0, &bytes).unwrap();
+
+        let history = GameHistory::load(&file.0).unwrap();
+        assert_eq!(history.get(1), Some(&record(1)));
+        assert_eq!(history.get(2), Some(&record(2)));
+        assert_eq!(history.get(3), None);
+        assert_eq!(std::fs::metadata(&file.0).unwrap().len(), whole_len as u64);
+    }
+
+    #[test]
+    fn skips_records_of_unknown_versions() {
+        let file = TempFile::new("history-versions");
+        let mut future = record(1).encode();
+        future[4..6].copy_from_slice(&(HISTORY_VERSION + 1).to_le_bytes());
+        std::fs::write(&file.0, [future, record(2).encode()].concat()).unwrap();
+
+        let history = GameHistory::load(&file.0).unwrap();
+        assert_eq!(history.recent(0, 10), vec![&record(2)]);
+    }
+
+    #[test]
+    fn pages_through_games_newest_first() {
+        let mut history = GameHistory::new();
+        for start_ms in 1..=5 {
+            history.push(record(start_ms));
+        }
+        let ids = |records: Vec<&GameRecord>| records.iter().map(|record| record.game_id()).collect::<Vec<_>>();
+        assert_eq!(ids(history.recent(0, 2)), vec![5, 4]);
+        assert_eq!(ids(history.recent(4, 2)), vec![3, 2]);
+        assert_eq!(ids(history.recent(2, 2)), vec![1]);
+    }
+
+    #[test]
+    fn times_version_1_records_from_when_the_board_was_made() {
+        let file = TempFile::new("history-version-1");
+        let mut bytes = record(1).encode();
+        bytes.drain(40..48); // Duration, which version 1 did not have.
+        let length = bytes.len() as u32 - 4;
+        bytes[..4].copy_from_slice(&length.to_le_bytes());
+        bytes[4..6].copy_from_slice(&1u16.to_le_bytes());
+        std::fs::write(&file.0, bytes).unwrap();
+
+        let history = GameHistory::load(&file.0).unwrap();
+        assert_eq!(history.get(1), Some(&GameRecord { duration_ms: 5_000, ..record(1) }));
+    }
+}
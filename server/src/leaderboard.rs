@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::game_log::GameResult;
+use crate::history::{GameRecord, Reader};
+
+// Version of the file format described at the bottom of this file.
+pub const LEADERBOARD_VERSION: u16 = 1;
+const MAGIC: &[u8; 4] = b"MLDB";
+// Most players listed in one Leaderboard packet.
+pub const MAX_LEADERBOARD_ROWS: usize = 20;
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Lifetime = 0,
+    Weekly = 1, // Since the start of Monday (UTC).
+}
+
+// What players are ranked by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    GamesPlayed = 0,
+    Wins = 1,
+    SquaresCleared = 2,
+    FastestWin = 3, // On one board config.
+}
+
+// Board width, height and number of mines.
+pub type BoardConfig = (u16, u16, u32);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Totals {
+    games_played: u32,
+    wins: u32,
+    squares_cleared: u64,
+    fastest_wins: BTreeMap<BoardConfig, u64>, // Time on the game clock of the fastest win on each config.
+}
+
+impl Totals {
+    fn add(&mut self, game: &GameRecord, squares_cleared: u32) {
+        self.games_played += 1;
+        self.squares_cleared += squares_cleared as u64;
+        if game.result == GameResult::Won {
+            self.wins += 1;
+            let fastest = self.fastest_wins.entry((game.width, game.height, game.num_mines)).or_insert(u64::MAX);
+            *fastest = (*fastest).min(game.duration_ms);
+        }
+    }
+
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.games_played.to_le_bytes());
+        bytes.extend_from_slice(&self.wins.to_le_bytes());
+        bytes.extend_from_slice(&self.squares_cleared.to_le_bytes());
+        bytes.extend_from_slice(&(self.fastest_wins.len() as u32).to_le_bytes());
+        for (&(width, height, num_mines), &duration_ms) in &self.fastest_wins {
+            bytes.extend_from_slice(&width.to_le_bytes());
+            bytes.extend_from_slice(&height.to_le_bytes());
+            bytes.extend_from_slice(&num_mines.to_le_bytes());
+            bytes.extend_from_slice(&duration_ms.to_le_bytes());
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Option<Totals> {
+        let games_played = reader.u32()?;
+        let wins = reader.u32()?;
+        let squares_cleared = reader.u64()?;
+        let num_fastest_wins = reader.u32()?;
+        let mut fastest_wins = BTreeMap::new();
+        for _ in 0..num_fastest_wins {
+            let config = (reader.u16()?, reader.u16()?, reader.u32()?);
+            fastest_wins.insert(config, reader.u64()?);
+        }
+        Some(Totals { games_played, wins, squares_cleared, fastest_wins })
+    }
+}
+
+struct PlayerEntry {
+    name: String, // Name the player had in their latest game.
+    lifetime: Totals,
+    week: u64, // Week the weekly totals are for.
+    weekly: Totals,
+}
+
+// What a player did in a finished game, as far as the leaderboard cares.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerResult {
+    pub identity: String, // Stays the same across games and restarts, unlike player IDs.
+    pub name: String,
+    pub squares_cleared: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaderboardRow {
+    pub name: String,
+    pub games_played: u32,
+    pub wins: u32,
+    pub squares_cleared: u64,
+    pub fastest_win_ms: Option<u64>, // On the board config which was asked about.
+}
+
+// Totals for every player who has finished a game, by identity.
+pub struct Leaderboard {
+    players: HashMap<String, PlayerEntry>,
+}
+
+impl Leaderboard {
+    pub fn new() -> Leaderboard {
+        Leaderboard { players: HashMap::new() }
+    }
+
+    // Reads the leaderboard file at the given path, if there is one.
+    pub fn load(path: &std::path::Path) -> std::io::Result<Leaderboard> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Leaderboard::new()),
+            Err(err) => return Err(err),
+        };
+        Self::decode(&bytes).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is not a leaderboard file", path.display()))
+        })
+    }
+
+    pub fn record_game(&mut self, game: &GameRecord, results: &[PlayerResult]) {
+        let week = week_of(game.end_ms);
+        for result in results {
+            let entry = self.players.entry(result.identity.clone()).or_insert_with(|| PlayerEntry {
+                name: result.name.clone(),
+                lifetime: Totals::default(),
+                week,
+                weekly: Totals::default(),
+            });
+            entry.name = result.name.clone();
+            if entry.week != week {
+                entry.week = week;
+                entry.weekly = Totals::default();
+            }
+            entry.lifetime.add(game, result.squares_cleared);
+            entry.weekly.add(game, result.squares_cleared);
+        }
+    }
+
+    // The best players by the given metric, best first. Fastest wins are on the given config, and
+    // players without a win on it are left out.
+    pub fn rows(&self, period: Period, metric: Metric, config: BoardConfig, now_ms: u64) -> Vec<LeaderboardRow> {
+        let week = week_of(now_ms);
+        let mut rows: Vec<LeaderboardRow> = self.players.values()
+            .filter_map(|entry| match period {
+                Period::Lifetime => Some((entry, &entry.lifetime)),
+                Period::Weekly => (entry.week == week).then_some((entry, &entry.weekly)),
+            })
+            .map(|(entry, totals)| LeaderboardRow {
+                name: entry.name.clone(),
+                games_played: totals.games_played,
+                wins: totals.wins,
+                squares_cleared: totals.squares_cleared,
+                fastest_win_ms: totals.fastest_wins.get(&config).copied(),
+            })
+            .filter(|row| metric != Metric::FastestWin || row.fastest_win_ms.is_some())
+            .collect();
+        rows.sort_by(|a, b| {
+            let order = match metric {
+                Metric::GamesPlayed => b.games_played.cmp(&a.games_played),
+                Metric::Wins => b.wins.cmp(&a.wins),
+                Metric::SquaresCleared => b.squares_cleared.cmp(&a.squares_cleared),
+                Metric::FastestWin => a.fastest_win_ms.cmp(&b.fastest_win_ms),
+            };
+            order.then_with(|| a.name.cmp(&b.name))
+        });
+        rows.truncate(MAX_LEADERBOARD_ROWS);
+        rows
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&LEADERBOARD_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.players.len() as u32).to_le_bytes());
+        for (identity, entry) in &self.players {
+            for string in [identity, &entry.name] {
+                let string = &string.as_bytes()[..string.len().min(u8::MAX as usize)];
+                bytes.push(string.len() as u8);
+                bytes.extend_from_slice(string);
+            }
+            entry.lifetime.encode(&mut bytes);
+            bytes.extend_from_slice(&entry.week.to_le_bytes());
+            entry.weekly.encode(&mut bytes);
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Leaderboard> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(4)? != MAGIC || reader.u16()? != LEADERBOARD_VERSION {
+            return None;
+        }
+        let num_players = reader.u32()?;
+        let mut players = HashMap::new();
+        for _ in 0..num_players {
+            let identity_len = reader.u8()?;
+            let identity = String::from_utf8_lossy(reader.take(identity_len as usize)?).into_owned();
+            let name_len = reader.u8()?;
+            let name = String::from_utf8_lossy(reader.take(name_len as usize)?).into_owned();
+            let lifetime = Totals::decode(&mut reader)?;
+            let week = reader.u64()?;
+            let weekly = Totals::decode(&mut reader)?;
+            players.insert(identity, PlayerEntry { name, lifetime, week, weekly });
+        }
+        Some(Leaderboard { players })
+    }
+}
+
+// Weeks since the Unix epoch, starting on Mondays. The epoch was a Thursday.
+fn week_of(time_ms: u64) -> u64 {
+    (time_ms / MS_PER_DAY + 3) / 7
+}
+
+/*
+ * Leaderboard File Format:
+ *   The whole leaderboard, rewritten after every game. Little Endian.
+ *   4 bytes: "MLDB".
+ *   u16: format version, currently 1.
+ *   u32: number of players, followed by that many player records:
+ *     u8: length of identity.
+ *     (length of identity) bytes: identity in UTF-8. "token:" followed by the token the player
+ *       connected with, or their IP address if they connected without one.
+ *     u8: length of name.
+ *     (length of name) bytes: name the player had in their latest game, in UTF-8.
+ *     totals: over every game the player has finished.
+ *     u64: week of the weekly totals, counted in weeks starting on Monday since the Unix epoch.
+ *     totals: over the games the player finished that week.
+ *   Totals are:
+ *     u32: games played.
+ *     u32: games won.
+ *     u64: squares cleared, including those revealed by cascades and chords.
+ *     u32: number of board configs won on, followed by that many records:
+ *       u16: board width.
+ *       u16: board height.
+ *       u32: number of mines.
+ *       u64: fastest win on the config, in milliseconds on the game clock.
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONDAY_MS: u64 = 4 * MS_PER_DAY; // The first Monday after the Unix epoch.
+
+    fn game(result: GameResult, end_ms: u64, duration_ms: u64) -> GameRecord {
+        GameRecord {
+            width: 9,
+            height: 9,
+            num_mines: 10,
+            seed: 0,
+            start_ms: end_ms - duration_ms,
+            end_ms,
+            duration_ms,
+            result,
+            loser_id: 0,
+            players: vec![],
+        }
+    }
+
+    fn result(identity: &str, squares_cleared: u32) -> PlayerResult {
+        PlayerResult { identity: identity.to_string(), name: identity.to_uppercase(), squares_cleared }
+    }
+
+    fn names(rows: &[LeaderboardRow]) -> Vec<&str> {
+        rows.iter().map(|row| row.name.as_str()).collect()
+    }
+
+    #[test]
+    fn ranks_players_by_the_chosen_metric() {
+        let mut leaderboard = Leaderboard::new();
+        leaderboard.record_game(&game(GameResult::Won, MONDAY_MS, 30_000), &[result("a", 10), result("b", 50)]);
+        leaderboard.record_game(&game(GameResult::Won, MONDAY_MS, 20_000), &[result("a", 10)]);
+        leaderboard.record_game(&game(GameResult::Lost, MONDAY_MS, 1_000), &[result("c", 1)]);
+
+        let rows = |metric| leaderboard.rows(Period::Lifetime, metric, (9, 9, 10), MONDAY_MS);
+        assert_eq!(names(&rows(Metric::GamesPlayed)), vec!["A", "B", "C"]);
+        assert_eq!(names(&rows(Metric::SquaresCleared)), vec!["B", "A", "C"]);
+        let fastest = rows(Metric::FastestWin);
+        assert_eq!(names(&fastest), vec!["A", "B"]);
+        assert_eq!(fastest[0].fastest_win_ms, Some(20_000));
+        assert!(leaderboard.rows(Period::Lifetime, Metric::FastestWin, (30, 16, 99), MONDAY_MS).is_empty());
+    }
+
+    #[test]
+    fn starts_weekly_totals_afresh_each_monday() {
+        let mut leaderboard = Leaderboard::new();
+        leaderboard.record_game(&game(GameResult::Won, MONDAY_MS - 1, 1_000), &[result("a", 10), result("b", 10)]);
+        leaderboard.record_game(&game(GameResult::Lost, MONDAY_MS, 1_000), &[result("a", 5)]);
+
+        let weekly = leaderboard.rows(Period::Weekly, Metric::GamesPlayed, (9, 9, 10), MONDAY_MS + 1);
+        assert_eq!(names(&weekly), vec!["A"]);
+        assert_eq!((weekly[0].games_played, weekly[0].wins, weekly[0].squares_cleared), (1, 0, 5));
+        let lifetime = leaderboard.rows(Period::Lifetime, Metric::GamesPlayed, (9, 9, 10), MONDAY_MS + 1);
+        assert_eq!((lifetime[0].games_played, lifetime[0].wins, lifetime[0].squares_cleared), (2, 1, 15));
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let mut leaderboard = Leaderboard::new();
+        leaderboard.record_game(&game(GameResult::Won, MONDAY_MS, 30_000), &[result("a", 10), result("b", 50)]);
+        let decoded = Leaderboard::decode(&leaderboard.encode()).unwrap();
+        for metric in [Metric::GamesPlayed, Metric::FastestWin] {
+            assert_eq!(
+                decoded.rows(Period::Weekly, metric, (9, 9, 10), MONDAY_MS),
+                leaderboard.rows(Period::Weekly, metric, (9, 9, 10), MONDAY_MS)
+            );
+        }
+        assert!(Leaderboard::decode(b"MLDB\x02\x00\x00\x00\x00\x00").is_none());
+    }
+}
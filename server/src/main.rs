@@ -1,46 +1,120 @@
 use futures_util::{SinkExt, StreamExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use tokio_tungstenite::tungstenite::{handshake::server::Request, Message};
 use clap::Parser;
 use std::sync::{Arc, Mutex};
-use http;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashMap;
 
 mod board;
+mod daily;
+use daily::{DailyResults, DailySeeds};
 mod names;
 mod encoding;
-use encoding::{ClientToServerPacket, ServerToClientPacket, encode_entry};
+use encoding::{ClientToServerPacket, ServerToClientPacket, PacketError, ErrorResponse, Protocol};
+use encoding::{MAX_GAME_HISTORY_PAGE, PROTOCOL_VERSION};
+mod fog;
+mod game;
+use game::{Clock, Event, GameState, Outgoing};
+mod game_log;
+mod history;
+use history::GameHistory;
+mod leaderboard;
+use leaderboard::Leaderboard;
+mod mode;
+mod race;
+mod rotation;
+mod teams;
+mod replay;
+use replay::{Replay, ReplayLibrary};
 mod player_list;
-use player_list::PlayerList;
-
-const MIN_DELAY_TILL_NEW_BOARD: std::time::Duration = std::time::Duration::from_millis(500);
-
-#[derive(Debug)]
-enum BoardEvent {
-    RevealSquare((u16, u16)),
-    ChordSquare((u16, u16)),
-    ToggleFlagSquare((u16, u16)),
-    NewConnection(mpsc::UnboundedSender<ServerToClientPacket>),
-    NewBoard,
-    NextBoardConfig { width: u16, height: u16, num_mines: u32 },
+// Length limits of the token a client may connect with.
+const MIN_TOKEN_LEN: usize = 8;
+const MAX_TOKEN_LEN: usize = 64;
+
+type ClientSender = mpsc::UnboundedSender<ServerToClientPacket>;
+
+// Finished games, shared between the board and the connections which ask about them.
+#[derive(Clone)]
+struct Archive {
+    replays: Arc<Mutex<ReplayLibrary>>,
+    history: Arc<Mutex<GameHistory>>,
+    leaderboard: Arc<Mutex<Leaderboard>>,
+    clock: Arc<dyn Clock + Sync>, // The game's clock, which weekly leaderboards are counted by.
+}
+
+// Where finished games are written to. Nothing is written for paths which are unset.
+struct ArchiveFiles {
+    game_log_dir: Option<String>,
+    history_file: Option<String>,
+    leaderboard_file: Option<String>,
+    daily_file: Option<String>,
 }
 
 fn unwrap_header_value(header_value: Arc<Mutex<Option<http::header::HeaderValue>>>) -> Option<String> {
     Some(header_value.lock().ok()?.clone()?.to_str().ok()?.to_string())
 }
 
+type WsSender = futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, Message>;
+
+// Reacts to an error caused by the client according to its severity. Returns false if the
+// connection should be closed.
+async fn report_error(ws_sender: &mut WsSender, error: PacketError, protocol: Protocol) -> bool {
+    println!("Client error: {error}");
+    let response = error.response();
+    if response == ErrorResponse::Ignore {
+        return true;
+    }
+    if let Some(bytes) = error.to_packet().encode(protocol) {
+        if ws_sender.send(Message::Binary(bytes)).await.is_err() {
+            return false;
+        }
+    }
+    if response == ErrorResponse::Disconnect {
+        let _ = ws_sender.close().await;
+        return false;
+    }
+    true
+}
+
+// Sends the packets the client understands. Returns false if the connection is broken.
+async fn send_packets(ws_sender: &mut WsSender, packets: Vec<ServerToClientPacket>, protocol: Protocol) -> bool {
+    for packet in packets {
+        if let Some(bytes) = packet.encode(protocol) {
+            if ws_sender.send(Message::Binary(bytes)).await.is_err() {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn schedule_replay(timer: std::pin::Pin<&mut tokio::time::Sleep>, replay: &Replay) {
+    if let Some(delay) = replay.delay_until_next() {
+        timer.reset(tokio::time::Instant::now() + delay);
+    }
+}
+
+#[allow(clippy::result_large_err)] // The handshake callback's error type is fixed by tungstenite.
 async fn accept_connection(
     stream: TcpStream,
-    event_tx: mpsc::UnboundedSender<(BoardEvent, std::net::SocketAddr)>,
+    event_tx: mpsc::UnboundedSender<(Event, ClientSender)>,
+    archive: Archive,
 ) {
     // let addr: Arc<str> = stream.peer_addr().expect("should have address").to_string().as_str().into();
     let addr_guard = Arc::new(Mutex::new(None));
     let port_guard = Arc::new(Mutex::new(None));
+    let query_guard = Arc::new(Mutex::new(None));
     let ws_stream = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response| {
         let mut addr = addr_guard.lock().unwrap();
-        *addr = request.headers().get("X-Real-IP").map(|e| e.clone());
+        *addr = request.headers().get("X-Real-IP").cloned();
         let mut port = port_guard.lock().unwrap();
-        *port = request.headers().get("X-Real-Port").map(|e| e.clone());
+        *port = request.headers().get("X-Real-Port").cloned();
+        *query_guard.lock().unwrap() = request.uri().query().map(String::from);
         Ok(response)
     }).await.expect("Failed to accept.");
 
@@ -61,16 +135,48 @@ async fn accept_connection(
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
     let (board_to_client_tx, mut board_to_client_rx) = mpsc::unbounded_channel();
 
-    event_tx.send((BoardEvent::NewConnection(board_to_client_tx), socket_addr)).expect("sent");
+    let query = query_guard.lock().unwrap().take();
+    let player = match query.as_deref().and_then(client_token) {
+        Some(token) => format!("token:{token}"),
+        None => addr_to_key(socket_addr),
+    };
+    event_tx.send((Event::Connect { player: player.clone() }, board_to_client_tx.clone())).expect("sent");
+
+    // Until the client says Hello or sends anything else, we don't know which protocol it speaks.
+    // Packets from the board are sent in the legacy protocol meanwhile, and those it can't carry
+    // are withheld until a Hello tells us whether the client understands them.
+    let mut protocol: Option<Protocol> = None;
+    let mut withheld: Vec<ServerToClientPacket> = vec![];
+
+    // Set while the client is watching a replay instead of the live game.
+    let mut replay: Option<Replay> = None;
+    let replay_timer = tokio::time::sleep(std::time::Duration::ZERO);
+    tokio::pin!(replay_timer);
 
     loop {
         tokio::select! {
+            _ = &mut replay_timer, if replay.as_ref().is_some_and(|replay| replay.delay_until_next().is_some()) => {
+                let playing = replay.as_mut().unwrap();
+                if !send_packets(&mut ws_sender, playing.step(), protocol.unwrap()).await {
+                    break;
+                }
+                schedule_replay(replay_timer.as_mut(), playing);
+            }
             packet = board_to_client_rx.recv() => {
                 let Some(packet) = packet else {
                     // println!("Packet to send to client is none.");
                     break;
                 };
-                if ws_sender.send(Message::Binary(packet.encode())).await.is_err() {
+                if replay.is_some() {
+                    continue; // Watching a replay. The live game is sent afresh once it stops.
+                }
+                let Some(bytes) = packet.encode(protocol.unwrap_or(Protocol::LEGACY)) else {
+                    if protocol.is_none() {
+                        withheld.push(packet);
+                    }
+                    continue; // Client does not understand this packet.
+                };
+                if ws_sender.send(Message::Binary(bytes)).await.is_err() {
                     // println!("Failed to send packet by websocket.");
                     break;
                 }
@@ -86,31 +192,193 @@ async fn accept_connection(
                 };
                 match message {
                     Message::Binary(bytes) => {
-                        let Ok(packet) = ClientToServerPacket::decode(&bytes) else {
-                            // println!("Could not decode bytes receive from client.");
-                            break;
+                        let packet = match ClientToServerPacket::decode(&bytes) {
+                            Ok(packet) => packet,
+                            Err(error) => {
+                                if protocol.is_none() {
+                                    withheld.clear();
+                                }
+                                let protocol = *protocol.get_or_insert(Protocol::LEGACY);
+                                if report_error(&mut ws_sender, error, protocol).await {
+                                    continue;
+                                } else {
+                                    break;
+                                }
+                            }
                         };
+                        if let ClientToServerPacket::Hello { version, capabilities } = packet {
+                            if let Some(protocol) = protocol {
+                                if report_error(&mut ws_sender, PacketError::UnexpectedHello, protocol).await {
+                                    continue;
+                                } else {
+                                    break;
+                                }
+                            }
+                            let Some(negotiated) = Protocol::negotiate(version, capabilities) else {
+                                // The Error packet is laid out the same in every version.
+                                let protocol = Protocol { version: PROTOCOL_VERSION, capabilities: 0 };
+                                report_error(&mut ws_sender, PacketError::UnsupportedVersion(version), protocol).await;
+                                break;
+                            };
+                            println!("Client speaks protocol version {} with capabilities {:#x}.", negotiated.version, negotiated.capabilities);
+                            let welcome = ServerToClientPacket::Welcome {
+                                version: negotiated.version,
+                                capabilities: negotiated.capabilities,
+                            };
+                            if ws_sender.send(Message::Binary(welcome.encode(negotiated).unwrap())).await.is_err() {
+                                break;
+                            }
+                            protocol = Some(negotiated);
+                            if !send_packets(&mut ws_sender, std::mem::take(&mut withheld), negotiated).await {
+                                break;
+                            }
+                            continue;
+                        }
+                        // A client which acts before saying Hello is never going to say it.
+                        if protocol.is_none() {
+                            withheld.clear();
+                        }
+                        let protocol = *protocol.get_or_insert(Protocol::LEGACY);
+                        let replay_error = match packet {
+                            ClientToServerPacket::StartReplay { .. } => None,
+                            ClientToServerPacket::ReplaySpeed { .. }
+                            | ClientToServerPacket::StepReplay
+                            | ClientToServerPacket::SeekReplay { .. }
+                            | ClientToServerPacket::StopReplay => replay.is_none().then_some(PacketError::NotReplaying),
+                            _ => None,
+                        };
+                        if let Some(error) = replay_error {
+                            if report_error(&mut ws_sender, error, protocol).await {
+                                continue;
+                            } else {
+                                break;
+                            }
+                        }
                         match packet {
                             ClientToServerPacket::RevealSquare(coords) => {
                                 println!("Reveal Square packet.");
-                                event_tx.send((BoardEvent::RevealSquare(coords), socket_addr)).expect("sent");
+                                event_tx.send((Event::RevealSquare { player: player.clone(), coords }, board_to_client_tx.clone())).expect("sent");
                             }
                             ClientToServerPacket::ChordSquare(coords) => {
                                 println!("Chord Square packet.");
-                                event_tx.send((BoardEvent::ChordSquare(coords), socket_addr)).expect("sent");
+                                event_tx.send((Event::ChordSquare { player: player.clone(), coords }, board_to_client_tx.clone())).expect("sent");
                             }
                             ClientToServerPacket::ToggleFlagSquare(coords) => {
                                 println!("Toggle flag packet.");
-                                event_tx.send((BoardEvent::ToggleFlagSquare(coords), socket_addr)).expect("sent");
+                                event_tx.send((Event::ToggleFlagSquare { player: player.clone(), coords }, board_to_client_tx.clone())).expect("sent");
+                            }
+                            ClientToServerPacket::CycleMark { coords, private } => {
+                                println!("Cycle mark packet.");
+                                event_tx.send((Event::CycleMark { player: player.clone(), coords, private }, board_to_client_tx.clone())).expect("sent");
                             }
                             ClientToServerPacket::NewBoard => {
                                 println!("New board requested.");
-                                event_tx.send((BoardEvent::NewBoard, socket_addr)).expect("sent");
+                                event_tx.send((Event::NewBoard, board_to_client_tx.clone())).expect("sent");
                             }
                             ClientToServerPacket::NextBoardConfig { width, height, num_mines } => {
                                 println!("New board config requested.");
-                                event_tx.send((BoardEvent::NextBoardConfig { width, height, num_mines }, socket_addr)).expect("sent");
+                                event_tx.send((Event::NextBoardConfig { width, height, num_mines }, board_to_client_tx.clone())).expect("sent");
+                            }
+                            ClientToServerPacket::NextGameMode(mode) => {
+                                println!("New game mode requested.");
+                                event_tx.send((Event::NextGameMode(mode), board_to_client_tx.clone())).expect("sent");
+                            }
+                            ClientToServerPacket::NextFlagRules(rules) => {
+                                println!("New flag rules requested.");
+                                event_tx.send((Event::NextFlagRules(rules), board_to_client_tx.clone())).expect("sent");
+                            }
+                            ClientToServerPacket::ChooseTeam { team } => {
+                                println!("Team {team} chosen.");
+                                event_tx.send((Event::ChooseTeam { player: player.clone(), team }, board_to_client_tx.clone())).expect("sent");
+                            }
+                            ClientToServerPacket::StartDaily { preset } => {
+                                println!("Daily challenge requested.");
+                                event_tx.send((Event::StartDaily { player: player.clone(), preset }, board_to_client_tx.clone())).expect("sent");
+                            }
+                            ClientToServerPacket::LeaveDaily => {
+                                println!("Leaving daily challenge.");
+                                event_tx.send((Event::LeaveDaily { player: player.clone() }, board_to_client_tx.clone())).expect("sent");
+                            }
+                            ClientToServerPacket::RequestDailyRanking { preset } => {
+                                println!("Daily ranking requested.");
+                                event_tx.send((Event::RequestDailyRanking { preset }, board_to_client_tx.clone())).expect("sent");
                             }
+                            ClientToServerPacket::StartReplay { game_id } => {
+                                println!("Replay of game {game_id} requested.");
+                                let log = archive.replays.lock().unwrap().get(game_id).cloned();
+                                let Some(log) = log else {
+                                    if report_error(&mut ws_sender, PacketError::UnknownGame(game_id), protocol).await {
+                                        continue;
+                                    } else {
+                                        break;
+                                    }
+                                };
+                                let started = replay.insert(Replay::new(log));
+                                if !send_packets(&mut ws_sender, started.start_packets(), protocol).await {
+                                    break;
+                                }
+                                schedule_replay(replay_timer.as_mut(), started);
+                            }
+                            ClientToServerPacket::ReplaySpeed { speed_percent } => {
+                                let playing = replay.as_mut().unwrap();
+                                playing.set_speed(speed_percent);
+                                schedule_replay(replay_timer.as_mut(), playing);
+                            }
+                            ClientToServerPacket::StepReplay => {
+                                let playing = replay.as_mut().unwrap();
+                                playing.set_speed(0);
+                                if !send_packets(&mut ws_sender, playing.step(), protocol).await {
+                                    break;
+                                }
+                            }
+                            ClientToServerPacket::SeekReplay { num_actions } => {
+                                let playing = replay.as_mut().unwrap();
+                                if !send_packets(&mut ws_sender, playing.seek(num_actions as usize), protocol).await {
+                                    break;
+                                }
+                                schedule_replay(replay_timer.as_mut(), playing);
+                            }
+                            ClientToServerPacket::StopReplay => {
+                                println!("Replay stopped.");
+                                replay = None;
+                                // Reconnecting resends the live game and does not duplicate this connection.
+                                event_tx.send((Event::Connect { player: player.clone() }, board_to_client_tx.clone())).expect("sent");
+                            }
+                            ClientToServerPacket::RequestGameHistory { before_game_id, max_games } => {
+                                println!("Game history requested.");
+                                let games = archive.history.lock().unwrap()
+                                    .recent(before_game_id, (max_games as usize).min(MAX_GAME_HISTORY_PAGE))
+                                    .into_iter()
+                                    .cloned()
+                                    .collect();
+                                if !send_packets(&mut ws_sender, vec![ServerToClientPacket::GameHistory { games }], protocol).await {
+                                    break;
+                                }
+                            }
+                            ClientToServerPacket::RequestGame { game_id } => {
+                                println!("Record of game {game_id} requested.");
+                                let game = archive.history.lock().unwrap().get(game_id).cloned();
+                                let Some(game) = game else {
+                                    if report_error(&mut ws_sender, PacketError::UnknownGameRecord(game_id), protocol).await {
+                                        continue;
+                                    } else {
+                                        break;
+                                    }
+                                };
+                                if !send_packets(&mut ws_sender, vec![ServerToClientPacket::GameDetails { game }], protocol).await {
+                                    break;
+                                }
+                            }
+                            ClientToServerPacket::RequestLeaderboard { period, metric, config } => {
+                                println!("Leaderboard requested.");
+                                let now_ms = archive.clock.now_ms();
+                                let rows = archive.leaderboard.lock().unwrap().rows(period, metric, config, now_ms);
+                                let packet = ServerToClientPacket::Leaderboard { period, metric, config, rows };
+                                if !send_packets(&mut ws_sender, vec![packet], protocol).await {
+                                    break;
+                                }
+                            }
+                            ClientToServerPacket::Hello { .. } => unreachable!(),
                         }
                     }
                     Message::Close(..) => {
@@ -123,14 +391,19 @@ async fn accept_connection(
         }
     }
     println!("Ending connection.");
+    event_tx.send((Event::Disconnect { player }, board_to_client_tx)).expect("sent");
 }
 
 
 
-fn send_to_all(senderss: Vec<&mut Vec<mpsc::UnboundedSender<encoding::ServerToClientPacket>>>, packet: ServerToClientPacket) {
-    for ref mut senders in senderss {
-        senders.retain(|sender| sender.send(packet.clone()).is_ok());
+// Players away playing a daily challenge are left out.
+fn send_to_all(senders_by_player: &mut HashMap<String, Vec<ClientSender>>, game: &GameState, packet: ServerToClientPacket) {
+    for (player, senders) in senders_by_player.iter_mut() {
+        if !game.in_daily(player) {
+            senders.retain(|sender| sender.send(packet.clone()).is_ok());
+        }
     }
+    senders_by_player.retain(|_, senders| !senders.is_empty());
 }
 
 // Possibly many-to-one function.
@@ -139,212 +412,134 @@ fn addr_to_key(addr: std::net::SocketAddr) -> String {
     // addr.to_string()
 }
 
-#[derive(Debug)]
-struct BoardConfig {
-    width: u16,
-    height: u16,
-    num_mines: u32,
+// The token a client gave in its connection URL's query string, if it is a valid one. Clients with
+// a token are told apart by it rather than by their IP address.
+fn client_token(query: &str) -> Option<&str> {
+    let token = query.split('&').find_map(|param| param.strip_prefix("token="))?;
+    let valid = (MIN_TOKEN_LEN..=MAX_TOKEN_LEN).contains(&token.len())
+        && token.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+    if !valid {
+        println!("Ignoring invalid client token.");
+    }
+    valid.then_some(token)
+}
+
+async fn append_to_file(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(bytes).await
 }
 
-enum StateInfo {
-    Ongoing,
-    Lost {loser_id: u8, hit_mines: Vec<(u16, u16)>, wrong_flags: Vec<(u16, u16)>},
-    Won,
+// Writes the file so that it is never left half written.
+async fn replace_file(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let temp_path = format!("{path}.tmp");
+    tokio::fs::write(&temp_path, bytes).await?;
+    tokio::fs::rename(&temp_path, path).await
 }
 
+// Feeds events into the game and delivers the packets it produces.
 async fn board_handler(
-    mut event_rx: mpsc::UnboundedReceiver<(BoardEvent, std::net::SocketAddr)>,
-    nouns_path: String,
-    adjectives_path: String,
+    mut event_rx: mpsc::UnboundedReceiver<(Event, ClientSender)>,
+    mut game: GameState,
+    archive: Archive,
+    files: ArchiveFiles,
 ) {
-    let mut board_config = BoardConfig {
-        width: 20,
-        height: 20,
-        num_mines: 80,
-    };
-    assert!(board::MultiplayerBoard::possible_config(board_config.width, board_config.height, board_config.num_mines));
-    let mut board = board::MultiplayerBoard::new(
-        board_config.width,
-        board_config.height,
-        board_config.num_mines,
-    );
-
-    let mut done_instant: Option<std::time::Instant> = None;
-    let mut players = PlayerList::new(&nouns_path, &adjectives_path);
-    let mut state_info = StateInfo::Ongoing;
+    let mut senders_by_player: HashMap<String, Vec<ClientSender>> = HashMap::new();
 
-    while let Some((event, addr)) = event_rx.recv().await {
-        let addr_key = addr_to_key(addr);
-        match event {
-            BoardEvent::RevealSquare(coords) | BoardEvent::ChordSquare(coords) => {
-                if coords.0 >= board.height() || coords.1 >= board.width() {
-                    continue; // Ignore any input coords which are invalid.
-                }
-
-                let this_player_id = players.keep_addr_alive(&addr_key).unwrap();
-
-                let prev_state = *board.board_state();
-
-                let changed_coords = if let BoardEvent::RevealSquare(..) = event {
-                    board.reveal_square(coords, this_player_id)
-                } else if let BoardEvent::ChordSquare(..) = event {
-                    board.chord_square(coords, this_player_id)
-                } else {
-                    panic!("Shouldn't arrive here");
-                };
+    loop {
+        // Turns in turn-based mode can run out without anyone doing anything.
+        let turn_time_left_ms = game.turn_time_left_ms();
+        let turn_timer = tokio::time::sleep(std::time::Duration::from_millis(turn_time_left_ms.unwrap_or(0)));
+        let (event, reply_tx) = tokio::select! {
+            received = event_rx.recv() => match received {
+                Some((event, reply_tx)) => (event, Some(reply_tx)),
+                None => break,
+            },
+            _ = turn_timer, if turn_time_left_ms.is_some() => (Event::TurnTimedOut, None),
+        };
+
+        if let Event::Disconnect { ref player } = event {
+            // A player has only gone once their last connection has.
+            let senders = senders_by_player.entry(player.clone()).or_default();
+            senders.retain(|sender| !reply_tx.as_ref().is_some_and(|reply_tx| sender.same_channel(reply_tx)) && !sender.is_closed());
+            if !senders.is_empty() {
+                continue;
+            }
+            senders_by_player.remove(player);
+        }
 
-                if changed_coords.len() > 0 {
-                    // Only send an update if something actually changed.
-                    let updates: Vec<(u16, u16, u8, u8)> = changed_coords.iter().map(|coords| {
-                            let entry = board.revealed_entry_at(*coords);
-                            let player_id = board.player_at(*coords);
-                            (coords.0, coords.1, encode_entry(&entry), player_id)
-                        }).collect();
-                    send_to_all(players.all_senders(), ServerToClientPacket::SparseBoard { updates });
+        // A new connection only starts receiving broadcasts once it has been sent the current state.
+        let new_connection = match event {
+            Event::Connect { ref player } => Some(player.clone()),
+            _ => None,
+        };
+
+        for outgoing in game.apply(event) {
+            match outgoing {
+                Outgoing::ToAll(packet) => send_to_all(&mut senders_by_player, &game, packet),
+                Outgoing::Reply(packet) => {
+                    if let Some(ref reply_tx) = reply_tx {
+                        let _ = reply_tx.send(packet);
+                    }
                 }
-
-                if *board.board_state() == board::BoardState::Lost && prev_state != board::BoardState::Lost {
-                    // Just lost game.
-                    let wrong_flags = {
-                        let mut out = vec![];
-                        for i in 0..board.height() {
-                            for j in 0..board.width() {
-                                let coords = (i, j);
-                                if board.revealed_entry_at(coords).is_flagged() && !board.true_entry_at(coords).unwrap().is_mine() {
-                                    out.push(coords);
-                                }
-                            }
+                Outgoing::ToPlayer { player, packet } => {
+                    let senders = senders_by_player.get_mut(&player);
+                    // A new connection is only registered once it has been sent the current state.
+                    if let (Some(new_player), Some(reply_tx)) = (&new_connection, &reply_tx) {
+                        let registered = senders.as_ref().is_some_and(|senders| senders.iter().any(|sender| sender.same_channel(reply_tx)));
+                        if *new_player == player && !registered {
+                            let _ = reply_tx.send(packet.clone());
                         }
-                        out
-                    };
-                    state_info = StateInfo::Lost { loser_id: this_player_id, hit_mines: changed_coords.clone(), wrong_flags: wrong_flags.clone() };
-                    let packet = ServerToClientPacket::BoardLoss { loser_id: this_player_id, hit_mines: changed_coords, wrong_flags };
-                    send_to_all(players.all_senders(), packet);
-
-                    // Mark time lost.
-                    done_instant = Some(std::time::Instant::now());
-                } else if *board.board_state() == board::BoardState::Won && prev_state != board::BoardState::Won {
-                    // Just won game.
-                    state_info = StateInfo::Won;
-                    send_to_all(players.all_senders(), ServerToClientPacket::BoardWin);
-
-                    // Mark time won.
-                    done_instant = Some(std::time::Instant::now());
-                }
-            }
-            BoardEvent::ToggleFlagSquare(coords) => {
-                if coords.0 >= board.height() || coords.1 >= board.width() {
-                    continue; // Ignore any input coords which are invalid.
-                }
-                
-                let this_player_id = players.keep_addr_alive(&addr_key).unwrap();
-
-                if board.toggle_flag_square(coords, this_player_id) {
-                    let entry = board.revealed_entry_at(coords);
-                    let player_id = board.player_at(coords);
-                    let updates = vec![(coords.0, coords.1, encode_entry(&entry), player_id)];
-                    send_to_all(players.all_senders(), ServerToClientPacket::SparseBoard { updates });
-                }
-            }
-            BoardEvent::NewConnection(board_to_client_tx) => {
-                players.construct_player(&addr_key);
-                let this_player = players.get_player(&addr_key).unwrap();
-                let this_player_id = this_player.id();
-
-                // Send the new connection their player info.
-                let this_player_info = ServerToClientPacket::YourPlayerInfo {
-                    player_id: this_player_id,
-                    color: *this_player.color(),
-                    name: this_player.name().to_string(),
-                };
-                board_to_client_tx.send(this_player_info).expect("Sent");
-                // Send the new connection all the other players' infos.
-                for player in players.players() {
-                    let packet = ServerToClientPacket::PlayerInfo { 
-                        player_id: player.id(),
-                        color: *player.color(),
-                        name: player.name().to_string(),
-                    };
-                    board_to_client_tx.send(packet).expect("sent");
-                }   
-
-                // Send the new connection the full board.
-                let packet = ServerToClientPacket::FullBoard {
-                    width: board.width(),
-                    height: board.height(),
-                    num_mines: board.num_mines(),
-                    board_state: *board.board_state(),
-                    entries: board.revealed_board().clone(),
-                    player_ids: board.player_ids().clone(),
-                };
-                board_to_client_tx.send(packet).expect("sent");
-
-                // Send the new connection the loss/win info if applicable.
-                match &state_info {
-                    &StateInfo::Lost { loser_id, ref hit_mines, ref wrong_flags } => {
-                        let packet = ServerToClientPacket::BoardLoss { loser_id, hit_mines: hit_mines.clone(), wrong_flags: wrong_flags.clone() };
-                        board_to_client_tx.send(packet).expect("sent");
                     }
-                    &StateInfo::Won => {
-                        board_to_client_tx.send(ServerToClientPacket::BoardWin).expect("sent");
+                    if let Some(senders) = senders {
+                        senders.retain(|sender| sender.send(packet.clone()).is_ok());
                     }
-                    _ => {}
                 }
+                Outgoing::DailyResult(result) => {
+                    if let Some(ref path) = files.daily_file {
+                        if let Err(err) = append_to_file(path, &result.encode()).await {
+                            println!("Failed to append to daily results {path}: {err}");
+                        }
+                    }
+                }
+                Outgoing::GameFinished { log, record, results } => {
+                    if let Some(ref dir) = files.game_log_dir {
+                        let path = std::path::Path::new(dir).join(log.file_name());
+                        if let Err(err) = tokio::fs::write(&path, log.encode()).await {
+                            println!("Failed to write game log {}: {err}", path.display());
+                        }
+                    }
+                    archive.replays.lock().unwrap().push(log);
 
-                // Send the new connection the next board configuration.
-                board_to_client_tx.send(ServerToClientPacket::NextBoardConfig { 
-                    width: board_config.width, 
-                    height: board_config.height, 
-                    num_mines: board_config.num_mines
-                }).expect("sent");
-
-                // Send the new player's info to all the other players.
-                let player_info = ServerToClientPacket::PlayerInfo {
-                    player_id: this_player_id,
-                    color: *this_player.color(),
-                    name: this_player.name().to_string(),
-                };
-                send_to_all(players.all_senders(), player_info);
-
-                players.get_mut_senders(&addr_key).map(|ref mut senders| senders.push(board_to_client_tx));
-            }
-            BoardEvent::NewBoard => {
-                if *board.board_state() == board::BoardState::Lost || *board.board_state() == board::BoardState::Won { // Only reset board if the current one is finished.
-                    if done_instant.unwrap().elapsed() > MIN_DELAY_TILL_NEW_BOARD {
-                        // Reset board.
-                        board = board::MultiplayerBoard::new(
-                            board_config.width,
-                            board_config.height,
-                            board_config.num_mines,
-                        );
-
-                        let packet = ServerToClientPacket::FullBoard {
-                            width: board.width(),
-                            height: board.height(),
-                            num_mines: board.num_mines(),
-                            board_state: *board.board_state(),
-                            entries: board.revealed_board().clone(),
-                            player_ids: board.player_ids().clone(),
-                        };
-                        send_to_all(players.all_senders(), packet);
+                    if let Some(ref path) = files.history_file {
+                        if let Err(err) = append_to_file(path, &record.encode()).await {
+                            println!("Failed to append to game history {path}: {err}");
+                        }
+                    }
 
-                        done_instant = None;
-                        state_info = StateInfo::Ongoing;
+                    let leaderboard_bytes = {
+                        let mut leaderboard = archive.leaderboard.lock().unwrap();
+                        leaderboard.record_game(&record, &results);
+                        leaderboard.encode()
+                    };
+                    if let Some(ref path) = files.leaderboard_file {
+                        if let Err(err) = replace_file(path, &leaderboard_bytes).await {
+                            println!("Failed to write leaderboard {path}: {err}");
+                        }
                     }
+
+                    archive.history.lock().unwrap().push(record);
                 }
-            }
-            BoardEvent::NextBoardConfig { width, height, num_mines } => {
-                if board::MultiplayerBoard::possible_config(width, height, num_mines) {
-                    board_config.width = width;
-                    board_config.height = height;
-                    board_config.num_mines = num_mines;
-
-                    send_to_all(players.all_senders(), ServerToClientPacket::NextBoardConfig { width, height, num_mines });
-                    println!("New board config: {:?}", board_config);
-                } else {
-                    println!("Invalid board config received: {width}x{height} with {num_mines} mines.");
+                Outgoing::Evicted { player } => {
+                    senders_by_player.remove(&player);
                 }
-                
+            }
+        }
+
+        if let (Some(player), Some(reply_tx)) = (new_connection, reply_tx) {
+            let senders = senders_by_player.entry(player).or_default();
+            // Connections which come back from watching a replay are already registered.
+            if !senders.iter().any(|sender| sender.same_channel(&reply_tx)) {
+                senders.push(reply_tx);
             }
         }
     }
@@ -361,6 +556,34 @@ struct Args {
 
     #[arg(short, long, default_value_t = 3002)]
     port: u16,
+
+    // Directory to write a log of every finished game to, for replays. Logs are not kept if unset.
+    #[arg(long)]
+    game_log_dir: Option<String>,
+
+    // Number of finished games kept in memory for replays.
+    #[arg(long, default_value_t = 20)]
+    replay_capacity: usize,
+
+    // File to keep a record of every finished game in, so the game history survives restarts.
+    // History is only kept in memory if unset.
+    #[arg(long)]
+    history_file: Option<String>,
+
+    // File to keep the leaderboard in, so that it survives restarts. The leaderboard is only kept
+    // in memory if unset.
+    #[arg(long)]
+    leaderboard_file: Option<String>,
+
+    // File to keep daily challenge results in, so that attempts and rankings survive restarts.
+    // Results are only kept in memory if unset.
+    #[arg(long)]
+    daily_file: Option<String>,
+
+    // File to keep the secret daily boards are made from in, written if it doesn't exist. Daily
+    // boards change whenever the server restarts if unset.
+    #[arg(long)]
+    daily_secret_file: Option<String>,
 }
 
 #[tokio::main]
@@ -369,12 +592,64 @@ async fn main() {
 
     let (event_tx, event_rx) = mpsc::unbounded_channel();
 
-    tokio::spawn(board_handler(event_rx, args.nouns_path, args.adjectives_path));
+    let daily_results = match args.daily_file {
+        Some(ref path) => DailyResults::load(std::path::Path::new(path)).expect("Can't read daily results."),
+        None => DailyResults::new(),
+    };
+    let daily_seeds = match args.daily_secret_file {
+        Some(ref path) => DailySeeds::load_or_create(std::path::Path::new(path), &mut rand::thread_rng()).expect("Can't read daily secret."),
+        None => DailySeeds::new(rand::random()),
+    };
+    let clock: Arc<dyn Clock + Sync> = Arc::new(game::SystemClock);
+    let game = GameState::new(
+        player_list::read_word_list(&args.nouns_path),
+        player_list::read_word_list(&args.adjectives_path),
+        daily_results,
+        daily_seeds,
+        Box::new(clock.clone()),
+        StdRng::from_entropy(),
+    );
+    let history = match args.history_file {
+        Some(ref path) => GameHistory::load(std::path::Path::new(path)).expect("Can't read game history."),
+        None => GameHistory::new(),
+    };
+    let leaderboard = match args.leaderboard_file {
+        Some(ref path) => Leaderboard::load(std::path::Path::new(path)).expect("Can't read leaderboard."),
+        None => Leaderboard::new(),
+    };
+    let archive = Archive {
+        replays: Arc::new(Mutex::new(ReplayLibrary::new(args.replay_capacity))),
+        history: Arc::new(Mutex::new(history)),
+        leaderboard: Arc::new(Mutex::new(leaderboard)),
+        clock,
+    };
+    let files = ArchiveFiles {
+        game_log_dir: args.game_log_dir,
+        history_file: args.history_file,
+        leaderboard_file: args.leaderboard_file,
+        daily_file: args.daily_file,
+    };
+    tokio::spawn(board_handler(event_rx, game, archive.clone(), files));
 
     let addr = format!("0.0.0.0:{}", args.port);
     let listener = TcpListener::bind(&addr).await.expect("Can't listen.");
 
     while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(accept_connection(stream, event_tx.clone()));
+        tokio::spawn(accept_connection(stream, event_tx.clone(), archive.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_well_formed_client_tokens() {
+        assert_eq!(client_token("token=abc-DEF_123"), Some("abc-DEF_123"));
+        assert_eq!(client_token("v=2&token=abcdefgh"), Some("abcdefgh"));
+        assert_eq!(client_token("token=short"), None);
+        assert_eq!(client_token(&format!("token={}", "a".repeat(MAX_TOKEN_LEN + 1))), None);
+        assert_eq!(client_token("token=abcdefg%21"), None);
+        assert_eq!(client_token("other=abcdefgh"), None);
     }
 }
@@ -0,0 +1,125 @@
+use crate::teams::MAX_TEAMS;
+
+const FREE_FLAGS_BIT: u32 = 1 << 16; // In the setting of turn-based mode, below the turn length.
+
+// Rules a board is played under. Chosen for the next board, like the board config.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GameMode {
+    Coop, // Everyone plays the same board and wins or loses together.
+    Race, // Everyone plays their own copy of the same board and the first to clear theirs wins.
+    Teams { num_teams: u8 }, // Co-op, but the team which cleared the most wins when the board is solved.
+    Elimination, // Hitting a mine knocks the player out, and the board is lost once everyone is out.
+    Lives { lives: u8 }, // Co-op, but hitting a mine costs a shared life and the board is only lost without any left.
+    Freeze { seconds: u8 }, // Hitting a mine stops only that player acting for a while, and costs them score.
+    Turns { turn_seconds: u16, free_flags: bool }, // Co-op, but players take turns to reveal or chord. 0 seconds is no time limit.
+    Fog { radius: u8 }, // Co-op, but players only see the board near the squares they revealed.
+    Territory, // Revealing squares claims them, cascades included, and the most area claimed wins when the board is solved.
+}
+
+impl GameMode {
+    // Reads a mode as sent in packets. Returns None if the mode is unknown or its parameter is out
+    // of range.
+    pub fn decode(mode: u8, param: u32) -> Option<GameMode> {
+        match (mode, param) {
+            (0, 0) => Some(GameMode::Coop),
+            (1, 0) => Some(GameMode::Race),
+            (2, num_teams) if (2..=MAX_TEAMS as u32).contains(&num_teams) => Some(GameMode::Teams { num_teams: num_teams as u8 }),
+            (3, 0) => Some(GameMode::Elimination),
+            (4, lives) if (1..=u8::MAX as u32).contains(&lives) => Some(GameMode::Lives { lives: lives as u8 }),
+            (5, seconds) if (1..=u8::MAX as u32).contains(&seconds) => Some(GameMode::Freeze { seconds: seconds as u8 }),
+            (6, param) if param & !(FREE_FLAGS_BIT | u16::MAX as u32) == 0 => Some(GameMode::Turns {
+                turn_seconds: param as u16,
+                free_flags: param & FREE_FLAGS_BIT != 0,
+            }),
+            (7, radius) if (1..=u8::MAX as u32).contains(&radius) => Some(GameMode::Fog { radius: radius as u8 }),
+            (8, 0) => Some(GameMode::Territory),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            GameMode::Coop => 0,
+            GameMode::Race => 1,
+            GameMode::Teams { .. } => 2,
+            GameMode::Elimination => 3,
+            GameMode::Lives { .. } => 4,
+            GameMode::Freeze { .. } => 5,
+            GameMode::Turns { .. } => 6,
+            GameMode::Fog { .. } => 7,
+            GameMode::Territory => 8,
+        }
+    }
+
+    // Whether hitting a mine leaves play going on the room's board.
+    pub fn survives_mines(self) -> bool {
+        matches!(self, GameMode::Elimination | GameMode::Lives { .. } | GameMode::Freeze { .. })
+    }
+
+    // Setting of the mode, such as a number of lives. 0 for modes without one.
+    pub fn param(self) -> u32 {
+        match self {
+            GameMode::Coop | GameMode::Race | GameMode::Elimination | GameMode::Territory => 0,
+            GameMode::Teams { num_teams } => num_teams as u32,
+            GameMode::Lives { lives } => lives as u32,
+            GameMode::Freeze { seconds } => seconds as u32,
+            GameMode::Turns { turn_seconds, free_flags } => turn_seconds as u32 | if free_flags { FREE_FLAGS_BIT } else { 0 },
+            GameMode::Fog { radius } => radius as u32,
+        }
+    }
+}
+
+const SHOW_WRONG_FLAGS_BIT: u8 = 1 << 0;
+const MARKS_AFTER_START_BIT: u8 = 1 << 1;
+
+// Who may take a flag off the room's board, other than whoever placed it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlagRemoval {
+    Anyone,
+    Owner,     // Nobody else.
+    Confirmed, // Anyone else, once a second player has asked for it too.
+}
+
+// How players may use flags on the room's board. Chosen for the next board, like the game mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlagRules {
+    pub removal: FlagRemoval,
+    pub show_wrong_flags: bool,  // A flag is shown as wrong once the revealed squares prove there is no mine under it.
+    pub marks_after_start: bool, // Nothing may be marked until the board has been started.
+}
+
+impl FlagRules {
+    // Anyone may change any mark at any time.
+    pub const FREE: FlagRules = FlagRules { removal: FlagRemoval::Anyone, show_wrong_flags: false, marks_after_start: false };
+
+    // Reads rules as sent in packets. Returns None if the removal rule or an option is unknown.
+    pub fn decode(removal: u8, options: u8) -> Option<FlagRules> {
+        let removal = match removal {
+            0 => FlagRemoval::Anyone,
+            1 => FlagRemoval::Owner,
+            2 => FlagRemoval::Confirmed,
+            _ => return None,
+        };
+        if options & !(SHOW_WRONG_FLAGS_BIT | MARKS_AFTER_START_BIT) != 0 {
+            return None;
+        }
+        Some(FlagRules {
+            removal,
+            show_wrong_flags: options & SHOW_WRONG_FLAGS_BIT != 0,
+            marks_after_start: options & MARKS_AFTER_START_BIT != 0,
+        })
+    }
+
+    pub fn removal_code(self) -> u8 {
+        match self.removal {
+            FlagRemoval::Anyone => 0,
+            FlagRemoval::Owner => 1,
+            FlagRemoval::Confirmed => 2,
+        }
+    }
+
+    pub fn options(self) -> u8 {
+        (if self.show_wrong_flags { SHOW_WRONG_FLAGS_BIT } else { 0 })
+            | if self.marks_after_start { MARKS_AFTER_START_BIT } else { 0 }
+    }
+}
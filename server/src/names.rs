@@ -2,6 +2,7 @@
 
 use rand::{seq::SliceRandom, Rng};
 
+#[allow(dead_code)]
 pub enum Name {
     /// This represents a plain naming strategy of the form `"ADJECTIVE-NOUN"`
     Plain,
@@ -1,10 +1,6 @@
 use rand::rngs::StdRng;
-use rand::SeedableRng;
 use std::collections::HashMap;
-use std::time;
-use tokio::sync::mpsc;
 
-use crate::encoding;
 use crate::names;
 
 const MAX_NUM_PLAYERS: usize = 255; // Must be (strictly) less than 256.
@@ -32,10 +28,9 @@ const COLORS: [[u8; 4]; 14] = [
 
 pub struct Player {
     id: u8,
-    last_interaction: time::Instant,
+    last_interaction: u64, // Milliseconds since the Unix epoch.
     name: String,
     color: [u8; 4], // RGBA
-    senders: Vec<mpsc::UnboundedSender<encoding::ServerToClientPacket>>,
 }
 
 impl Player {
@@ -58,28 +53,26 @@ pub struct PlayerList {
     name_gen: names::Generator<StdRng>,
 }
 
-impl PlayerList {
-    pub fn new(nouns_path: &str, adjectives_path: &str) -> PlayerList {
-        let nouns: Vec<String> = std::fs::read_to_string(nouns_path)
-            .expect(&format!("nouns file {nouns_path} is missing"))
-            .lines()
-            .map(String::from)
-            .collect();
-        let adjectives: Vec<String> = std::fs::read_to_string(adjectives_path)
-            .expect(&format!("adjectives file {adjectives_path} is missing"))
-            .lines()
-            .map(String::from)
-            .collect();
-
-        let rng = StdRng::from_entropy();
+// Reads a file of words used to generate player names, one word per line.
+pub fn read_word_list(path: &str) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap_or_else(|_| panic!("word list {path} is missing"))
+        .lines()
+        .map(String::from)
+        .collect()
+}
 
+impl PlayerList {
+    pub fn new(nouns: Vec<String>, adjectives: Vec<String>, rng: StdRng) -> PlayerList {
         PlayerList {
             player_by_addr: HashMap::new(),
             name_gen: names::Generator::new(adjectives, nouns, names::Name::Plain, rng),
         }
     }
 
-    pub fn construct_player(&mut self, addr: &str) {
+    // Returns the address of the player who was dropped to make room, if the list was full.
+    pub fn construct_player(&mut self, addr: &str, now_ms: u64) -> Option<String> {
+        let mut evicted = None;
         if !self.player_by_addr.contains_key(addr) {
             let id = if self.player_by_addr.len() < MAX_NUM_PLAYERS {
                 u8::try_from(self.player_by_addr.len() + 1).expect("error handling num players")
@@ -87,57 +80,47 @@ impl PlayerList {
                 assert!(self.player_by_addr.len() == MAX_NUM_PLAYERS);
                 let (replaced_addr, replaced_id) = self.player_by_addr.iter()
                     .min_by_key(|&(_, player)| player.last_interaction)
-                    .map(|(ref addr, ref player)| ((*addr).clone(), player.id))
+                    .map(|(addr, player)| (addr.clone(), player.id))
                     .unwrap();
                 self.player_by_addr.remove(&replaced_addr);
+                evicted = Some(replaced_addr);
                 replaced_id
             };
 
             // TODO: make name and color guaranteed to be different from other players (as much as possible).
             let new_player = Player {
                 id,
-                last_interaction: time::Instant::now(),
+                last_interaction: now_ms,
                 name: self.name_gen.next().unwrap_or(addr.to_string()),
                 color: COLORS[id as usize % COLORS.len()],
-                senders: vec![],
             };
             self.player_by_addr.insert(addr.to_string(), new_player);
         }
+        evicted
     }
 
     pub fn get_player(&self, addr: &str) -> Option<&Player> {
         self.player_by_addr.get(addr)
     }
 
-    pub fn keep_addr_alive(&mut self, addr: &str) -> Option<u8> {
+    pub fn get_player_by_id(&self, id: u8) -> Option<&Player> {
+        self.player_by_addr.values().find(|player| player.id == id)
+    }
+
+    pub fn get_addr_by_id(&self, id: u8) -> Option<&str> {
+        self.player_by_addr.iter().find(|(_, player)| player.id == id).map(|(addr, _)| addr.as_str())
+    }
+
+    pub fn keep_addr_alive(&mut self, addr: &str, now_ms: u64) -> Option<u8> {
         if let Some(ref mut player) = self.player_by_addr.get_mut(addr) {
-            player.last_interaction = time::Instant::now();
+            player.last_interaction = now_ms;
             Some(player.id)
         } else {
             None
         }
     }
 
-    // TODO: turn this into an iterator.
-    pub fn all_senders(&mut self) -> Vec<&mut Vec<mpsc::UnboundedSender<encoding::ServerToClientPacket>>> {
-        let mut res = vec![];
-        for &mut Player { ref mut senders, .. } in self.player_by_addr.values_mut() {
-            res.push(senders);
-        }
-        res
-    }
-
     pub fn players(&self) -> impl Iterator<Item = &Player> {
         self.player_by_addr.values()
     }
-
-    pub fn get_mut_senders(
-        &mut self, addr: &str
-    ) -> Option<&mut Vec<mpsc::UnboundedSender<encoding::ServerToClientPacket>>> {
-        if self.player_by_addr.contains_key(addr) {
-            Some(&mut self.player_by_addr.get_mut(addr).unwrap().senders)
-        } else {
-            None
-        }
-    }
 }
@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+
+use crate::board;
+use crate::encoding::{Action, ServerToClientPacket};
+use crate::game::play_action;
+
+// One player's copy of the race board.
+struct Run {
+    board: board::MultiplayerBoard,
+    end_packets: Vec<ServerToClientPacket>, // What was sent when the run ended, for reconnecting clients.
+}
+
+// Everyone plays their own copy of the same board and the first to clear theirs wins. Hitting a
+// mine only ends that player's run. The race is over once someone wins or every player still in
+// the room who has started a run has hit a mine. Players who never act are not waited for.
+pub struct Race {
+    width: u16,
+    height: u16,
+    num_mines: u32,
+    seed: u64,
+    safe_square: (u16, u16), // Mines are placed as if this were revealed first, so every copy is the same.
+    runs: BTreeMap<u8, Run>, // By player ID, for players who have acted.
+    winner_id: Option<u8>,
+    finish_ms: Option<u64>,
+}
+
+impl Race {
+    pub fn new(width: u16, height: u16, num_mines: u32, seed: u64) -> Race {
+        Race {
+            width,
+            height,
+            num_mines,
+            seed,
+            safe_square: (height / 2, width / 2),
+            runs: BTreeMap::new(),
+            winner_id: None,
+            finish_ms: None,
+        }
+    }
+
+    pub fn safe_square(&self) -> (u16, u16) {
+        self.safe_square
+    }
+
+    // When someone won or the last run ended.
+    pub fn finish_ms(&self) -> Option<u64> {
+        self.finish_ms
+    }
+
+    fn new_board(&self) -> board::MultiplayerBoard {
+        board::MultiplayerBoard::with_safe_square(self.width, self.height, self.num_mines, self.seed, self.safe_square)
+    }
+
+    // Plays an action on the player's own board, starting their run if it is their first. Returns
+    // the packets to send to that player. `in_room` are the players who haven't left or gone off to
+    // play something else, whose runs must all have hit a mine for the race to end without a winner.
+    pub fn act(
+        &mut self,
+        player_id: u8,
+        action: Action,
+        coords: (u16, u16),
+        in_room: &[u8],
+        now_ms: u64,
+    ) -> Result<Vec<ServerToClientPacket>, board::Rejection> {
+        if self.finish_ms.is_some() {
+            return Err(board::Rejection::GameOver);
+        }
+        if !self.runs.contains_key(&player_id) {
+            let board = self.new_board();
+            self.runs.insert(player_id, Run { board, end_packets: vec![] });
+        }
+        let run = self.runs.get_mut(&player_id).unwrap();
+        let packets = play_action(&mut run.board, player_id, action, coords, now_ms)?;
+
+        let state = *run.board.board_state();
+        if let board::BoardState::Lost | board::BoardState::Won = state {
+            run.end_packets = packets.iter()
+                .filter(|packet| !matches!(packet, ServerToClientPacket::SparseBoard { .. }))
+                .cloned()
+                .collect();
+        }
+        match state {
+            board::BoardState::Won => {
+                self.winner_id = Some(player_id);
+                self.finish_ms = Some(now_ms);
+            }
+            board::BoardState::Lost => {
+                self.check_all_lost(in_room, now_ms);
+            }
+            _ => {}
+        }
+        Ok(packets)
+    }
+
+    // Ends the race if every run of a player in the room has hit a mine. Runs of players who are
+    // away are left out, but the race waits for them if nobody else is running. Returns whether
+    // this ended the race.
+    pub fn check_all_lost(&mut self, in_room: &[u8], now_ms: u64) -> bool {
+        if self.finish_ms.is_some() {
+            return false;
+        }
+        let mut runs = self.runs.iter().filter(|(player_id, _)| in_room.contains(player_id)).peekable();
+        if runs.peek().is_none() {
+            return false;
+        }
+        let all_lost = runs.all(|(_, run)| *run.board.board_state() == board::BoardState::Lost);
+        if all_lost {
+            self.finish_ms = Some(now_ms);
+        }
+        all_lost
+    }
+
+    // Drops the run of a player who is gone, so whoever is handed their ID starts afresh.
+    pub fn forget_player(&mut self, player_id: u8) {
+        self.runs.remove(&player_id);
+    }
+
+    // How far the player has got.
+    pub fn progress(&self, player_id: u8) -> ServerToClientPacket {
+        let run = self.runs.get(&player_id);
+        ServerToClientPacket::RaceProgress {
+            player_id,
+            squares_cleared: run.map_or(0, |run| run.board.squares_cleared()),
+            squares_to_clear: (self.width as u32) * (self.height as u32) - self.num_mines,
+            board_state: run.map_or(board::BoardState::Uninitialized, |run| *run.board.board_state()),
+        }
+    }
+
+    // Packets which show the race as the given player sees it.
+    pub fn packets(&self, player_id: u8, now_ms: u64) -> Vec<ServerToClientPacket> {
+        let mut packets = vec![ServerToClientPacket::SafeSquare { coords: self.safe_square }];
+        match self.runs.get(&player_id) {
+            Some(run) => {
+                packets.push(ServerToClientPacket::full_board(&run.board));
+                packets.push(ServerToClientPacket::game_clock(&run.board, now_ms));
+                packets.extend(run.end_packets.iter().cloned());
+            }
+            None => {
+                let board = board::MultiplayerBoard::new(self.width, self.height, self.num_mines, self.seed);
+                packets.push(ServerToClientPacket::full_board(&board));
+                packets.push(ServerToClientPacket::game_clock(&board, now_ms));
+            }
+        }
+        packets.extend(self.runs.keys().map(|&other_id| self.progress(other_id)));
+        if let Some(packet) = self.finished_packet() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    // Announces the end of the race, if it is over.
+    pub fn finished_packet(&self) -> Option<ServerToClientPacket> {
+        let finish_ms = self.finish_ms?;
+        let duration_ms = match self.winner_id {
+            Some(winner_id) => self.runs[&winner_id].board.elapsed_ms(finish_ms),
+            None => 0,
+        };
+        Some(ServerToClientPacket::RaceFinished { winner_id: self.winner_id.unwrap_or(0), duration_ms })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small race, and the mines on its board.
+    fn race() -> (Race, Vec<(u16, u16)>) {
+        let race = Race::new(9, 9, 10, 1);
+        let mines = board::MultiplayerBoard::with_safe_square(9, 9, 10, 1, race.safe_square()).mines().unwrap();
+        (race, mines)
+    }
+
+    #[test]
+    fn gives_every_player_the_same_board() {
+        let (mut race, mines) = race();
+        race.act(2, Action::Reveal, race.safe_square(), &[1, 2], 0).unwrap();
+        race.act(1, Action::Reveal, mines[0], &[1, 2], 0).unwrap();
+        assert_eq!(race.finish_ms(), None);
+        race.act(2, Action::Reveal, mines[0], &[1, 2], 0).unwrap();
+        let lost = |race: &Race, player_id| matches!(race.progress(player_id), ServerToClientPacket::RaceProgress { board_state: board::BoardState::Lost, .. });
+        assert!(lost(&race, 1) && lost(&race, 2));
+        assert_eq!(race.finish_ms(), Some(0));
+        assert!(matches!(race.act(3, Action::Reveal, race.safe_square(), &[1, 2, 3], 0), Err(board::Rejection::GameOver)));
+    }
+
+    #[test]
+    fn ends_once_every_runner_in_the_room_has_hit_a_mine() {
+        let (mut race, mines) = race();
+        race.act(1, Action::Reveal, race.safe_square(), &[1, 2, 3], 0).unwrap();
+        race.act(2, Action::Reveal, mines[0], &[1, 2, 3], 5).unwrap();
+        assert_eq!(race.finish_ms(), None);
+
+        // Player 1 goes off to play something else, leaving only player 2's lost run.
+        assert!(race.check_all_lost(&[2, 3], 6));
+        assert_eq!(race.finish_ms(), Some(6));
+    }
+
+    #[test]
+    fn forgets_the_run_of_a_player_who_is_gone() {
+        let (mut race, _) = race();
+        race.act(1, Action::Reveal, race.safe_square(), &[1], 0).unwrap();
+        race.forget_player(1);
+        assert!(matches!(race.progress(1), ServerToClientPacket::RaceProgress { squares_cleared: 0, .. }));
+        assert!(!race.check_all_lost(&[1], 0));
+    }
+
+    #[test]
+    fn is_won_by_the_first_to_clear_their_board() {
+        let (mut race, mines) = race();
+        race.act(2, Action::Reveal, race.safe_square(), &[1, 2], 0).unwrap();
+        race.act(1, Action::Reveal, race.safe_square(), &[1, 2], 1_000).unwrap();
+        for coords in (0..9).flat_map(|i| (0..9).map(move |j| (i, j))).filter(|coords| !mines.contains(coords)) {
+            // Squares a cascade already opened are rejected, as is everything once the race is won.
+            let _ = race.act(1, Action::Reveal, coords, &[1, 2], 5_000);
+        }
+        assert_eq!(race.finish_ms(), Some(5_000));
+        assert!(matches!(race.finished_packet(), Some(ServerToClientPacket::RaceFinished { winner_id: 1, duration_ms: 4_000 })));
+        assert!(matches!(race.act(2, Action::Reveal, mines[0], &[1, 2], 6_000), Err(board::Rejection::GameOver)));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+
+use crate::board;
+use crate::encoding::ServerToClientPacket;
+use crate::game;
+use crate::game_log::{GameLog, GameResult};
+
+// Idle stretches of a recorded game are shortened to this long (in original time) when played back.
+const MAX_REPLAY_GAP_MS: u64 = 5000;
+
+// The most recently finished games, oldest first.
+pub struct ReplayLibrary {
+    logs: VecDeque<GameLog>,
+    capacity: usize,
+}
+
+impl ReplayLibrary {
+    pub fn new(capacity: usize) -> ReplayLibrary {
+        ReplayLibrary { logs: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn push(&mut self, log: GameLog) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.logs.len() == self.capacity {
+            self.logs.pop_front();
+        }
+        self.logs.push_back(log);
+    }
+
+    // Games are identified by the time their board was created.
+    pub fn get(&self, game_id: u64) -> Option<&GameLog> {
+        self.logs.iter().find(|log| log.start_ms == game_id)
+    }
+}
+
+// Plays a recorded game back as the packets clients saw while it was live.
+pub struct Replay {
+    log: GameLog,
+    board: board::MultiplayerBoard,
+    num_applied: usize, // Number of the log's actions applied to the board so far.
+    speed_percent: u16, // Playback speed relative to the original game. 0 when paused.
+}
+
+impl Replay {
+    pub fn new(log: GameLog) -> Replay {
+        let board = Self::new_board(&log);
+        Replay { log, board, num_applied: 0, speed_percent: 100 }
+    }
+
+    fn new_board(log: &GameLog) -> board::MultiplayerBoard {
+        let mut board = board::MultiplayerBoard::with_mines(log.width, log.height, log.seed, &log.mines);
+        board.set_survive_mines(log.survive_mines);
+        board.set_show_wrong_flags(log.show_wrong_flags);
+        board
+    }
+
+    // Packets which set a client up to watch the replay from its current position.
+    pub fn start_packets(&self) -> Vec<ServerToClientPacket> {
+        let mut packets: Vec<ServerToClientPacket> = self.log.players.iter()
+            .map(|player| ServerToClientPacket::PlayerInfo {
+                player_id: player.id,
+                color: player.color,
+                name: player.name.clone(),
+            })
+            .collect();
+        packets.push(ServerToClientPacket::full_board(&self.board));
+        packets.push(ServerToClientPacket::game_clock(&self.board, self.time_ms()));
+        packets
+    }
+
+    pub fn set_speed(&mut self, speed_percent: u16) {
+        self.speed_percent = speed_percent;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.num_applied == self.log.actions.len()
+    }
+
+    // How long to wait before playing the next action. None if paused or finished.
+    pub fn delay_until_next(&self) -> Option<std::time::Duration> {
+        if self.speed_percent == 0 || self.is_finished() {
+            return None;
+        }
+        let gap_ms = self.log.actions[self.num_applied].time_ms.saturating_sub(self.time_ms()).min(MAX_REPLAY_GAP_MS);
+        Some(std::time::Duration::from_millis(gap_ms * 100 / self.speed_percent as u64))
+    }
+
+    // Plays the next action and returns the packets it caused.
+    pub fn step(&mut self) -> Vec<ServerToClientPacket> {
+        if self.is_finished() {
+            return vec![];
+        }
+        let packets = self.apply_next();
+        self.num_applied += 1;
+        packets
+    }
+
+    // Jumps to the point where the given number of actions have been played and returns the packets
+    // which show the board at that point.
+    pub fn seek(&mut self, num_actions: usize) -> Vec<ServerToClientPacket> {
+        let num_actions = num_actions.min(self.log.actions.len());
+        self.board = Self::new_board(&self.log);
+        self.num_applied = 0;
+        let mut last_packets = vec![];
+        while self.num_applied < num_actions {
+            last_packets = self.apply_next();
+            self.num_applied += 1;
+        }
+        // Only the action which ended the game causes more than a board update.
+        let mut packets = vec![
+            ServerToClientPacket::full_board(&self.board),
+            ServerToClientPacket::game_clock(&self.board, self.time_ms()),
+        ];
+        packets.extend(last_packets.into_iter().filter(|packet| {
+            !matches!(packet, ServerToClientPacket::SparseBoard { .. } | ServerToClientPacket::GameClock { .. })
+        }));
+        packets
+    }
+
+    // Time in the original game which has been played up to.
+    fn time_ms(&self) -> u64 {
+        match self.num_applied {
+            0 => self.log.start_ms,
+            n => self.log.actions[n - 1].time_ms,
+        }
+    }
+
+    fn apply_next(&mut self) -> Vec<ServerToClientPacket> {
+        let action = self.log.actions[self.num_applied];
+        let packets = game::play_action(&mut self.board, action.player_id, action.action, action.coords, action.time_ms);
+        // Only accepted actions are logged, so they are accepted again on the same board.
+        let mut packets = packets.unwrap_or_default();
+        // A board which survives mines can be lost without one ending it, after its last action.
+        let is_last = self.num_applied + 1 == self.log.actions.len();
+        if is_last && self.log.result == GameResult::Lost && *self.board.board_state() == board::BoardState::Ongoing {
+            let outcome = self.board.lose(self.log.end_ms);
+            packets.extend(game::outcome_packets(action.player_id, &outcome, &self.board, self.log.end_ms));
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::encoding::Action;
+    use crate::game_log::{LoggedAction, LoggedPlayer};
+
+    const START_MS: u64 = 1_000_000;
+
+    // Player 1 reveals a square, then player 2 hits a mine long after.
+    fn log() -> GameLog {
+        let action = |time_ms, player_id, coords| LoggedAction { time_ms, player_id, action: Action::Reveal, coords };
+        GameLog {
+            width: 4,
+            height: 4,
+            num_mines: 4,
+            seed: 0,
+            start_ms: START_MS,
+            end_ms: START_MS + 60_000,
+            result: GameResult::Lost,
+            survive_mines: false,
+            show_wrong_flags: false,
+            mines: vec![(0, 0), (0, 3), (3, 0), (3, 3)],
+            players: vec![
+                LoggedPlayer { id: 1, color: [1, 1, 1, 255], name: "One".to_string() },
+                LoggedPlayer { id: 2, color: [2, 2, 2, 255], name: "Two".to_string() },
+            ],
+            actions: vec![action(START_MS + 1_000, 1, (1, 1)), action(START_MS + 60_000, 2, (0, 0))],
+        }
+    }
+
+    fn revealed(packet: &ServerToClientPacket) -> usize {
+        match packet {
+            ServerToClientPacket::FullBoard { entries, .. } => entries.iter().flatten().filter(|entry| !entry.is_unknown()).count(),
+            _ => panic!("not a full board: {packet:?}"),
+        }
+    }
+
+    #[test]
+    fn keeps_only_the_most_recent_games() {
+        let mut library = ReplayLibrary::new(2);
+        for start_ms in [1, 2, 3] {
+            library.push(GameLog { start_ms, ..log() });
+        }
+        assert!(library.get(1).is_none());
+        assert_eq!(library.get(3).map(|log| log.start_ms), Some(3));
+    }
+
+    #[test]
+    fn plays_actions_back_at_the_chosen_speed_with_long_pauses_shortened() {
+        let mut replay = Replay::new(log());
+        let start = replay.start_packets();
+        assert_eq!(start.len(), 4);
+        assert_eq!(revealed(&start[2]), 0);
+
+        assert_eq!(replay.delay_until_next(), Some(Duration::from_millis(1_000)));
+        assert!(matches!(replay.step()[..], [ServerToClientPacket::SparseBoard { .. }, ServerToClientPacket::GameClock { .. }]));
+        replay.set_speed(200);
+        assert_eq!(replay.delay_until_next(), Some(Duration::from_millis(MAX_REPLAY_GAP_MS / 2)));
+        assert!(matches!(
+            replay.step()[..],
+            [_, ServerToClientPacket::BoardLoss { loser_id: 2, .. }, ServerToClientPacket::TrueBoard { .. }, _]
+        ));
+        assert!(replay.is_finished());
+        assert_eq!(replay.delay_until_next(), None);
+    }
+
+    #[test]
+    fn seeks_by_rebuilding_the_board_up_to_the_chosen_action() {
+        let mut replay = Replay::new(log());
+        let packets = replay.seek(2);
+        assert!(matches!(
+            packets[..],
+            [_, ServerToClientPacket::GameClock { elapsed_ms: 59_000, .. }, ServerToClientPacket::BoardLoss { .. }, ServerToClientPacket::TrueBoard { .. }]
+        ));
+        assert_eq!(revealed(&packets[0]), 2);
+
+        let packets = replay.seek(1);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(revealed(&packets[0]), 1);
+        replay.set_speed(0);
+        assert_eq!(replay.delay_until_next(), None);
+    }
+}
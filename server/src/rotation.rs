@@ -0,0 +1,87 @@
+use crate::encoding::ServerToClientPacket;
+
+// Whose turn it is in turn-based mode. Players take turns in order of player ID, wrapping round.
+pub struct Rotation {
+    turn_ms: Option<u64>, // Time limit for each turn.
+    player_id: Option<u8>, // None while nobody is there to take a turn.
+    until_ms: Option<u64>,
+}
+
+impl Rotation {
+    pub fn new(turn_seconds: u16) -> Rotation {
+        Rotation {
+            turn_ms: (turn_seconds > 0).then_some(1000 * turn_seconds as u64),
+            player_id: None,
+            until_ms: None,
+        }
+    }
+
+    pub fn player_id(&self) -> Option<u8> {
+        self.player_id
+    }
+
+    // When the current turn runs out, if turns are timed.
+    pub fn until_ms(&self) -> Option<u64> {
+        self.until_ms
+    }
+
+    // Passes the turn to whoever of the given players comes after the current one. A lone player
+    // gets another turn.
+    pub fn advance(&mut self, player_ids: &[u8], now_ms: u64) {
+        let next = player_ids.iter()
+            .copied()
+            .filter(|&player_id| self.player_id.is_some_and(|current| player_id > current))
+            .min()
+            .or_else(|| player_ids.iter().copied().min());
+        self.player_id = next;
+        self.until_ms = match next {
+            Some(_) => self.turn_ms.map(|turn_ms| now_ms + turn_ms),
+            None => None,
+        };
+    }
+
+    // Nobody gets a turn, such as once the board is over.
+    pub fn stop(&mut self) {
+        self.player_id = None;
+        self.until_ms = None;
+    }
+
+    pub fn packet(&self, now_ms: u64) -> ServerToClientPacket {
+        ServerToClientPacket::TurnChanged {
+            player_id: self.player_id.unwrap_or(0),
+            until_ms: self.until_ms.unwrap_or(0),
+            remaining_ms: self.until_ms.map_or(0, |until_ms| until_ms.saturating_sub(now_ms) as u32),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_turns_in_order_of_player_id_wrapping_round() {
+        let mut rotation = Rotation::new(0);
+        rotation.advance(&[4, 2, 7], 0);
+        assert_eq!(rotation.player_id(), Some(2));
+        rotation.advance(&[4, 2, 7], 0);
+        assert_eq!(rotation.player_id(), Some(4));
+        // Player 7 has left.
+        rotation.advance(&[4, 2], 0);
+        assert_eq!(rotation.player_id(), Some(2));
+        rotation.advance(&[], 0);
+        assert_eq!(rotation.player_id(), None);
+    }
+
+    #[test]
+    fn times_each_turn_from_when_it_starts() {
+        let mut rotation = Rotation::new(3);
+        rotation.advance(&[1], 1_000);
+        assert_eq!(rotation.until_ms(), Some(4_000));
+        assert!(matches!(rotation.packet(2_500), ServerToClientPacket::TurnChanged { player_id: 1, until_ms: 4_000, remaining_ms: 1_500 }));
+
+        rotation.stop();
+        assert!(matches!(rotation.packet(2_500), ServerToClientPacket::TurnChanged { player_id: 0, until_ms: 0, remaining_ms: 0 }));
+        assert_eq!(Rotation::new(0).until_ms(), None);
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+
+// Shown instead of the players' own colours, so that each team's squares look alike.
+const TEAM_COLORS: [[u8; 4]; 4] = [
+    [0xef, 0x53, 0x50, 0xff], // red 400
+    [0x42, 0xa5, 0xf5, 0xff], // blue 400
+    [0x66, 0xbb, 0x6a, 0xff], // green 400
+    [0xff, 0xca, 0x28, 0xff], // amber 400
+];
+pub const MAX_TEAMS: u8 = TEAM_COLORS.len() as u8;
+
+// Which team each player is on. Teams are numbered from 1.
+pub struct Teams {
+    num_teams: u8,
+    team_by_player: BTreeMap<u8, u8>,
+}
+
+impl Teams {
+    pub fn new(num_teams: u8) -> Teams {
+        assert!((2..=MAX_TEAMS).contains(&num_teams));
+        Teams { num_teams, team_by_player: BTreeMap::new() }
+    }
+
+    pub fn team_of(&self, player_id: u8) -> Option<u8> {
+        self.team_by_player.get(&player_id).copied()
+    }
+
+    // Puts a player without a team on the smallest one. Returns whether they were put on one.
+    pub fn assign(&mut self, player_id: u8) -> bool {
+        if self.team_by_player.contains_key(&player_id) {
+            return false;
+        }
+        let team = (1..=self.num_teams)
+            .min_by_key(|&team| self.team_by_player.values().filter(|&&other| other == team).count())
+            .unwrap();
+        self.team_by_player.insert(player_id, team);
+        true
+    }
+
+    // Returns false if there is no such team.
+    pub fn choose(&mut self, player_id: u8, team: u8) -> bool {
+        if !(1..=self.num_teams).contains(&team) {
+            return false;
+        }
+        self.team_by_player.insert(player_id, team);
+        true
+    }
+
+    // Takes a player who is gone off their team, so whoever is handed their ID is put on one afresh.
+    pub fn forget_player(&mut self, player_id: u8) {
+        self.team_by_player.remove(&player_id);
+    }
+
+    pub fn color(team: u8) -> [u8; 4] {
+        TEAM_COLORS[(team - 1) as usize]
+    }
+
+    // Squares cleared by each team's players, by team.
+    pub fn scores(&self, squares_by_player: impl Iterator<Item = (u8, u32)>) -> Vec<(u8, u32)> {
+        let mut scores: Vec<(u8, u32)> = (1..=self.num_teams).map(|team| (team, 0)).collect();
+        for (player_id, squares) in squares_by_player {
+            if let Some(team) = self.team_of(player_id) {
+                scores[(team - 1) as usize].1 += squares;
+            }
+        }
+        scores
+    }
+
+    // Whoever has the most, or None if there is a tie for the most. Used for players as well as teams.
+    pub fn leader(scores: &[(u8, u32)]) -> Option<u8> {
+        let &(team, most) = scores.iter().max_by_key(|&&(_, squares)| squares)?;
+        (scores.iter().filter(|&&(_, squares)| squares == most).count() == 1).then_some(team)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn puts_each_newcomer_on_the_smallest_team() {
+        let mut teams = Teams::new(3);
+        for player_id in 1..=3 {
+            assert!(teams.assign(player_id));
+        }
+        assert!(!teams.assign(1));
+        assert!(teams.choose(3, 1));
+        assert!(!teams.choose(3, 4));
+        assert!(teams.assign(4));
+        assert_eq!(teams.team_of(4), Some(3));
+
+        teams.forget_player(4);
+        assert_eq!(teams.team_of(4), None);
+    }
+
+    #[test]
+    fn crowns_a_team_only_if_it_cleared_the_most_on_its_own() {
+        let mut teams = Teams::new(2);
+        teams.assign(1);
+        teams.assign(2);
+        teams.assign(3);
+        let scores = teams.scores([(1, 5), (2, 7), (3, 3), (4, 50)].into_iter());
+        assert_eq!(scores, vec![(1, 8), (2, 7)]);
+        assert_eq!(Teams::leader(&scores), Some(1));
+        assert_eq!(Teams::leader(&[(1, 4), (2, 4)]), None);
+    }
+}